@@ -0,0 +1,104 @@
+use super::super::code_gen::segment::Segment;
+use super::super::instruction::{Instruction, Located, Operation};
+
+/// An inclusive range of values a stack slot may hold.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Range {
+  pub low: i16,
+  pub high: i16,
+}
+
+pub const FULL: Range = Range { low: i16::MIN, high: i16::MAX };
+const BOOLEAN: Range = Range { low: -1, high: 0 };
+
+impl Range {
+  pub fn constant(value: i16) -> Range {
+    Range { low: value, high: value }
+  }
+
+  fn from_wide(low: i32, high: i32) -> Range {
+    if low < i16::MIN as i32 || high > i16::MAX as i32 {
+      FULL
+    } else {
+      Range { low: low as i16, high: high as i16 }
+    }
+  }
+
+  fn is_non_negative(self) -> bool {
+    self.low >= 0
+  }
+
+  /// Whether `self - other` is guaranteed not to overflow 16 bits.
+  pub fn can_subtract(self, other: Range) -> bool {
+    let low = self.low as i32 - other.high as i32;
+    let high = self.high as i32 - other.low as i32;
+    low >= i16::MIN as i32 && high <= i16::MAX as i32
+  }
+}
+
+fn apply(op: Operation, x: Range, y: Range) -> Range {
+  match op {
+    Operation::Add => Range::from_wide(x.low as i32 + y.low as i32, x.high as i32 + y.high as i32),
+    Operation::Sub => Range::from_wide(x.low as i32 - y.high as i32, x.high as i32 - y.low as i32),
+    Operation::Neg => Range::from_wide(-(y.high as i32), -(y.low as i32)),
+    Operation::Not => Range { low: !y.high, high: !y.low },
+    Operation::Eq | Operation::Gt | Operation::Lt => BOOLEAN,
+    Operation::And if x.is_non_negative() || y.is_non_negative() => {
+      let high = match (x.is_non_negative(), y.is_non_negative()) {
+        (true, true) => x.high.min(y.high),
+        (true, false) => x.high,
+        _ => y.high,
+      };
+      Range { low: 0, high }
+    },
+    Operation::Or if x.is_non_negative() && y.is_non_negative() => {
+      // every bit set in either operand is below the highest bit of the larger
+      let bits = 16 - (x.high | y.high).leading_zeros();
+      Range { low: x.low.max(y.low), high: ((1i32 << bits) - 1) as i16 }
+    },
+    Operation::And | Operation::Or => FULL,
+  }
+}
+
+/// For every binary arithmetic instruction, the ranges its two operands
+/// (deeper first) are known to lie within. Only straight-line code is
+/// tracked: anything reachable by a jump, or loaded from memory, is assumed
+/// to be able to hold any value.
+pub fn operand_ranges(instructions: &[Located]) -> Vec<Option<(Range, Range)>> {
+  let mut ranges = Vec::with_capacity(instructions.len());
+  // values below the bottom of this are unknown
+  let mut stack: Vec<Range> = Vec::new();
+  let pop = |stack: &mut Vec<Range>| stack.pop().unwrap_or(FULL);
+
+  for located in instructions {
+    let mut operands = None;
+    match &located.instruction {
+      Instruction::Push(Segment::Constant, value) => stack.push(Range::constant(*value)),
+      Instruction::Push(_, _) => stack.push(FULL),
+      Instruction::Pop(_, _) | Instruction::IfGoto(_) => {
+        pop(&mut stack);
+      },
+      Instruction::Arithmetic(op) => {
+        let y = pop(&mut stack);
+        let x = if op.is_unary() { FULL } else { pop(&mut stack) };
+        if !op.is_unary() {
+          operands = Some((x, y));
+        }
+        stack.push(apply(*op, x, y));
+      },
+      Instruction::Call(_, num_args) => {
+        for _ in 0..*num_args {
+          pop(&mut stack);
+        }
+        stack.push(FULL);
+      },
+      Instruction::Label(_)
+      | Instruction::Goto(_)
+      | Instruction::Function(_, _)
+      | Instruction::Return => stack.clear(),
+    }
+    ranges.push(operands);
+  }
+
+  ranges
+}
@@ -1,5 +1,145 @@
 use std::io::{Write, Result};
 use super::code_gen::segment::Segment;
+use super::hack::asm::{self, AsmInstr};
+use super::hack::outline;
+
+// Appends Hack instructions to `$a`, written as assembly and separated by
+// commas, so that a malformed one doesn't compile: `@SP`, `@256`, `AM=M-1`,
+// `D;JGT`. Besides those, `@(e)` loads a computed symbol or constant, `(e)`
+// is a computed label, `#(...)` a comment taking `format!` arguments,
+// `..(e)` appends the instructions of `e`, and `D=(c)` and `D;(j)` take a
+// computed comp and jump.
+macro_rules! hack {
+  ( $a:expr, $($code:tt)* ) => {{
+    let instructions = &mut $a;
+    hack_instructions!(instructions; []; $($code)*);
+  }};
+}
+
+// Splits the instructions of `hack!` at the commas, taking the usual shapes
+// whole so that long routines stay within the recursion limit
+macro_rules! hack_instructions {
+  ( $a:ident; []; $x:tt , $($rest:tt)* ) => {
+    hack_instruction!($a; $x);
+    hack_instructions!($a; []; $($rest)*);
+  };
+  ( $a:ident; []; $x:tt $y:tt , $($rest:tt)* ) => {
+    hack_instruction!($a; $x $y);
+    hack_instructions!($a; []; $($rest)*);
+  };
+  ( $a:ident; []; $x:tt $y:tt $z:tt , $($rest:tt)* ) => {
+    hack_instruction!($a; $x $y $z);
+    hack_instructions!($a; []; $($rest)*);
+  };
+  ( $a:ident; []; $w:tt $x:tt $y:tt $z:tt , $($rest:tt)* ) => {
+    hack_instruction!($a; $w $x $y $z);
+    hack_instructions!($a; []; $($rest)*);
+  };
+  ( $a:ident; [$($instruction:tt)*]; ) => {
+    hack_instruction!($a; $($instruction)*)
+  };
+  ( $a:ident; [$($instruction:tt)*]; , $($rest:tt)* ) => {
+    hack_instruction!($a; $($instruction)*);
+    hack_instructions!($a; []; $($rest)*);
+  };
+  ( $a:ident; [$($instruction:tt)*]; $next:tt $($rest:tt)* ) => {
+    hack_instructions!($a; [$($instruction)* $next]; $($rest)*)
+  };
+}
+
+macro_rules! hack_instruction {
+  ( $a:ident; ) => {};
+  ( $a:ident; @ $symbol:ident ) => {
+    $a.push($crate::hack::asm::AsmInstr::symbol(stringify!($symbol)))
+  };
+  ( $a:ident; @ $value:literal ) => {
+    $a.push($crate::hack::asm::AsmInstr::AConstant(const {
+      assert!($value <= 0x7fff, "Constant out of range");
+      $value
+    }))
+  };
+  ( $a:ident; @ ($address:expr) ) => {
+    $a.push($crate::hack::asm::Address::load($address))
+  };
+  ( $a:ident; ($label:expr) ) => {
+    $a.push($crate::hack::asm::AsmInstr::Label(($label).into()))
+  };
+  ( $a:ident; # ($text:literal) ) => {
+    $a.push($crate::hack::asm::AsmInstr::Comment(String::from($text)))
+  };
+  ( $a:ident; # ($($text:tt)*) ) => {
+    $a.push($crate::hack::asm::AsmInstr::Comment(format!($($text)*)))
+  };
+  ( $a:ident; .. ($instructions:expr) ) => {
+    $a.extend($instructions)
+  };
+  ( $a:ident; $dest:ident = $($comp:tt)+ ) => {{
+    let (comp, jump) = hack_comp!($($comp)+);
+    $a.push($crate::hack::asm::AsmInstr::c(hack_dest!($dest), comp, jump))
+  }};
+  ( $a:ident; $($comp:tt)+ ) => {{
+    let (comp, jump) = hack_comp!($($comp)+);
+    $a.push($crate::hack::asm::AsmInstr::c($crate::hack::asm::Dest::NONE, comp, jump))
+  }};
+}
+
+macro_rules! hack_dest {
+  ( A ) => { $crate::hack::asm::Dest { a: true, d: false, m: false } };
+  ( D ) => { $crate::hack::asm::Dest { a: false, d: true, m: false } };
+  ( M ) => { $crate::hack::asm::Dest { a: false, d: false, m: true } };
+  ( AD ) => { $crate::hack::asm::Dest { a: true, d: true, m: false } };
+  ( AM ) => { $crate::hack::asm::Dest { a: true, d: false, m: true } };
+  ( MD ) => { $crate::hack::asm::Dest { a: false, d: true, m: true } };
+  ( AMD ) => { $crate::hack::asm::Dest { a: true, d: true, m: true } };
+}
+
+// The comp and jump of a C-instruction
+macro_rules! hack_comp {
+  ( ($comp:expr) $(; $jump:tt)? ) => { ($comp, hack_jump!($($jump)?)) };
+  ( 0 $(; $jump:tt)? ) => { hack_comp!(@ Zero $($jump)?) };
+  ( 1 $(; $jump:tt)? ) => { hack_comp!(@ One $($jump)?) };
+  ( - 1 $(; $jump:tt)? ) => { hack_comp!(@ MinusOne $($jump)?) };
+  ( D $(; $jump:tt)? ) => { hack_comp!(@ D $($jump)?) };
+  ( A $(; $jump:tt)? ) => { hack_comp!(@ A $($jump)?) };
+  ( M $(; $jump:tt)? ) => { hack_comp!(@ M $($jump)?) };
+  ( ! D $(; $jump:tt)? ) => { hack_comp!(@ NotD $($jump)?) };
+  ( ! A $(; $jump:tt)? ) => { hack_comp!(@ NotA $($jump)?) };
+  ( ! M $(; $jump:tt)? ) => { hack_comp!(@ NotM $($jump)?) };
+  ( - D $(; $jump:tt)? ) => { hack_comp!(@ NegD $($jump)?) };
+  ( - A $(; $jump:tt)? ) => { hack_comp!(@ NegA $($jump)?) };
+  ( - M $(; $jump:tt)? ) => { hack_comp!(@ NegM $($jump)?) };
+  ( D + 1 $(; $jump:tt)? ) => { hack_comp!(@ DPlusOne $($jump)?) };
+  ( A + 1 $(; $jump:tt)? ) => { hack_comp!(@ APlusOne $($jump)?) };
+  ( M + 1 $(; $jump:tt)? ) => { hack_comp!(@ MPlusOne $($jump)?) };
+  ( D - 1 $(; $jump:tt)? ) => { hack_comp!(@ DMinusOne $($jump)?) };
+  ( A - 1 $(; $jump:tt)? ) => { hack_comp!(@ AMinusOne $($jump)?) };
+  ( M - 1 $(; $jump:tt)? ) => { hack_comp!(@ MMinusOne $($jump)?) };
+  ( D + A $(; $jump:tt)? ) => { hack_comp!(@ DPlusA $($jump)?) };
+  ( D + M $(; $jump:tt)? ) => { hack_comp!(@ DPlusM $($jump)?) };
+  ( D - A $(; $jump:tt)? ) => { hack_comp!(@ DMinusA $($jump)?) };
+  ( D - M $(; $jump:tt)? ) => { hack_comp!(@ DMinusM $($jump)?) };
+  ( A - D $(; $jump:tt)? ) => { hack_comp!(@ AMinusD $($jump)?) };
+  ( M - D $(; $jump:tt)? ) => { hack_comp!(@ MMinusD $($jump)?) };
+  ( D & A $(; $jump:tt)? ) => { hack_comp!(@ DAndA $($jump)?) };
+  ( D & M $(; $jump:tt)? ) => { hack_comp!(@ DAndM $($jump)?) };
+  ( D | A $(; $jump:tt)? ) => { hack_comp!(@ DOrA $($jump)?) };
+  ( D | M $(; $jump:tt)? ) => { hack_comp!(@ DOrM $($jump)?) };
+  ( @ $comp:ident $($jump:tt)? ) => {
+    ($crate::hack::asm::Comp::$comp, hack_jump!($($jump)?))
+  };
+}
+
+macro_rules! hack_jump {
+  () => { $crate::hack::asm::Jump::Never };
+  ( JGT ) => { $crate::hack::asm::Jump::Gt };
+  ( JEQ ) => { $crate::hack::asm::Jump::Eq };
+  ( JGE ) => { $crate::hack::asm::Jump::Ge };
+  ( JLT ) => { $crate::hack::asm::Jump::Lt };
+  ( JNE ) => { $crate::hack::asm::Jump::Ne };
+  ( JLE ) => { $crate::hack::asm::Jump::Le };
+  ( JMP ) => { $crate::hack::asm::Jump::Always };
+  ( ($jump:expr) ) => { $jump };
+}
 
 macro_rules! new_label {
   ( $x:expr ) => {{
@@ -10,17 +150,18 @@ macro_rules! new_label {
 
 macro_rules! label {
   ( $x:expr, $function_name:expr, $label:expr ) => {{
-    write!(($x).buffer, "({}${})\n", $function_name, $label).unwrap();
+    hack!($x, (format!("{}${}", $function_name, $label)));
   }};
 }
 
 macro_rules! vm_goto {
   ( $a:expr, $label:expr ) => {{
-    write!(($a).buffer, "
-  // GOTO FUNCTION {label}
-  @{label}
-  0;JMP
-", label = $label).unwrap();
+    let label = $label;
+    hack!($a,
+      #("GOTO FUNCTION {}", label),
+      @(label),
+      0;JMP,
+    );
   }}
 }
 
@@ -33,254 +174,572 @@ macro_rules! goto {
 macro_rules! if_goto {
   ( $x:expr, $function_name:expr, $label:expr ) => {{
     pop_D!($x);
-    write!(($x).buffer, "
-  // IF-GOTO {function_name}${label}
-  @{function_name}${label}
-  D;JNE
-", label = $label
- , function_name = $function_name).unwrap();
+    if_goto_D!($x, $function_name, $label);
+  }}
+}
+
+// Like `if_goto!` with the condition already popped into D
+macro_rules! if_goto_D {
+  ( $x:expr, $function_name:expr, $label:expr ) => {{
+    let label = format!("{}${}", $function_name, $label);
+    hack!($x,
+      #("IF-GOTO {}", label),
+      @(label),
+      D;JNE,
+    );
+  }}
+}
+
+macro_rules! load {
+  ( $a:expr, $segment:expr, $index:expr ) => {{
+    let segment = &$segment;
+    let index: i16 = $index;
+    hack!($a,
+      #("PUSHING FROM {} {}", segment, index),
+      ..(segment.resolve_address(index)),
+      D=M,
+    );
   }}
 }
 
 macro_rules! push {
   ( $a:expr, $segment:expr, $index:expr ) => {{
-    write!(($a).buffer, "
-  // PUSHING FROM {segment} {index}
-  {address}
-  D=M
-", address = ($segment).resolve_address($index)
- , segment = $segment
- , index = $index).unwrap();
+    load!($a, $segment, $index);
     push_D!($a);
   }}
 }
 
 macro_rules! push_D {
   ( $a:expr ) => {{
-    write!(($a).buffer, "
-  // PUSHING FROM D REGISTER
-  @SP
-  A=M
-  M=D
-  @SP
-  M=M+1
-").unwrap();
+    hack!($a,
+      #("PUSHING FROM D REGISTER"),
+      @SP,
+      A=M,
+      M=D,
+      @SP,
+      M=M+1,
+    );
+  }}
+}
+
+macro_rules! load_constant {
+  ( $a:expr, $value:expr ) => {{
+    // only folded constants can be negative
+    let value: i16 = $value;
+    hack!($a, #("PUSHING CONSTANT {}", value));
+    match value {
+      0.. => hack!($a, @(value), D=A),
+      i16::MIN => hack!($a, @32767, D=-A, D=D-1),
+      _ => hack!($a, @(-value), D=-A),
+    }
   }}
 }
 
 macro_rules! push_constant {
   ( $a:expr, $value:expr ) => {{
-    write!(($a).buffer, "
-  // PUSHING CONSTANT {value}
-  @{value}
-  D=A
-  @SP
-  A=M
-  M=D
-  @SP
-  M=M+1
-", value = $value).unwrap();
+    load_constant!($a, $value);
+    hack!($a,
+      @SP,
+      A=M,
+      M=D,
+      @SP,
+      M=M+1,
+    );
   }}
 }
 
 macro_rules! push_address {
   ( $a:expr, $segment:expr ) => {{
-    write!(($a).buffer, "
-  @{segment}
-  D=M
-", segment = $segment).unwrap();
+    hack!($a,
+      @(($segment).to_string()),
+      D=M,
+    );
     push_D!($a);
   }}
 }
 
 macro_rules! pop {
   ( $a:expr, $segment:expr, $index:expr ) => {{
-    write!(($a).buffer, "
-  // POPPING INTO {segment} {index}
-  {address}
-  D=A
-  @R13
-  M=D
-  @SP
-  AM=M-1 // dereference and decrement SP at the same time
-  D=M
-  @R13
-  A=M
-  M=D
-", address = ($segment).resolve_address($index)
- , segment = $segment
- , index = $index).unwrap();
+    let segment = &$segment;
+    let index: i16 = $index;
+    // computing the address first and parking it in R13 takes 12
+    // instructions, 4 of which are the pop and the store
+    match segment.address_without_d(index, 8) {
+      Some(address) => {
+        pop_D!($a);
+        hack!($a,
+          #("POPPING INTO {} {}", segment, index),
+          ..(address),
+          M=D,
+        );
+      },
+      None => hack!($a,
+        #("POPPING INTO {} {}", segment, index),
+        @(index),
+        D=A,
+        @(segment.to_string()),
+        D=D+M,
+        @R13,
+        M=D,
+        @SP,
+        AM=M-1,
+        #("dereference and decrement SP at the same time"),
+        D=M,
+        @R13,
+        A=M,
+        M=D,
+      ),
+    }
+  }}
+}
+
+// Stores D without going through the stack
+macro_rules! store_D {
+  ( $a:expr, $segment:expr, $index:expr ) => {{
+    let segment = &$segment;
+    let index: i16 = $index;
+    // parking the value in R13 and the address in R14 takes 12 instructions
+    let address = match segment.address_without_d(index, 11) {
+      Some(address) => address,
+      None => {
+        let mut address = Vec::new();
+        hack!(address,
+          @R13,
+          M=D,
+          @(index),
+          D=A,
+          @(segment.to_string()),
+          D=D+M,
+          @R14,
+          M=D,
+          @R13,
+          D=M,
+          @R14,
+          A=M,
+        );
+        address
+      },
+    };
+    hack!($a,
+      #("POPPING INTO {} {}", segment, index),
+      ..(address),
+      M=D,
+    );
   }}
 }
 
 macro_rules! pop_D {
   ( $a:expr ) => {{
-    write!(($a).buffer, "
-  // POPPING INTO D REGISTER
-  @SP
-  AM=M-1 // dereference and decrement SP at the same time
-  D=M
-").unwrap();
+    hack!($a,
+      #("POPPING INTO D REGISTER"),
+      @SP,
+      AM=M-1,
+      #("dereference and decrement SP at the same time"),
+      D=M,
+    );
   }}
 }
 
 macro_rules! add {
   ( $a:expr ) => {{
     pop_D!($a); // pop top of stack into D register
-    write!(($a).buffer, "
-  // ADD
-  @SP
-  A=M-1
-  M=D+M
-").unwrap();
+    hack!($a,
+      #("ADD"),
+      @SP,
+      A=M-1,
+      M=D+M,
+    );
   }}
 }
 
 macro_rules! neg {
   ( $a:expr ) => {{
-    write!(($a).buffer, "
-  // NEG
-  @SP
-  A=M-1
-  M=-M
-").unwrap();
+    hack!($a,
+      #("NEG"),
+      @SP,
+      A=M-1,
+      M=-M,
+    );
   }}
 }
 
 macro_rules! sub {
   ( $a:expr ) => {{
     pop_D!($a); // pop top of stack into D register
-    write!(($a).buffer, "
-  // SUB
-  @SP
-  A=M-1
-  M=D-M
-  M=-M // negate the result since it was subtracted backwards
-").unwrap();
+    hack!($a,
+      #("SUB"),
+      @SP,
+      A=M-1,
+      M=D-M,
+      M=-M,
+      #("negate the result since it was subtracted backwards"),
+    );
   }}
 }
 
 macro_rules! eq {
   ( $a:expr ) => {{
     let label = new_label!($a);
+    let at = |name: &str| format!("{}_{}", name, label);
     pop_D!($a);
-    write!(($a).buffer, "
-  // EQ
-  @SP
-  A=M-1
-  D=D-M
-  @EQ_CALLBACK_{label}
-  D;JNE // not the same, return false
-  @SP
-  A=M-1
-  M=-1
-  @EQ_END_{label}
-  0;JMP
-(EQ_CALLBACK_{label})
-  @SP
-  A=M-1
-  M=0
-(EQ_END_{label})
-", label = label).unwrap();
+    hack!($a,
+      #("EQ"),
+      @SP,
+      A=M-1,
+      D=D-M,
+      @(at("EQ_CALLBACK")),
+      D;JNE,
+      #("not the same, return false"),
+      @SP,
+      A=M-1,
+      M=-1,
+      @(at("EQ_END")),
+      0;JMP,
+      (at("EQ_CALLBACK")),
+      @SP,
+      A=M-1,
+      M=0,
+      (at("EQ_END")),
+    );
+  }}
+}
+
+// Leaves x on the stack and sets D to a value with the same sign as x-y,
+// where y is popped from the top of the stack. Subtracting directly can
+// overflow when the operands have opposite signs, so the signs are compared
+// first and the subtraction is only done when they're the same.
+macro_rules! signed_difference {
+  ( $a:expr, $label:expr ) => {{
+    let label = $label;
+    let at = |name: &str| format!("{}_{}", name, label);
+    pop_D!($a);
+    hack!($a,
+      #("SIGN OF x-y"),
+      @R13,
+      M=D,
+      @SP,
+      A=M-1,
+      D=M,
+      @(at("X_NEGATIVE")),
+      D;JLT,
+      @R13,
+      D=M,
+      @(at("SIGNS_DIFFER")),
+      D;JLT,
+      #("x >= 0 > y"),
+      @(at("SAME_SIGN")),
+      0;JMP,
+      (at("X_NEGATIVE")),
+      @R13,
+      D=M,
+      @(at("SIGNS_DIFFER")),
+      D;JGE,
+      #("x < 0 <= y"),
+      (at("SAME_SIGN")),
+      @R13,
+      D=M,
+      @SP,
+      A=M-1,
+      D=M-D,
+      #("can't overflow when the signs are the same"),
+      @(at("DIFFERENCE")),
+      0;JMP,
+      (at("SIGNS_DIFFER")),
+      @SP,
+      A=M-1,
+      D=M,
+      @1,
+      D=D|A,
+      #("has the sign of x and is never 0"),
+      (at("DIFFERENCE")),
+    );
   }}
 }
 
 macro_rules! gt {
   ( $a:expr ) => {{
     let label = new_label!($a);
-    pop_D!($a);
-    write!(($a).buffer, "
-  // GT
-  @SP
-  A=M-1
-  D=D-M
-  @GT_CALLBACK_{label}
-  D;JLT // not greater, return false
-  @SP
-  A=M-1
-  M=0
-  @GT_END_{label}
-  0;JMP
-(GT_CALLBACK_{label})
-  @SP
-  A=M-1
-  M=-1
-(GT_END_{label})
-", label = label).unwrap();
+    signed_difference!($a, &label);
+    let at = |name: &str| format!("{}_{}", name, label);
+    hack!($a,
+      #("GT"),
+      @(at("GT_CALLBACK")),
+      D;JGT,
+      @SP,
+      A=M-1,
+      M=0,
+      @(at("GT_END")),
+      0;JMP,
+      (at("GT_CALLBACK")),
+      @SP,
+      A=M-1,
+      M=-1,
+      (at("GT_END")),
+    );
   }}
 }
 
 macro_rules! lt {
   ( $a:expr ) => {{
     let label = new_label!($a);
+    signed_difference!($a, &label);
+    let at = |name: &str| format!("{}_{}", name, label);
+    hack!($a,
+      #("LT"),
+      @(at("LT_CALLBACK")),
+      D;JLT,
+      @SP,
+      A=M-1,
+      M=0,
+      @(at("LT_END")),
+      0;JMP,
+      (at("LT_CALLBACK")),
+      @SP,
+      A=M-1,
+      M=-1,
+      (at("LT_END")),
+    );
+  }}
+}
+
+// Only correct when y-x can't overflow, see `signed_difference!`
+macro_rules! gt_fast {
+  ( $a:expr ) => {{
+    let label = new_label!($a);
+    let at = |name: &str| format!("{}_{}", name, label);
     pop_D!($a);
-    write!(($a).buffer, "
-  // LT
-  @SP
-  A=M-1
-  D=D-M
-  @LT_CALLBACK_{label}
-  D;JGT // not greater, return false
-  @SP
-  A=M-1
-  M=0
-  @LT_END_{label}
-  0;JMP
-(LT_CALLBACK_{label})
-  @SP
-  A=M-1
-  M=-1
-(LT_END_{label})
-", label = label).unwrap();
+    hack!($a,
+      #("GT"),
+      @SP,
+      A=M-1,
+      D=D-M,
+      @(at("GT_CALLBACK")),
+      D;JLT,
+      #("not greater, return false"),
+      @SP,
+      A=M-1,
+      M=0,
+      @(at("GT_END")),
+      0;JMP,
+      (at("GT_CALLBACK")),
+      @SP,
+      A=M-1,
+      M=-1,
+      (at("GT_END")),
+    );
+  }}
+}
+
+// Only correct when y-x can't overflow, see `signed_difference!`
+macro_rules! lt_fast {
+  ( $a:expr ) => {{
+    let label = new_label!($a);
+    let at = |name: &str| format!("{}_{}", name, label);
+    pop_D!($a);
+    hack!($a,
+      #("LT"),
+      @SP,
+      A=M-1,
+      D=D-M,
+      @(at("LT_CALLBACK")),
+      D;JGT,
+      #("not greater, return false"),
+      @SP,
+      A=M-1,
+      M=0,
+      @(at("LT_END")),
+      0;JMP,
+      (at("LT_CALLBACK")),
+      @SP,
+      A=M-1,
+      M=-1,
+      (at("LT_END")),
+    );
+  }}
+}
+
+// Pops y and x and jumps to `function_name$label` if x-y satisfies `jump`,
+// without pushing a boolean
+macro_rules! compare_branch {
+  ( $a:expr, $jump:expr, $function_name:expr, $label:expr ) => {{
+    let difference = new_label!($a);
+    signed_difference!($a, difference);
+    let target = format!("{}${}", $function_name, $label);
+    hack!($a,
+      #("BRANCH TO {}", target),
+      @SP,
+      M=M-1,
+      @(target),
+      D;($jump),
+    );
+  }}
+}
+
+// Only correct when x-y can't overflow, or when branching on equality
+macro_rules! compare_branch_fast {
+  ( $a:expr, $jump:expr, $function_name:expr, $label:expr ) => {{
+    pop_D!($a);
+    let target = format!("{}${}", $function_name, $label);
+    hack!($a,
+      #("BRANCH TO {}", target),
+      @SP,
+      AM=M-1,
+      D=M-D,
+      @(target),
+      D;($jump),
+    );
+  }}
+}
+
+// Pops x and sets D to x op D, where `comp` computes it with x in M
+macro_rules! binary_D {
+  ( $a:expr, $name:expr, $comp:expr ) => {{
+    hack!($a,
+      #("{}", $name),
+      @SP,
+      AM=M-1,
+      D=($comp),
+    );
+  }}
+}
+
+macro_rules! unary_D {
+  ( $a:expr, $name:expr, $comp:expr ) => {{
+    hack!($a,
+      #("{}", $name),
+      D=($comp),
+    );
+  }}
+}
+
+// `push x, push y, add, pop pointer 1, push that 0` with x already in D,
+// given the instructions that add y to D: points THAT at x+y and leaves
+// what's there in D
+macro_rules! array_read_D {
+  ( $a:expr, $add:expr ) => {{
+    hack!($a,
+      #("ARRAY READ"),
+      ..($add),
+      @THAT,
+      M=D,
+      A=D,
+      D=M,
+    );
+  }}
+}
+
+// `push s i, push constant c, add, pop s i` given the address of s i, which
+// may only use D if `delta` is 1 or -1
+macro_rules! increment {
+  ( $a:expr, $address:expr, $delta:expr ) => {{
+    let delta: i16 = $delta;
+    let update = match delta {
+      1 => $crate::hack::asm::Comp::MPlusOne,
+      -1 => $crate::hack::asm::Comp::MMinusOne,
+      _ => {
+        load_constant!($a, delta);
+        $crate::hack::asm::Comp::DPlusM
+      },
+    };
+    hack!($a,
+      #("INCREMENT BY {}", delta),
+      ..($address),
+      M=(update),
+    );
   }}
 }
 
 macro_rules! and {
   ( $a:expr ) => {{
     pop_D!($a);
-    write!(($a).buffer, "
-  // AND
-  @SP
-  A=M-1
-  M=D&M
-").unwrap();
+    hack!($a,
+      #("AND"),
+      @SP,
+      A=M-1,
+      M=D&M,
+    );
   }}
 }
 
 macro_rules! or {
   ( $a:expr ) => {{
     pop_D!($a);
-    write!(($a).buffer, "
-  // OR
-  @SP
-  A=M-1
-  M=D|M
-").unwrap();
+    hack!($a,
+      #("OR"),
+      @SP,
+      A=M-1,
+      M=D|M,
+    );
   }}
 }
 
 macro_rules! not {
   ( $a:expr ) => {{
-    write!(($a).buffer, "
-  // NOT
-  @SP
-  A=M-1
-  M=!M
-").unwrap();
+    hack!($a,
+      #("NOT"),
+      @SP,
+      A=M-1,
+      M=!M,
+    );
   }}
 }
 
 macro_rules! vm_label {
   ( $a:expr, $function_name:expr ) => {{
-    write!(($a).buffer, "\n({})\n", $function_name).unwrap();
+    hack!($a, ($function_name));
   }}
 }
 
+/// Instructions in the loop that clears a function's locals, whatever their
+/// number. Clearing them unrolled takes 2 per local plus 4, but the loop
+/// spends 7 cycles on each.
+pub const CLEAR_LOOP_SIZE: i16 = 9;
+
 macro_rules! function {
-  ( $a:expr, $name:expr, $num_vars:expr ) => {{
+  ( $a:expr, $name:expr, $num_vars:expr, $prefer_size:expr ) => {{
     vm_label!($a, $name);
-    for _ in 0..($num_vars) {
-      push_constant!($a, 0);
+    let num_vars: i16 = $num_vars;
+    if num_vars == 1 {
+      hack!($a,
+        @SP,
+        A=M,
+        M=0,
+        @SP,
+        M=M+1,
+      );
+    } else if num_vars > 1 && $prefer_size && CLEAR_LOOP_SIZE < 2 * num_vars + 4 {
+      let label = new_label!($a);
+      hack!($a,
+        #("CLEAR {} LOCALS", num_vars),
+        @(num_vars),
+        D=A,
+        (&label),
+        @SP,
+        AM=M+1,
+        A=A-1,
+        M=0,
+        D=D-1,
+        @(&label),
+        D;JGT,
+      );
+    } else if num_vars > 1 {
+      hack!($a,
+        #("CLEAR {} LOCALS", num_vars),
+        @SP,
+        A=M,
+      );
+      for _ in 1..num_vars {
+        hack!($a,
+          M=0,
+          A=A+1,
+        );
+      }
+      hack!($a,
+        M=0,
+        D=A+1,
+        @SP,
+        M=D,
+      );
     }
   }}
 }
@@ -288,10 +747,10 @@ macro_rules! function {
 macro_rules! call {
   ( $a:expr, $function_name:expr, $num_args:expr ) => {{
     let label = new_label!($a);
-    write!(($a).buffer, "
-  @{}
-  D=A
-", label).unwrap();
+    hack!($a,
+      @(&label),
+      D=A,
+    );
     push_D!($a);
     // store addresses of caller's segments
     push_address!($a, Segment::Local);
@@ -299,105 +758,793 @@ macro_rules! call {
     push_address!($a, Segment::This);
     push_address!($a, Segment::That);
     // reposition arg segment
-    write!(($a).buffer, "
-  @SP
-  D=M
-").unwrap();
+    hack!($a,
+      @SP,
+      D=M,
+    );
     push_D!($a);
     push_constant!($a, ($num_args) + 5);
     sub!($a);
     pop_D!($a);
-    write!(($a).buffer, "
-  @{argument}
-  M=D
-", argument = Segment::Argument).unwrap();
+    hack!($a,
+      @ARG,
+      M=D,
+    );
     // reposition local pointer
-    write!(($a).buffer, "
-  @SP
-  D=M
-  @{local}
-  M=D
-", local = Segment::Local).unwrap();
+    hack!($a,
+      @SP,
+      D=M,
+      @LCL,
+      M=D,
+    );
     vm_goto!($a, $function_name);
     vm_label!($a, label);
   }}
 }
 
+/// Where `return_!` keeps the frame pointer and the return address.
+pub const FRAME: &str = "FRAME";
+pub const RET: &str = "RET";
+
+/// The variables the generated code allocates besides the program's statics.
+pub const SCRATCH_VARIABLES: [&str; 3] = [FRAME, RET, outline::RETURN];
+
 macro_rules! return_ {
   ( $a:expr ) => {{
-    write!(($a).buffer, "
-  @LCL // store position of frame
-  D=M
-  @FRAME
-  M=D
-  @5 // put return address in temp var
-  A=D-A
-  D=M
-  @RET
-  M=D
-  @SP // pop into *(ARG)
-  AM=M-1
-  D=M
-  @ARG
-  A=M
-  M=D
-  @ARG // restore SP of caller
-  D=M
-  @SP
-  M=D+1
-  @FRAME // restore THAT
-  D=M
-  @1
-  A=D-A
-  D=M
-  @THAT
-  M=D
-  @FRAME // restore THIS
-  D=M
-  @2
-  A=D-A
-  D=M
-  @THIS
-  M=D
-  @FRAME // restore ARG
-  D=M
-  @3
-  A=D-A
-  D=M
-  @ARG
-  M=D
-  @FRAME // restore LCL
-  D=M
-  @4
-  A=D-A
-  D=M
-  @LCL
-  M=D
-  @RET
-  A=M
-  0;JMP
-").unwrap();
+    hack!($a,
+      @LCL,
+      #("store position of frame"),
+      D=M,
+      @(FRAME),
+      M=D,
+      @5,
+      #("put return address in temp var"),
+      A=D-A,
+      D=M,
+      @(RET),
+      M=D,
+      @SP,
+      #("pop into *(ARG)"),
+      AM=M-1,
+      D=M,
+      @ARG,
+      A=M,
+      M=D,
+      @ARG,
+      #("restore SP of caller"),
+      D=M,
+      @SP,
+      M=D+1,
+      @(FRAME),
+      #("restore THAT"),
+      D=M,
+      @1,
+      A=D-A,
+      D=M,
+      @THAT,
+      M=D,
+      @(FRAME),
+      #("restore THIS"),
+      D=M,
+      @2,
+      A=D-A,
+      D=M,
+      @THIS,
+      M=D,
+      @(FRAME),
+      #("restore ARG"),
+      D=M,
+      @3,
+      A=D-A,
+      D=M,
+      @ARG,
+      M=D,
+      @(FRAME),
+      #("restore LCL"),
+      D=M,
+      @4,
+      A=D-A,
+      D=M,
+      @LCL,
+      M=D,
+      @(RET),
+      A=M,
+      0;JMP,
+    );
+  }}
+}
+
+// `call` immediately followed by `return`, reusing the current frame: the
+// callee gets our return address and saved segments, with its arguments
+// where ours were. The saved frame waits in the variables `return_!` only
+// uses within a return while the arguments move down to ARG, copied one by
+// one with SP and LCL as the pointers; the source is always above the
+// destination, so copying upwards doesn't overwrite anything unread.
+macro_rules! tail_call {
+  ( $a:expr, $function_name:expr, $num_args:expr ) => {{
+    let num_args: i16 = $num_args;
+    // lowest first
+    let saved = [RET, "R13", "R14", "R15", FRAME];
+    hack!($a, #("TAIL CALL {} {}", $function_name, num_args));
+    for (register, offset) in saved.iter().zip((1..=5i16).rev()) {
+      match offset {
+        1 => hack!($a, @LCL, A=M-1, D=M, @(*register), M=D),
+        _ => hack!($a,
+          @LCL,
+          D=M,
+          @(offset),
+          A=D-A,
+          D=M,
+          @(*register),
+          M=D,
+        ),
+      }
+    }
+    // both pointers are incremented before each word
+    hack!($a,
+      @ARG,
+      D=M-1,
+      @LCL,
+      M=D,
+    );
+    if num_args > 0 {
+      hack!($a,
+        @(num_args + 1),
+        D=A,
+        @SP,
+        M=M-D,
+      );
+    }
+    for _ in 0..num_args {
+      hack!($a,
+        @SP,
+        AM=M+1,
+        D=M,
+        @LCL,
+        AM=M+1,
+        M=D,
+      );
+    }
+    for register in saved {
+      hack!($a,
+        @(register),
+        D=M,
+        @LCL,
+        AM=M+1,
+        M=D,
+      );
+    }
+    hack!($a,
+      #("just past the copied frame"),
+      @LCL,
+      MD=M+1,
+      @SP,
+      M=D,
+    );
+    vm_goto!($a, $function_name);
+  }}
+}
+
+pub const SHARED_CALL: &str = "__VM_CALL";
+pub const SHARED_RETURN: &str = "__VM_RETURN";
+
+// Calls through the routine emitted by `shared_call_routines!`, with the
+// target in R13, the number of arguments in R14 and the return address in R15
+macro_rules! call_shared {
+  ( $a:expr, $function_name:expr, $num_args:expr ) => {{
+    let label = new_label!($a);
+    let num_args: i16 = $num_args;
+    hack!($a,
+      #("CALL {} {}", $function_name, num_args),
+      @($function_name),
+      D=A,
+      @R13,
+      M=D,
+    );
+    match num_args {
+      0 => hack!($a, @R14, M=0),
+      1 => hack!($a, @R14, M=1),
+      _ => hack!($a,
+        @(num_args),
+        D=A,
+        @R14,
+        M=D,
+      ),
+    }
+    hack!($a,
+      @(&label),
+      D=A,
+      @R15,
+      M=D,
+      @(SHARED_CALL),
+      0;JMP,
+    );
+    vm_label!($a, label);
+  }}
+}
+
+macro_rules! return_shared {
+  ( $a:expr ) => {{
+    hack!($a,
+      #("RETURN"),
+      @(SHARED_RETURN),
+      0;JMP,
+    );
+  }}
+}
+
+macro_rules! shared_call_routines {
+  ( $a:expr ) => {{
+    vm_label!($a, SHARED_CALL);
+    hack!($a,
+      @R15,
+      D=M,
+    );
+    push_D!($a);
+    // store addresses of caller's segments
+    push_address!($a, Segment::Local);
+    push_address!($a, Segment::Argument);
+    push_address!($a, Segment::This);
+    push_address!($a, Segment::That);
+    hack!($a,
+      #("ARG = SP - 5 - R14"),
+      @R14,
+      D=M,
+      @5,
+      D=D+A,
+      @SP,
+      D=M-D,
+      @ARG,
+      M=D,
+      #("LCL = SP"),
+      @SP,
+      D=M,
+      @LCL,
+      M=D,
+      @R13,
+      A=M,
+      0;JMP,
+    );
+    vm_label!($a, SHARED_RETURN);
+    return_!($a);
+  }}
+}
+
+pub const SHARED_EQ: &str = "__VM_EQ";
+pub const SHARED_GT: &str = "__VM_GT";
+pub const SHARED_LT: &str = "__VM_LT";
+
+// Calls one of the routines emitted by `shared_comparison_routines!`,
+// `multiply_routine!` or `divide_routine!`, with the return address in R15
+macro_rules! call_routine {
+  ( $a:expr, $routine:expr ) => {{
+    let label = new_label!($a);
+    hack!($a,
+      @(&label),
+      D=A,
+      @R15,
+      M=D,
+      @($routine),
+      0;JMP,
+    );
+    vm_label!($a, label);
+  }}
+}
+
+macro_rules! shared_comparison_routines {
+  ( $a:expr ) => {{
+    let at = |routine: &str, name: &str| format!("{}_{}", routine, name);
+    vm_label!($a, SHARED_EQ);
+    pop_D!($a);
+    hack!($a,
+      @SP,
+      A=M-1,
+      D=D-M,
+      @(at(SHARED_EQ, "FALSE")),
+      D;JNE,
+      @SP,
+      A=M-1,
+      M=-1,
+      @R15,
+      A=M,
+      0;JMP,
+      (at(SHARED_EQ, "FALSE")),
+      @SP,
+      A=M-1,
+      M=0,
+      @R15,
+      A=M,
+      0;JMP,
+    );
+    let comparisons = [
+      (SHARED_GT, $crate::hack::asm::Jump::Gt),
+      (SHARED_LT, $crate::hack::asm::Jump::Lt),
+    ];
+    for (routine, jump) in comparisons {
+      vm_label!($a, routine);
+      signed_difference!($a, routine);
+      hack!($a,
+        @(at(routine, "TRUE")),
+        D;(jump),
+        @SP,
+        A=M-1,
+        M=0,
+        @R15,
+        A=M,
+        0;JMP,
+        (at(routine, "TRUE")),
+        @SP,
+        A=M-1,
+        M=-1,
+        @R15,
+        A=M,
+        0;JMP,
+      );
+    }
+  }}
+}
+
+// `call Math.abs 1`
+macro_rules! abs {
+  ( $a:expr ) => {{
+    let label = new_label!($a);
+    hack!($a,
+      #("ABS"),
+      @SP,
+      A=M-1,
+      D=M,
+      @(&label),
+      D;JGE,
+      @SP,
+      A=M-1,
+      M=-D,
+    );
+    vm_label!($a, label);
+  }}
+}
+
+// `call Memory.peek 1`
+macro_rules! peek {
+  ( $a:expr ) => {{
+    hack!($a,
+      #("PEEK"),
+      @SP,
+      A=M-1,
+      A=M,
+      D=M,
+      @SP,
+      A=M-1,
+      M=D,
+    );
+  }}
+}
+
+// `call Memory.poke 2`, which returns 0
+macro_rules! poke {
+  ( $a:expr ) => {{
+    hack!($a,
+      #("POKE"),
+      @SP,
+      AM=M-1,
+      D=M,
+      A=A-1,
+      A=M,
+      M=D,
+      @SP,
+      A=M-1,
+      M=0,
+    );
+  }}
+}
+
+/// The digits of `m` in non-adjacent form, lowest first: each is -1, 0 or 1
+/// and no two adjacent ones are non-zero, so as few as possible are.
+pub fn signed_digits(m: u16) -> Vec<i8> {
+  let mut m = m as i32;
+  let mut digits = Vec::new();
+  while m != 0 {
+    let digit = match m % 4 {
+      1 => 1,
+      3 => -1,
+      _ => 0,
+    };
+    m = (m - digit) / 2;
+    digits.push(digit as i8);
+  }
+  digits
+}
+
+/// Instructions it takes to push a constant and call a shared routine with
+/// it.
+pub const CONSTANT_ROUTINE_CALL_SIZE: usize = 12;
+
+/// Instructions `multiply_by!` takes.
+pub fn multiply_by_size(constant: i16) -> usize {
+  let digits = signed_digits(constant.unsigned_abs());
+  let nonzero = digits.iter().filter(|digit| **digit != 0).count();
+  let negate = usize::from(constant < 0);
+  match constant {
+    1 => 0,
+    0 => 3,
+    _ if nonzero == 1 => 2 * digits.len() + negate,
+    _ => 8 + 3 * (digits.len() - 1) + 2 * (nonzero - 1) + negate,
+  }
+}
+
+// `push constant c, call Math.multiply 2`: x*c by doubling x and adding or
+// subtracting it as `signed_digits` of c says, with x in R13
+macro_rules! multiply_by {
+  ( $a:expr, $constant:expr ) => {{
+    let constant: i16 = $constant;
+    let digits = signed_digits(constant.unsigned_abs());
+    let nonzero = digits.iter().filter(|digit| **digit != 0).count();
+    if constant != 1 {
+      hack!($a,
+        #("MULTIPLY BY {}", constant),
+        @SP,
+        A=M-1,
+      );
+    }
+    if constant == 0 {
+      hack!($a, M=0);
+    } else if nonzero == 1 {
+      // a power of two: double x in place
+      for _ in 1..digits.len() {
+        hack!($a, D=M, M=D+M);
+      }
+      if constant < 0 {
+        hack!($a, M=-M);
+      }
+    } else {
+      hack!($a,
+        D=M,
+        @R13,
+        M=D,
+      );
+      for digit in digits.iter().rev().skip(1) {
+        hack!($a,
+          @R14,
+          M=D,
+          D=D+M,
+        );
+        match digit {
+          1 => hack!($a, @R13, D=D+M),
+          -1 => hack!($a, @R13, D=D-M),
+          _ => (),
+        }
+      }
+      if constant < 0 {
+        hack!($a, D=-D);
+      }
+      hack!($a,
+        @SP,
+        A=M-1,
+        M=D,
+      );
+    }
+  }}
+}
+
+/// Steps of the long division in `divide_by!`: one for each multiple of
+/// |c| by a power of two up to 32768.
+pub fn divide_steps(constant: i16) -> usize {
+  let divisor = constant.unsigned_abs() as u32;
+  (0..15).take_while(|shift| divisor << shift <= 32768).count()
+}
+
+/// Instructions `divide_by!` takes.
+pub fn divide_by_size(constant: i16) -> usize {
+  match constant {
+    1 => 0,
+    -1 => 3,
+    _ => 22 + 12 * (divide_steps(constant) - 1) + 10,
+  }
+}
+
+// `push constant c, call Math.divide 2` for c other than 0, truncating
+// towards 0: long division of -|x|, which unlike |x| always fits, by the
+// multiples of |c|, with what's left of it in R14 and the quotient in R13
+macro_rules! divide_by {
+  ( $a:expr, $constant:expr ) => {{
+    let constant: i16 = $constant;
+    match constant {
+      1 => (),
+      -1 => neg!($a),
+      _ => {
+        let label = new_label!($a);
+        let at = |name: &str| format!("{}_{}", label, name);
+        hack!($a,
+          #("DIVIDE BY {}", constant),
+          @SP,
+          A=M-1,
+          D=M,
+          @(at("NEGATIVE")),
+          D;JLT,
+          D=-D,
+          (at("NEGATIVE")),
+          @R14,
+          M=D,
+          @R13,
+          M=0,
+        );
+        for shift in (0..divide_steps(constant)).rev() {
+          // what's left fits a multiple v if it's at most -v
+          let multiple = (constant.unsigned_abs() as i32) << shift;
+          let fits = at(&shift.to_string());
+          hack!($a,
+            @R14,
+            D=M,
+            @(multiple - 1),
+            D=D+A,
+            @(&fits),
+            D;JGE,
+            @R14,
+            M=D+1,
+          );
+          match shift {
+            0 => hack!($a, @R13, M=M+1),
+            _ => hack!($a, @(1i32 << shift), D=A, @R13, M=D+M),
+          }
+          vm_label!($a, fits);
+        }
+        let jump = match constant > 0 {
+          true => $crate::hack::asm::Jump::Ge,
+          false => $crate::hack::asm::Jump::Lt,
+        };
+        hack!($a,
+          @SP,
+          A=M-1,
+          D=M,
+          @(at("SIGNED")),
+          D;(jump),
+          @R13,
+          M=-M,
+          (at("SIGNED")),
+          @R13,
+          D=M,
+          @SP,
+          A=M-1,
+          M=D,
+        );
+      },
+    }
+  }}
+}
+
+pub const SHARED_MULTIPLY: &str = "__VM_MULTIPLY";
+pub const SHARED_DIVIDE: &str = "__VM_DIVIDE";
+
+// Replaces x and y on the stack with x*y: adds x shifted left to the result
+// for each bit of y, clearing the bit in y's old slot until none are left
+macro_rules! multiply_routine {
+  ( $a:expr ) => {{
+    let at = |name: &str| format!("{}_{}", SHARED_MULTIPLY, name);
+    hack!($a,
+      (SHARED_MULTIPLY),
+      @SP,
+      AM=M-1,
+      D=M,
+      @(at("POSITIVE")),
+      D;JGE,
+      @SP,
+      A=M,
+      M=-D,
+      A=A-1,
+      M=-M,
+      (at("POSITIVE")),
+      @SP,
+      A=M-1,
+      D=M,
+      M=0,
+      @R13,
+      M=D,
+      @R14,
+      M=1,
+      (at("LOOP")),
+      @SP,
+      A=M,
+      D=M,
+      @R15,
+      A=M,
+      D;JEQ,
+      @R14,
+      D=D&M,
+      @(at("NEXT")),
+      D;JEQ,
+      @SP,
+      A=M,
+      M=M-D,
+      @R13,
+      D=M,
+      @SP,
+      A=M-1,
+      M=D+M,
+      (at("NEXT")),
+      @R13,
+      D=M,
+      M=D+M,
+      @R14,
+      D=M,
+      M=D+M,
+      @(at("LOOP")),
+      0;JMP,
+    );
+  }}
+}
+
+// Replaces x and y on the stack with x/y, truncated towards 0: long
+// division of -|x| by -|y| doubled as often as fits, keeping the multiples
+// above the stack, with what's left of -|x| in x's slot, the quotient in R14
+// and whether to negate it in y's old slot. Calls `function` to divide by 0,
+// which is an error.
+macro_rules! divide_routine {
+  ( $a:expr, $function:expr ) => {{
+    let at = |name: &str| format!("{}_{}", SHARED_DIVIDE, name);
+    hack!($a,
+      (SHARED_DIVIDE),
+      @SP,
+      A=M-1,
+      D=M,
+      @(at("BY_ZERO")),
+      D;JEQ,
+      @R14,
+      M=0,
+      @SP,
+      AM=M-1,
+      M=0,
+      @(at("Y")),
+      D;JLT,
+      D=-D,
+      @SP,
+      A=M,
+      M=!M,
+      (at("Y")),
+      @SP,
+      A=M+1,
+      M=D,
+      D=A,
+      @R13,
+      M=D,
+      @SP,
+      A=M-1,
+      D=M,
+      @(at("UP")),
+      D;JLE,
+      @SP,
+      A=M-1,
+      M=-D,
+      @SP,
+      A=M,
+      M=!M,
+    );
+    hack!($a,
+      (at("UP")),
+      @R13,
+      A=M,
+      D=M,
+      @16384,
+      D=D+A,
+      @(at("DOWN")),
+      D;JLT,
+      @R13,
+      A=M,
+      D=M,
+      D=D+M,
+      @SP,
+      A=M-1,
+      D=D-M,
+      @(at("DOWN")),
+      D;JLT,
+      @R13,
+      M=M+1,
+      A=M-1,
+      D=M,
+      A=A+1,
+      M=D,
+      M=D+M,
+      @(at("UP")),
+      0;JMP,
+    );
+    hack!($a,
+      (at("DOWN")),
+      @R14,
+      D=M,
+      M=D+M,
+      @R13,
+      A=M,
+      D=M,
+      @SP,
+      A=M-1,
+      D=D-M,
+      @(at("NEXT")),
+      D;JLT,
+      @SP,
+      A=M-1,
+      M=-D,
+      @R14,
+      M=M+1,
+    );
+    hack!($a,
+      (at("NEXT")),
+      @R13,
+      M=M-1,
+      D=M,
+      @SP,
+      D=D-M,
+      @(at("DOWN")),
+      D;JGT,
+      @SP,
+      A=M,
+      D=M,
+      @(at("SIGNED")),
+      D;JEQ,
+      @R14,
+      M=-M,
+      (at("SIGNED")),
+      @R14,
+      D=M,
+      @SP,
+      A=M-1,
+      M=D,
+      @R15,
+      A=M,
+      0;JMP,
+    );
+    hack!($a,
+      (at("BY_ZERO")),
+      @R15,
+      D=M,
+    );
+    push_D!($a);
+    push_address!($a, Segment::Local);
+    push_address!($a, Segment::Argument);
+    push_address!($a, Segment::This);
+    push_address!($a, Segment::That);
+    hack!($a,
+      @SP,
+      D=M,
+      @7,
+      D=D-A,
+      @ARG,
+      M=D,
+      @SP,
+      D=M,
+      @LCL,
+      M=D,
+    );
+    vm_goto!($a, $function);
   }}
 }
 
 #[derive(Debug)]
 pub struct AssemblyBuilder {
-  pub buffer: Vec<u8>,
+  pub instructions: Vec<AsmInstr>,
   label_count: i32,
 }
 
 impl AssemblyBuilder {
   pub fn new() -> AssemblyBuilder {
     let mut builder = AssemblyBuilder {
-      buffer: initial_asm().to_vec(),
+      instructions: Vec::new(),
       label_count: 0,
     };
+    hack!(builder,
+      #("initialize stack pointer to 256"),
+      @256,
+      D=A,
+      @SP,
+      M=D,
+    );
     call!(builder, "Sys.init", 0);
     builder
   }
 
-  pub fn write(&self, stream: &mut Write) -> Result<()> {
-    stream.write_all(&self.buffer[..])
+  pub fn push(&mut self, instruction: AsmInstr) {
+    self.instructions.push(instruction);
+  }
+
+  pub fn write(&self, stream: &mut dyn Write) -> Result<()> {
+    asm::print(&self.instructions, stream)
   }
 
   pub fn next_label_count(&mut self) -> i32 {
@@ -406,12 +1553,49 @@ impl AssemblyBuilder {
   }
 }
 
-fn initial_asm() -> &'static [u8] {
-  b"\
-  // initialize stack pointer to 256
-  @256
-  D=A
-  @SP
-  M=D
-"
+impl Extend<AsmInstr> for AssemblyBuilder {
+  fn extend<I: IntoIterator<Item = AsmInstr>>(&mut self, instructions: I) {
+    self.instructions.extend(instructions);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use super::super::hack::assembler::assemble;
+  use super::super::hack::emulator::Emulator;
+
+  /// Run the prologue of a function with `num_vars` locals on a stack of
+  /// garbage, returning its size and the emulator afterwards.
+  fn run_prologue(num_vars: i16, prefer_size: bool) -> (usize, Emulator) {
+    let mut a = AssemblyBuilder::new();
+    let start = a.instructions.len();
+    function!(a, "Main.f", num_vars, prefer_size);
+    let program = assemble(&a.instructions[start..]).unwrap();
+    let mut emulator = Emulator::new(program.rom.clone());
+    emulator.ram[0] = 300;
+    emulator.ram[300..340].fill(-1);
+    while emulator.step() {}
+    (program.rom.len(), emulator)
+  }
+
+  #[test]
+  fn clears_locals_unrolled() {
+    let (size, emulator) = run_prologue(20, false);
+    assert_eq!(size, 2 * 20 + 4);
+    assert_eq!(emulator.ram[0], 320);
+    assert!(emulator.ram[300..320].iter().all(|&word| word == 0));
+    assert_eq!(emulator.ram[320], -1);
+  }
+
+  #[test]
+  fn clears_locals_in_a_loop_for_size() {
+    let (size, emulator) = run_prologue(20, true);
+    assert_eq!(size, CLEAR_LOOP_SIZE as usize);
+    assert_eq!(emulator.ram[0], 320);
+    assert!(emulator.ram[300..320].iter().all(|&word| word == 0));
+    assert_eq!(emulator.ram[320], -1);
+    // Too few locals for the loop to be shorter.
+    assert_eq!(run_prologue(2, true).0, 2 * 2 + 4);
+  }
 }
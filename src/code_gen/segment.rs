@@ -1,4 +1,9 @@
-#[derive(Debug)]
+use super::super::hack::asm::{Address, AsmInstr};
+
+/// Statics take up RAM from 16 to 255.
+pub const STATIC_WORDS: usize = 240;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum Segment {
   Argument,
   Constant,
@@ -40,77 +45,87 @@ impl Segment {
     }
   }
 
-  pub fn is_valid_name(name: &str) -> bool {
-    match name {
-      "argument" => true,
-      "constant" => true,
-      "local" => true,
-      "pointer" => true,
-      "static" => true,
-      "temp" => true,
-      "this" => true,
-      "that" => true,
-      _ => false,
+  pub fn name(&self) -> &'static str {
+    match self {
+      Segment::Argument => "argument",
+      Segment::Constant => "constant",
+      Segment::Local => "local",
+      Segment::Pointer => "pointer",
+      Segment::Static(_) => "static",
+      Segment::Temp => "temp",
+      Segment::This => "this",
+      Segment::That => "that",
     }
   }
 
+  pub fn is_valid_name(name: &str) -> bool {
+    matches!(
+      name,
+      "argument" | "constant" | "local" | "pointer" | "static" | "temp" | "this" | "that"
+    )
+  }
+
   pub fn is_writable(&self) -> bool {
+    !matches!(self, Segment::Constant)
+  }
+
+  /// Whether `index` lies inside the segment.
+  pub fn is_valid_index(&self, index: i16) -> bool {
     match self {
-      Segment::Constant => false,
-      _ => true,
+      Segment::Pointer => (0..=1).contains(&index),
+      Segment::Temp => (0..=7).contains(&index),
+      Segment::Static(_) => index >= 0 && (index as usize) < STATIC_WORDS,
+      _ => index >= 0,
     }
   }
 
-  pub fn resolve_address(&self, index: i16) -> String {
+  /// What to load into A for `index`, for the segments that sit at a fixed
+  /// address and so need no arithmetic.
+  pub fn fixed_address(&self, index: i16) -> Option<AsmInstr> {
+    match self {
+      Segment::Static(name) => Some(AsmInstr::ASymbol(format!("{}.{}", name, index))),
+      // decode keeps these indexes small, so overflow is a bug
+      Segment::Pointer => Some(Address::load(3i16.checked_add(index).expect("pointer index"))),
+      Segment::Temp => Some(Address::load(5i16.checked_add(index).expect("temp index"))),
+      _ => None,
+    }
+  }
+
+  /// Instructions that leave the address of `index` in A without touching
+  /// D, if that takes at most `budget` of them. Segments based at a pointer
+  /// walk up from it with `A=A+1`, which costs `index + 1`.
+  pub fn address_without_d(&self, index: i16, budget: usize) -> Option<Vec<AsmInstr>> {
+    if let Some(address) = self.fixed_address(index) {
+      return Some(vec![address]);
+    }
+    let mut address = Vec::new();
     match self {
       Segment::Constant => unreachable!(),
-      Segment::Static(name) => format!("@{}.{}\n", name, index),
-      Segment::This
-      | Segment::That
-      | Segment::Local
-      | Segment::Argument => {
-        if index == 0 {
-          format!("
-  @{}
-  A=M
-", self)
-        } else if index == 1 {
-          format!("
-  @{}
-  A=M+1
-", self)
-        } else {
-          format!("\
-  @{}
-  D=A
-  @{}
-  A=D+M
-", index, self)
-        }
-      },
-      Segment::Pointer
-      | Segment::Temp => {
-        let base_address = if let Segment::Pointer = self {
-          "THIS"
-        } else {
-          "5"
-        };
-        if index == 0 {
-          format!("@{}\n", base_address)
-        } else if index == 1 {
-          format!("
-  @{}
-  A=A+1
-", base_address)
-        } else {
-          format!("\
-  @{}
-  D=A
-  @{}
-  A=D+A
-", base_address, index)
+      _ if index == 0 => hack!(address, @(self.to_string()), A=M),
+      _ if (index as usize) < budget => {
+        hack!(address, @(self.to_string()), A=M+1);
+        for _ in 1..index {
+          hack!(address, A=A+1);
         }
       },
+      _ => return None,
     }
+    Some(address)
+  }
+
+  /// Instructions that leave the address of `index` in A, using D if that's
+  /// shorter than walking up from the segment's base.
+  pub fn resolve_address(&self, index: i16) -> Vec<AsmInstr> {
+    // adding the index through D takes 4 instructions
+    self.address_without_d(index, 4).unwrap_or_else(|| {
+      let mut address = Vec::new();
+      hack!(address,
+        @(index),
+        D=A,
+        @(self.to_string()),
+        A=D+M,
+      );
+      address
+    })
   }
 }
@@ -0,0 +1,827 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use super::hack::assembler::{self, Program as HackProgram};
+use super::hack::emulator::Emulator;
+use super::instruction::{Instruction, Module};
+use super::interpreter::{Interpreter, Program};
+use super::pipeline;
+use super::rom;
+use super::translator::{self, CHECKPOINT_PREFIX};
+
+/// How long either side may run before the comparison gives up.
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+  pub vm_steps: u64,
+  pub cpu_cycles: u64,
+}
+
+impl Default for Limits {
+  fn default() -> Limits {
+    Limits {
+      vm_steps: 1_000_000,
+      cpu_cycles: 50_000_000,
+    }
+  }
+}
+
+/// The part of the machine state both executions must agree on, relative to
+/// the current frame so that layout differences in the saved frames below
+/// it don't count as divergence.
+#[derive(Debug, PartialEq)]
+pub struct Snapshot {
+  pub arguments: Vec<i16>,
+  /// Locals and working stack, from LCL up to SP.
+  pub frame: Vec<i16>,
+  pub pointers: [i16; 2],
+  pub temps: [i16; 8],
+  pub statics: BTreeMap<String, i16>,
+  pub heap: BTreeMap<u16, i16>,
+}
+
+impl Snapshot {
+  pub fn capture(
+    ram: &[i16],
+    num_args: i16,
+    statics: BTreeMap<String, i16>,
+    heap_addresses: &BTreeSet<u16>,
+  ) -> Snapshot {
+    let read = |address: i32| ram[(address as u16 & 0x7fff) as usize];
+    let (sp, lcl, arg) = (ram[0] as i32, ram[1] as i32, ram[2] as i32);
+    let mut temps = [0; 8];
+    temps.copy_from_slice(&ram[5..13]);
+    Snapshot {
+      arguments: (0..num_args as i32).map(|i| read(arg + i)).collect(),
+      frame: (lcl..sp.max(lcl)).map(read).collect(),
+      pointers: [ram[3], ram[4]],
+      temps,
+      statics,
+      heap: heap_addresses.iter().map(|address| (*address, ram[*address as usize])).collect(),
+    }
+  }
+
+  /// Describe every way in which `other` (the assembly's state) differs from
+  /// this one (the interpreter's state). Both must have been captured with
+  /// the same statics and heap addresses.
+  pub fn differences(&self, other: &Snapshot) -> Vec<String> {
+    let mut differences = Vec::new();
+    compare_slices(&mut differences, "argument", &self.arguments, &other.arguments);
+    if self.frame.len() != other.frame.len() {
+      differences.push(format!(
+        "frame depth (SP - LCL): vm={} asm={}", self.frame.len(), other.frame.len(),
+      ));
+    }
+    compare_slices(&mut differences, "frame", &self.frame, &other.frame);
+    compare_slices(&mut differences, "pointer", &self.pointers, &other.pointers);
+    compare_slices(&mut differences, "temp", &self.temps, &other.temps);
+    for ((name, ours), theirs) in self.statics.iter().zip(other.statics.values()) {
+      if ours != theirs {
+        differences.push(format!("static {}: vm={} asm={}", name, ours, theirs));
+      }
+    }
+    for ((address, ours), theirs) in self.heap.iter().zip(other.heap.values()) {
+      if ours != theirs {
+        differences.push(format!("RAM[{}]: vm={} asm={}", address, ours, theirs));
+      }
+    }
+    differences
+  }
+}
+
+fn compare_slices(differences: &mut Vec<String>, name: &str, ours: &[i16], theirs: &[i16]) {
+  for (i, (a, b)) in ours.iter().zip(theirs).enumerate() {
+    if a != b {
+      differences.push(format!("{} {}: vm={} asm={}", name, i, a, b));
+    }
+  }
+}
+
+#[derive(Debug)]
+pub enum Outcome {
+  /// Both executions agreed at every checkpoint up to the end.
+  Agreed { halted: bool },
+  /// The states differed after executing `commands`.
+  Diverged { commands: Vec<String>, differences: Vec<String> },
+}
+
+#[derive(Debug)]
+pub struct Report {
+  pub outcome: Outcome,
+  pub checkpoints: u64,
+  pub vm_steps: u64,
+  pub cpu_cycles: u64,
+}
+
+impl Report {
+  pub fn diverged(&self) -> bool {
+    matches!(self.outcome, Outcome::Diverged { .. })
+  }
+}
+
+impl std::fmt::Display for Report {
+  fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    match &self.outcome {
+      Outcome::Agreed { halted } => writeln!(
+        f,
+        "OK: {} checkpoints matched{}",
+        self.checkpoints,
+        if *halted { "" } else { " (stopped at the step limit)" },
+      )?,
+      Outcome::Diverged { commands, differences } => {
+        writeln!(f, "DIVERGED after {} matching checkpoints", self.checkpoints)?;
+        match commands.last() {
+          Some(last) => writeln!(f, "first diverging command: {}", last)?,
+          None => writeln!(f, "diverged before executing any command")?,
+        }
+        if commands.len() > 1 {
+          writeln!(f, "commands executed since the last checkpoint:")?;
+          for command in commands {
+            writeln!(f, "  {}", command)?;
+          }
+        }
+        for difference in differences {
+          writeln!(f, "  {}", difference)?;
+        }
+      },
+    }
+    write!(f, "{} VM steps, {} CPU cycles", self.vm_steps, self.cpu_cycles)
+  }
+}
+
+/// Run `modules` in the VM interpreter and, in lockstep, their translation
+/// in the Hack emulator, comparing states at every command boundary the
+/// translation marks with a checkpoint. The VM-level passes enabled in
+/// `options` only apply to the translation, so they're checked too. A
+/// translation that doesn't fit in the ROM is an error.
+pub fn run(modules: &[Module], options: translator::Options, limits: Limits) -> Result<Report, String> {
+  let program = Program::load(modules)?;
+  let options = translator::Options { checkpoints: true, ..options };
+  let compiled = pipeline::compile(modules, options)?;
+  rom::check(pipeline::size(&compiled.translator), None)?;
+  let hack = assembler::assemble(compiled.translator.instructions())?;
+  run_translated(&program, &hack, &origins(&program, &compiled.modules), limits)
+}
+
+/// For every instruction in `optimised`, the index in `program` of the
+/// command at the same source position, if there is one.
+fn origins(program: &Program, optimised: &[Module]) -> Vec<Option<usize>> {
+  let positions: HashMap<(&str, usize, usize), usize> = program.instructions.iter()
+    .zip(&program.modules)
+    .enumerate()
+    .map(|(index, (located, module))| ((*module, located.line, located.column), index))
+    .collect();
+  optimised.iter().flat_map(|module| {
+    let positions = &positions;
+    module.instructions.iter().map(move |located| {
+      positions.get(&(module.name.as_str(), located.line, located.column)).cloned()
+    })
+  }).collect()
+}
+
+/// Like `run`, where checkpoint n stands for the command `origins[n]` of
+/// `program`; checkpoints without an origin are ignored.
+pub fn run_translated(
+  program: &Program,
+  hack: &HackProgram,
+  origins: &[Option<usize>],
+  limits: Limits,
+) -> Result<Report, String> {
+  let mut checkpoints: HashMap<u16, Vec<usize>> = HashMap::new();
+  for (label, address) in &hack.labels {
+    if let Some(index) = label.strip_prefix(CHECKPOINT_PREFIX) {
+      if let Some(origin) = origins[index.parse::<usize>().unwrap()] {
+        checkpoints.entry(*address).or_default().push(origin);
+      }
+    }
+  }
+  let checkpointed: BTreeSet<usize> = checkpoints.values().flatten().cloned().collect();
+
+  let mut vm = Interpreter::new(program)?;
+  let mut cpu = Emulator::new(hack.rom.clone());
+  let mut matched = 0;
+
+  macro_rules! report {
+    ( $outcome:expr ) => {
+      return Ok(Report {
+        outcome: $outcome,
+        checkpoints: matched,
+        vm_steps: vm.steps,
+        cpu_cycles: cpu.cycles,
+      })
+    };
+  }
+  macro_rules! diverged {
+    ( $commands:expr, $($fmtargs:expr),* ) => {
+      report!(Outcome::Diverged {
+        commands: $commands,
+        differences: vec![format!($($fmtargs),*)],
+      })
+    };
+  }
+
+  loop {
+    loop {
+      if !cpu.step() {
+        diverged!(Vec::new(), "assembly ran off the end of the ROM at {}", cpu.pc);
+      }
+      if checkpoints.contains_key(&cpu.pc) {
+        break;
+      }
+      if cpu.cycles >= limits.cpu_cycles {
+        report!(Outcome::Agreed { halted: false });
+      }
+    }
+    let targets = &checkpoints[&cpu.pc];
+
+    let mut executed = Vec::new();
+    // calls made since the last checkpoint may have been inlined, so only
+    // this frame has to stay in step
+    let depth = vm.frames.len();
+    // reaching a checkpoint again means at least one command ran, even if
+    // it's the same one, as when recursive calls return to the same place
+    while !targets.contains(&vm.pc) || matched > 0 && executed.is_empty() {
+      if vm.is_halted() || vm.steps >= limits.vm_steps {
+        diverged!(executed, "assembly reached checkpoint {} but the VM program stopped", cpu.pc);
+      }
+      if !executed.is_empty() && vm.frames.len() <= depth && checkpointed.contains(&vm.pc) {
+        diverged!(executed, "control flow diverged: VM is at {}", program.describe(vm.pc));
+      }
+      executed.push(program.describe(vm.pc));
+      vm.step()?;
+    }
+    // commands that translate to no code share their checkpoint with the
+    // command after them
+    while targets.contains(&(vm.pc + 1)) && emits_no_code(&program.instructions[vm.pc].instruction) {
+      executed.push(program.describe(vm.pc));
+      vm.step()?;
+    }
+
+    let heap_addresses = vm.heap_writes.union(&cpu.heap_writes).cloned().collect();
+    let vm_statics = program.statics.iter().map(|name| {
+      (name.clone(), vm.statics.get(name).cloned().unwrap_or(0))
+    }).collect();
+    let cpu_statics = program.statics.iter().map(|name| {
+      let value = hack.variables.get(name).map_or(0, |address| cpu.ram[*address as usize]);
+      (name.clone(), value)
+    }).collect();
+    let expected = Snapshot::capture(&vm.ram, vm.num_args(), vm_statics, &heap_addresses);
+    let actual = Snapshot::capture(&cpu.ram, vm.num_args(), cpu_statics, &heap_addresses);
+
+    let differences = expected.differences(&actual);
+    if !differences.is_empty() {
+      report!(Outcome::Diverged { commands: executed, differences });
+    }
+    matched += 1;
+
+    if vm.is_halted() {
+      report!(Outcome::Agreed { halted: true });
+    }
+    if vm.steps >= limits.vm_steps {
+      report!(Outcome::Agreed { halted: false });
+    }
+  }
+}
+
+fn emits_no_code(instruction: &Instruction) -> bool {
+  matches!(instruction, Instruction::Label(_) | Instruction::Function(_, 0))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use super::super::lexer::lex;
+  use super::super::parser::parse;
+  use super::super::instruction::decode;
+
+  fn modules(sources: &[(&str, &str)]) -> Vec<Module> {
+    sources.iter().map(|(name, source)| {
+      let tokens = lex(source);
+      decode(name, &parse(&tokens)).unwrap()
+    }).collect()
+  }
+
+  /// Check `sources` under the default options and every preset.
+  fn check(sources: &[(&str, &str)]) {
+    let presets = ["0", "1", "2", "s"].iter().map(|level| pipeline::preset(level).unwrap());
+    check_with(sources, std::iter::once(translator::Options::default()).chain(presets));
+  }
+
+  fn check_with<I>(sources: &[(&str, &str)], options: I)
+    where I: IntoIterator<Item = translator::Options>
+  {
+    let modules = modules(sources);
+    for options in options {
+      let report = run(&modules, options, Limits::default()).unwrap();
+      assert!(
+        matches!(report.outcome, Outcome::Agreed { halted: true }),
+        "{:?}: {}", options, report,
+      );
+    }
+  }
+
+  #[test]
+  fn arithmetic() {
+    check(&[("Sys", "
+      function Sys.init 2
+        push constant 7
+        push constant 12
+        add
+        pop local 0
+        push local 0
+        push constant 30
+        sub
+        neg
+        pop static 0
+        push constant 21845
+        push constant 13107
+        and
+        push constant 1
+        or
+        not
+        pop temp 3
+        push constant 3000
+        pop pointer 1
+        push local 0
+        pop that 2
+        push that 2
+        push constant 32767
+        add
+        pop static 1
+      label END
+        goto END
+    ")]);
+  }
+
+  #[test]
+  fn compare() {
+    check(&[("Sys", "
+      function Sys.init 1
+        push constant 5
+        push constant 5
+        eq
+        pop static 0
+        push constant 3
+        neg
+        push constant 4
+        gt
+        pop static 1
+        push constant 3
+        neg
+        push constant 4
+        lt
+        pop static 2
+        push constant 9
+        pop local 0
+      label LOOP
+        push local 0
+        push constant 1
+        sub
+        pop local 0
+        push local 0
+        push constant 2
+        gt
+        if-goto LOOP
+        push local 0
+        pop static 3
+      label END
+        goto END
+    ")]);
+  }
+
+  #[test]
+  fn values_across_labels() {
+    check(&[("Sys", "
+      function Sys.init 2
+        push constant 3
+        pop local 0
+      label LOOP
+        push constant 10
+        push local 0
+        push constant 1
+        and
+        if-goto ODD
+        push local 0
+        goto JOIN
+      label ODD
+        push constant 100
+      label JOIN
+        add
+        push local 1
+        add
+        pop local 1
+        push local 0
+        push constant 1
+        sub
+        pop local 0
+        push local 0
+        if-goto LOOP
+        push local 1
+        pop static 0
+      label END
+        goto END
+    ")]);
+  }
+
+  /// Local 0 of `Sys.init` is at 261, after the bootstrap's call frame.
+  #[test]
+  fn that_aliases_locals() {
+    check(&[("Sys", "
+      function Sys.init 1
+        push constant 5
+        pop local 0
+        push constant 261
+        pop pointer 1
+        push constant 9
+        pop that 0
+        push local 0
+        pop static 0
+        push constant 7
+        pop local 0
+        push that 0
+        pop static 1
+        push constant 0
+        pop local 0
+      label END
+        goto END
+    ")]);
+  }
+
+  #[test]
+  fn call_and_return() {
+    check(&[
+      ("Sys", "
+        function Sys.init 0
+          push constant 10
+          call Main.fib 1
+          pop static 0
+          push constant 3
+          push constant 4
+          push constant 5
+          call Main.weigh 3
+          pop static 1
+        label END
+          goto END
+      "),
+      ("Main", "
+        function Main.fib 0
+          push argument 0
+          push constant 2
+          lt
+          if-goto BASE
+          push argument 0
+          push constant 1
+          sub
+          call Main.fib 1
+          push argument 0
+          push constant 2
+          sub
+          call Main.fib 1
+          add
+          return
+        label BASE
+          push argument 0
+          return
+        function Main.weigh 2
+          push argument 0
+          push argument 1
+          add
+          pop local 0
+          push local 0
+          push argument 2
+          sub
+          pop local 1
+          push local 1
+          push local 0
+          add
+          return
+      "),
+    ]);
+  }
+
+  #[test]
+  fn statics() {
+    check(&[
+      ("Sys", "
+        function Sys.init 0
+          push constant 1
+          pop static 0
+          call Main.set 0
+          pop temp 0
+          call Util.set 0
+          pop temp 0
+          push static 0
+          call Main.get 0
+          add
+          call Util.get 0
+          add
+          pop static 1
+        label END
+          goto END
+      "),
+      ("Main", "
+        function Main.set 0
+          push constant 20
+          pop static 0
+          push constant 0
+          return
+        function Main.get 0
+          push static 0
+          return
+      "),
+      ("Util", "
+        function Util.set 0
+          push constant 300
+          pop static 0
+          push constant 0
+          return
+        function Util.get 0
+          push static 0
+          return
+      "),
+    ]);
+  }
+
+  /// The operands are as far apart as they get, so x-y overflows: fast
+  /// comparisons must see that and compare the signs instead.
+  #[test]
+  fn compare_extremes() {
+    let mut fast = translator::Options::default();
+    pipeline::find("fast-compare").unwrap().set(&mut fast, true);
+    let mut fused = fast;
+    pipeline::find("fuse-branches").unwrap().set(&mut fused, true);
+    check_with(&[("Sys", "
+      function Sys.init 0
+        push constant 32767
+        push constant 32767
+        neg
+        push constant 1
+        sub
+        gt
+        pop static 0
+        push constant 32767
+        neg
+        push constant 1
+        sub
+        push constant 32767
+        lt
+        pop static 1
+        push constant 32767
+        neg
+        push constant 1
+        sub
+        push constant 32767
+        neg
+        push constant 1
+        sub
+        eq
+        pop static 2
+        push constant 32767
+        push constant 32767
+        neg
+        push constant 1
+        sub
+        call Sys.compare 2
+        pop static 3
+      label END
+        goto END
+      function Sys.compare 0
+        push argument 0
+        push argument 1
+        gt
+        push argument 1
+        push argument 0
+        lt
+        and
+        push argument 1
+        push argument 1
+        eq
+        and
+        push argument 0
+        push argument 1
+        lt
+        if-goto WRONG
+        return
+      label WRONG
+        push constant 0
+        return
+    ")], [translator::Options::default(), fast, fused]);
+  }
+
+  /// `Sys.count` passes fewer arguments than `Sys.wide` takes, so moving
+  /// them down overwrites the frame being reused.
+  #[test]
+  fn tail_calls() {
+    let mut tail = translator::Options::default();
+    pipeline::find("tail-calls").unwrap().set(&mut tail, true);
+    check_with(&[("Sys", "
+      function Sys.init 0
+        push constant 40
+        call Sys.count 1
+        pop static 0
+      label END
+        goto END
+      function Sys.count 0
+        push argument 0
+        push constant 0
+        eq
+        if-goto DONE
+        push argument 0
+        push constant 1
+        sub
+        push constant 1
+        push constant 2
+        push constant 3
+        push constant 4
+        push constant 5
+        call Sys.wide 6
+        return
+      label DONE
+        push constant 0
+        call Sys.done 0
+        return
+      function Sys.done 0
+        push static 1
+        return
+      function Sys.wide 2
+        push argument 1
+        push argument 5
+        add
+        pop static 1
+        push argument 0
+        call Sys.count 1
+        return
+    ")], [tail, pipeline::preset("2").unwrap(), pipeline::preset("s").unwrap()]);
+  }
+
+  /// Dividing by 0 falls back to the program's own `Math.divide`, which has
+  /// to survive `drop-unused`, and `-32768 / -1` overflows back to -32768.
+  #[test]
+  fn divide_intrinsics() {
+    let presets = ["0", "1", "2", "s"].iter().map(|level| {
+      let mut options = pipeline::preset(level).unwrap();
+      pipeline::find("intrinsics").unwrap().set(&mut options, true);
+      options
+    });
+    check_with(&[
+      ("Sys", "
+        function Sys.init 0
+          push constant 32767
+          neg
+          push constant 1
+          sub
+          pop static 0
+          push constant 1
+          neg
+          pop static 1
+          push static 0
+          push static 1
+          call Math.divide 2
+          pop static 2
+          push constant 5
+          push static 3
+          call Math.divide 2
+          pop static 4
+          push static 0
+          push constant 0
+          call Math.divide 2
+          pop static 5
+          push constant 100
+          push constant 7
+          neg
+          call Math.divide 2
+          pop static 6
+          push static 0
+          push constant 7
+          call Math.multiply 2
+          pop static 7
+          push static 0
+          push static 1
+          call Math.multiply 2
+          pop static 8
+        label END
+          goto END
+      "),
+      // like the routines: dividing subtracts |y| from -|x|, which unlike
+      // |x| always fits, so -32768 / -1 wraps around to -32768
+      ("Math", "
+        function Math.divide 3
+          push argument 1
+          push constant 0
+          eq
+          if-goto BY_ZERO
+          push argument 0
+          push constant 0
+          lt
+          push argument 1
+          push constant 0
+          lt
+          eq
+          pop local 2
+          push argument 0
+          pop local 0
+          push argument 0
+          push constant 0
+          lt
+          if-goto X_NEGATIVE
+          push argument 0
+          neg
+          pop local 0
+        label X_NEGATIVE
+          push argument 1
+          push constant 0
+          lt
+          if-goto LOOP
+          push argument 1
+          neg
+          pop argument 1
+        label LOOP
+          push local 0
+          push argument 1
+          gt
+          if-goto DONE
+          push local 0
+          push argument 1
+          sub
+          pop local 0
+          push local 1
+          push constant 1
+          add
+          pop local 1
+          goto LOOP
+        label DONE
+          push local 2
+          if-goto SIGNED
+          push local 1
+          neg
+          return
+        label SIGNED
+          push local 1
+          return
+        label BY_ZERO
+          push constant 1
+          neg
+          return
+        function Math.multiply 1
+        label MULTIPLY
+          push argument 1
+          push constant 0
+          eq
+          if-goto PRODUCT
+          push argument 1
+          push constant 0
+          lt
+          if-goto NEGATIVE
+          push local 0
+          push argument 0
+          add
+          pop local 0
+          push argument 1
+          push constant 1
+          sub
+          pop argument 1
+          goto MULTIPLY
+        label NEGATIVE
+          push local 0
+          push argument 0
+          sub
+          pop local 0
+          push argument 1
+          push constant 1
+          add
+          pop argument 1
+          goto MULTIPLY
+        label PRODUCT
+          push local 0
+          return
+      "),
+    ], presets);
+  }
+
+  /// Labels past the end of the ROM wrap around, so running the translation
+  /// would report made-up divergences.
+  #[test]
+  fn rejects_programs_too_big_for_the_rom() {
+    let mut source = String::from("function Sys.init 0\n");
+    for index in 1..=500 {
+      for function in ["Math.multiply", "Math.divide"] {
+        source += &format!(
+          "push static 0\npush constant {}\ncall {} 2\npop static 0\n",
+          index, function,
+        );
+      }
+    }
+    source += "label END\ngoto END\n";
+    let math = "
+      function Math.multiply 0
+        push constant 0
+        return
+      function Math.divide 0
+        push constant 0
+        return
+    ";
+    let mut options = translator::Options::default();
+    pipeline::find("intrinsics").unwrap().set(&mut options, true);
+    let error = run(&modules(&[("Sys", &source), ("Math", math)]), options, Limits::default())
+      .unwrap_err();
+    assert!(error.contains("the ROM only holds"), "{}", error);
+  }
+}
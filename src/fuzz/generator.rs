@@ -0,0 +1,344 @@
+use std::fmt::Write;
+
+use super::rng::Rng;
+use super::super::instruction::Operation;
+
+/// Lowest and highest address `pointer` may be set to, so that `this` and
+/// `that` accesses always land in the heap.
+const HEAP_LOW: i32 = 2048;
+const HEAP_HIGH: i32 = 16000;
+
+const FILES: [&str; 3] = ["Main", "Util", "Math2"];
+const BINARY: [Operation; 7] = [
+  Operation::Add, Operation::Sub, Operation::Eq, Operation::Gt,
+  Operation::Lt, Operation::And, Operation::Or,
+];
+const UNARY: [Operation; 2] = [Operation::Neg, Operation::Not];
+
+/// A memory location an expression can read from or a statement can write to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Place {
+  pub segment: &'static str,
+  pub index: i16,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+  Constant(i16),
+  Load(Place),
+  Unary(Operation, Box<Expr>),
+  Binary(Operation, Box<Expr>, Box<Expr>),
+  /// Call the function with the given index in `Program::functions`.
+  Call(usize, Vec<Expr>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Statement {
+  Assign(Place, Expr),
+  /// Point `this` (0) or `that` (1) at a heap address.
+  SetPointer(i16, i16),
+  If(Expr, Vec<Statement>, Vec<Statement>),
+  /// Run `body` `iterations` times, counting down in the reserved local
+  /// `counter`, which nothing else writes to.
+  Loop { counter: i16, iterations: i16, body: Vec<Statement> },
+  Return(Expr),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Function {
+  pub file: &'static str,
+  pub name: String,
+  pub num_args: i16,
+  /// Locals the body may freely assign to; loop counters come after these.
+  pub num_plain_locals: i16,
+  pub num_locals: i16,
+  pub body: Vec<Statement>,
+  pub result: Expr,
+}
+
+/// A structured VM program. Functions may only call functions that come
+/// after them, which keeps the call graph acyclic; the first function is
+/// always `Sys.init`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Program {
+  pub functions: Vec<Function>,
+}
+
+struct Generator<'r> {
+  rng: &'r mut Rng,
+  num_functions: usize,
+  arities: Vec<i16>,
+  current: usize,
+  num_args: i16,
+  num_plain_locals: i16,
+  num_locals: i16,
+  budget: usize,
+}
+
+pub fn generate(rng: &mut Rng) -> Program {
+  let num_functions = rng.range(1, 5) as usize;
+  let arities: Vec<i16> = (0..num_functions)
+    .map(|i| if i == 0 { 0 } else { rng.range(0, 3) as i16 })
+    .collect();
+
+  let mut functions = Vec::new();
+  for (i, num_args) in arities.iter().enumerate() {
+    let file = if i == 0 { "Sys" } else { *rng.choose(&FILES) };
+    let num_plain_locals = rng.range(0, 4) as i16;
+    let mut generator = Generator {
+      rng,
+      num_functions,
+      arities: arities.clone(),
+      current: i,
+      num_args: *num_args,
+      num_plain_locals,
+      num_locals: num_plain_locals,
+      budget: 30,
+    };
+
+    let mut body = Vec::new();
+    if i == 0 {
+      body.push(Statement::SetPointer(0, generator.heap_address()));
+      body.push(Statement::SetPointer(1, generator.heap_address()));
+    }
+    body.extend(generator.block(0));
+    let result = generator.expr(0);
+
+    functions.push(Function {
+      file,
+      name: if i == 0 { String::from("Sys.init") } else { format!("{}.f{}", file, i) },
+      num_args: *num_args,
+      num_plain_locals,
+      num_locals: generator.num_locals,
+      body,
+      result,
+    });
+  }
+
+  Program { functions }
+}
+
+impl<'r> Generator<'r> {
+  fn heap_address(&mut self) -> i16 {
+    self.rng.range(HEAP_LOW, HEAP_HIGH) as i16
+  }
+
+  fn constant(&mut self) -> i16 {
+    match self.rng.below(4) {
+      0 => *self.rng.choose(&[0, 1, -1, 2, 16384, 32767, -32768, -32767]),
+      1 => self.rng.range(-32768, 32767) as i16,
+      _ => self.rng.range(-10, 10) as i16,
+    }
+  }
+
+  fn place(&mut self, writable: bool) -> Place {
+    loop {
+      let (segment, count) = match self.rng.below(7) {
+        0 => ("argument", self.num_args),
+        1 => ("local", if writable { self.num_plain_locals } else { self.num_locals }),
+        2 => ("static", 8),
+        3 => ("temp", 8),
+        4 => ("this", 8),
+        5 => ("that", 8),
+        _ if !writable => ("pointer", 2),
+        _ => continue,
+      };
+      if count > 0 {
+        return Place { segment, index: self.rng.below(count as usize) as i16 };
+      }
+    }
+  }
+
+  fn expr(&mut self, depth: usize) -> Expr {
+    let leaf = depth >= 3 || self.budget == 0;
+    match self.rng.below(if leaf { 2 } else { 6 }) {
+      0 => Expr::Constant(self.constant()),
+      1 => Expr::Load(self.place(false)),
+      2 => Expr::Unary(*self.rng.choose(&UNARY), Box::new(self.expr(depth + 1))),
+      3 | 4 => {
+        let op = *self.rng.choose(&BINARY);
+        Expr::Binary(op, Box::new(self.expr(depth + 1)), Box::new(self.expr(depth + 1)))
+      },
+      _ => {
+        if self.current + 1 >= self.num_functions {
+          return Expr::Constant(self.constant());
+        }
+        self.budget = self.budget.saturating_sub(1);
+        let callee = self.rng.range(self.current as i32 + 1, self.num_functions as i32 - 1) as usize;
+        let args = (0..self.arities[callee]).map(|_| self.expr(depth + 1)).collect();
+        Expr::Call(callee, args)
+      },
+    }
+  }
+
+  fn block(&mut self, depth: usize) -> Vec<Statement> {
+    let length = self.rng.range(1, if depth == 0 { 8 } else { 4 });
+    let mut statements = Vec::new();
+    for _ in 0..length {
+      if self.budget == 0 {
+        break;
+      }
+      self.budget -= 1;
+      statements.push(self.statement(depth));
+    }
+    statements
+  }
+
+  fn statement(&mut self, depth: usize) -> Statement {
+    let nested = depth < 2;
+    match self.rng.below(10) {
+      0 => Statement::SetPointer(self.rng.below(2) as i16, self.heap_address()),
+      1 | 2 if nested => {
+        let condition = self.expr(1);
+        let then = self.block(depth + 1);
+        let otherwise = if self.rng.chance(1, 2) { self.block(depth + 1) } else { Vec::new() };
+        Statement::If(condition, then, otherwise)
+      },
+      3 if nested => {
+        let counter = self.num_locals;
+        self.num_locals += 1;
+        let iterations = self.rng.range(0, 4) as i16;
+        Statement::Loop { counter, iterations, body: self.block(depth + 1) }
+      },
+      4 if self.current != 0 && self.rng.chance(1, 3) => Statement::Return(self.expr(0)),
+      _ => {
+        let place = self.place(true);
+        Statement::Assign(place, self.expr(0))
+      },
+    }
+  }
+}
+
+struct Renderer<'p> {
+  program: &'p Program,
+  out: String,
+  labels: usize,
+}
+
+impl<'p> Renderer<'p> {
+  fn line(&mut self, line: std::fmt::Arguments) {
+    self.out.write_fmt(line).unwrap();
+    self.out.push('\n');
+  }
+
+  fn label(&mut self, prefix: &str) -> String {
+    self.labels += 1;
+    format!("{}_{}", prefix, self.labels)
+  }
+
+  fn expr(&mut self, expr: &Expr) {
+    match expr {
+      Expr::Constant(value) => {
+        if *value >= 0 {
+          self.line(format_args!("push constant {}", value));
+        } else if *value == i16::MIN {
+          self.line(format_args!("push constant 32767"));
+          self.line(format_args!("neg"));
+          self.line(format_args!("push constant 1"));
+          self.line(format_args!("sub"));
+        } else {
+          self.line(format_args!("push constant {}", -value));
+          self.line(format_args!("neg"));
+        }
+      },
+      Expr::Load(place) => self.line(format_args!("push {} {}", place.segment, place.index)),
+      Expr::Unary(op, operand) => {
+        self.expr(operand);
+        self.line(format_args!("{}", op.name()));
+      },
+      Expr::Binary(op, left, right) => {
+        self.expr(left);
+        self.expr(right);
+        self.line(format_args!("{}", op.name()));
+      },
+      Expr::Call(callee, args) => {
+        for arg in args {
+          self.expr(arg);
+        }
+        let name = &self.program.functions[*callee].name;
+        self.line(format_args!("call {} {}", name, args.len()));
+      },
+    }
+  }
+
+  fn statement(&mut self, statement: &Statement) {
+    match statement {
+      Statement::Assign(place, expr) => {
+        self.expr(expr);
+        self.line(format_args!("pop {} {}", place.segment, place.index));
+      },
+      Statement::SetPointer(which, address) => {
+        self.line(format_args!("push constant {}", address));
+        self.line(format_args!("pop pointer {}", which));
+      },
+      Statement::If(condition, then, otherwise) => {
+        let if_true = self.label("IF_TRUE");
+        let if_end = self.label("IF_END");
+        self.expr(condition);
+        self.line(format_args!("if-goto {}", if_true));
+        for statement in otherwise {
+          self.statement(statement);
+        }
+        self.line(format_args!("goto {}", if_end));
+        self.line(format_args!("label {}", if_true));
+        for statement in then {
+          self.statement(statement);
+        }
+        self.line(format_args!("label {}", if_end));
+      },
+      Statement::Loop { counter, iterations, body } => {
+        let start = self.label("LOOP");
+        let end = self.label("LOOP_END");
+        self.line(format_args!("push constant {}", iterations));
+        self.line(format_args!("pop local {}", counter));
+        self.line(format_args!("label {}", start));
+        self.line(format_args!("push local {}", counter));
+        self.line(format_args!("push constant 0"));
+        self.line(format_args!("eq"));
+        self.line(format_args!("if-goto {}", end));
+        for statement in body {
+          self.statement(statement);
+        }
+        self.line(format_args!("push local {}", counter));
+        self.line(format_args!("push constant 1"));
+        self.line(format_args!("sub"));
+        self.line(format_args!("pop local {}", counter));
+        self.line(format_args!("goto {}", start));
+        self.line(format_args!("label {}", end));
+      },
+      Statement::Return(expr) => {
+        self.expr(expr);
+        self.line(format_args!("return"));
+      },
+    }
+  }
+
+  fn function(&mut self, index: usize) {
+    let function = &self.program.functions[index];
+    self.line(format_args!("function {} {}", function.name, function.num_locals));
+    for statement in &function.body {
+      self.statement(statement);
+    }
+    if index == 0 {
+      self.line(format_args!("label END"));
+      self.line(format_args!("goto END"));
+    } else {
+      self.expr(&function.result);
+      self.line(format_args!("return"));
+    }
+  }
+}
+
+/// Render the program as `.vm` sources, one per file, in a fixed order.
+pub fn render(program: &Program) -> Vec<(String, String)> {
+  let mut files: Vec<(String, String)> = Vec::new();
+  for (index, function) in program.functions.iter().enumerate() {
+    let mut renderer = Renderer { program, out: String::new(), labels: 0 };
+    renderer.function(index);
+    match files.iter_mut().find(|(name, _)| name == function.file) {
+      Some((_, source)) => source.push_str(&renderer.out),
+      None => files.push((String::from(function.file), renderer.out)),
+    }
+  }
+  files
+}
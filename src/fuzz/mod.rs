@@ -0,0 +1,76 @@
+pub mod generator;
+pub mod rng;
+pub mod shrink;
+
+use super::differential::{self, Limits};
+use super::instruction::{decode, Module};
+use super::lexer::lex;
+use super::parser::parse;
+use super::translator::Options;
+use self::generator::{generate, render, Program};
+use self::rng::Rng;
+
+#[derive(Debug)]
+pub struct Failure {
+  /// Seed that reproduces the original failing program with `--fuzz=1`.
+  pub seed: u64,
+  /// The shrunk program, as `(file name, source)` pairs.
+  pub sources: Vec<(String, String)>,
+  pub report: String,
+}
+
+/// Feed `program` through the lexer, parser and translator and compare its
+/// execution against the VM interpreter. Returns a description of the
+/// failure, if any.
+pub fn check(program: &Program, options: Options, limits: Limits) -> Result<(), String> {
+  let mut modules: Vec<Module> = Vec::new();
+  for (name, source) in render(program) {
+    let tokens = lex(&source);
+    let commands = parse(&tokens);
+    modules.push(decode(&name, &commands)?);
+  }
+  let report = differential::run(&modules, options, limits)?;
+  if report.diverged() {
+    Err(report.to_string())
+  } else {
+    Ok(())
+  }
+}
+
+/// Check `count` random programs, starting from `seed`, and return the first
+/// failure found, shrunk to a minimal program.
+pub fn run(seed: u64, count: u64, options: Options, limits: Limits) -> Option<Failure> {
+  for case in 0..count {
+    let case_seed = seed.wrapping_add(case);
+    let program = generate(&mut Rng::new(case_seed));
+    if check(&program, options, limits).is_ok() {
+      continue;
+    }
+
+    let program = shrink::shrink(program, |candidate| check(candidate, options, limits).is_err());
+    return Some(Failure {
+      seed: case_seed,
+      report: check(&program, options, limits).unwrap_err(),
+      sources: render(&program),
+    });
+  }
+  None
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use super::super::pipeline;
+
+  #[test]
+  fn presets_agree_with_the_interpreter() {
+    for level in ["0", "1", "2", "s"] {
+      let options = pipeline::preset(level).unwrap();
+      for seed in [1, 2024, 987_654_321] {
+        if let Some(failure) = run(seed, 8, options, Limits::default()) {
+          panic!("-O{} seed {}:\n{}", level, failure.seed, failure.report);
+        }
+      }
+    }
+  }
+}
@@ -0,0 +1,39 @@
+/// A small seedable generator (SplitMix64), so that any generated program
+/// can be reproduced from the seed it was made with.
+#[derive(Debug, Clone)]
+pub struct Rng {
+  state: u64,
+}
+
+impl Rng {
+  pub fn new(seed: u64) -> Rng {
+    Rng { state: seed }
+  }
+
+  pub fn next_u64(&mut self) -> u64 {
+    self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    let mut z = self.state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+  }
+
+  /// A number in `0..n`.
+  pub fn below(&mut self, n: usize) -> usize {
+    (self.next_u64() % n as u64) as usize
+  }
+
+  /// A number in `low..=high`.
+  pub fn range(&mut self, low: i32, high: i32) -> i32 {
+    low + self.below((high - low + 1) as usize) as i32
+  }
+
+  /// True with probability `numerator / denominator`.
+  pub fn chance(&mut self, numerator: usize, denominator: usize) -> bool {
+    self.below(denominator) < numerator
+  }
+
+  pub fn choose<'a, T>(&mut self, items: &'a [T]) -> &'a T {
+    &items[self.below(items.len())]
+  }
+}
@@ -0,0 +1,271 @@
+use super::generator::{Expr, Function, Program, Statement};
+
+/// Greedily simplify `program` for as long as some one-step simplification
+/// still makes `fails` return true.
+pub fn shrink<F>(mut program: Program, mut fails: F) -> Program
+  where F: FnMut(&Program) -> bool
+{
+  'progress: loop {
+    for candidate in candidates(&program) {
+      if fails(&candidate) {
+        program = candidate;
+        continue 'progress;
+      }
+    }
+    return program;
+  }
+}
+
+/// Every program that is one simplification step away from `program`. Each
+/// candidate is still well-formed: calls only ever disappear along with the
+/// expression that made them, and only uncalled functions are removed.
+fn candidates(program: &Program) -> Vec<Program> {
+  let mut candidates = Vec::new();
+
+  for index in 1..program.functions.len() {
+    if !program.functions.iter().any(|function| calls(function, index)) {
+      let mut candidate = program.clone();
+      candidate.functions.remove(index);
+      for function in &mut candidate.functions {
+        renumber_function(function, index);
+      }
+      candidates.push(candidate);
+    }
+  }
+
+  for (index, function) in program.functions.iter().enumerate() {
+    for body in block_variants(&function.body) {
+      let mut candidate = program.clone();
+      candidate.functions[index].body = body;
+      candidates.push(candidate);
+    }
+    for result in expr_variants(&function.result) {
+      let mut candidate = program.clone();
+      candidate.functions[index].result = result;
+      candidates.push(candidate);
+    }
+  }
+
+  candidates
+}
+
+fn block_variants(block: &[Statement]) -> Vec<Vec<Statement>> {
+  let mut variants = Vec::new();
+  for (i, statement) in block.iter().enumerate() {
+    let mut replace = |replacement: Vec<Statement>| {
+      let mut variant = block[..i].to_vec();
+      variant.extend(replacement);
+      variant.extend_from_slice(&block[i + 1..]);
+      variants.push(variant);
+    };
+
+    // `this` and `that` must keep pointing into the heap, so pointer
+    // assignments are never dropped on their own
+    if !matches!(statement, Statement::SetPointer(_, _)) {
+      replace(Vec::new());
+    }
+    match statement {
+      Statement::Assign(place, expr) => {
+        for expr in expr_variants(expr) {
+          replace(vec![Statement::Assign(*place, expr)]);
+        }
+      },
+      Statement::SetPointer(_, _) => (),
+      Statement::If(condition, then, otherwise) => {
+        replace(then.clone());
+        replace(otherwise.clone());
+        for condition in expr_variants(condition) {
+          replace(vec![Statement::If(condition, then.clone(), otherwise.clone())]);
+        }
+        for then in block_variants(then) {
+          replace(vec![Statement::If(condition.clone(), then, otherwise.clone())]);
+        }
+        for otherwise in block_variants(otherwise) {
+          replace(vec![Statement::If(condition.clone(), then.clone(), otherwise)]);
+        }
+      },
+      Statement::Loop { counter, iterations, body } => {
+        replace(body.clone());
+        if *iterations > 1 {
+          replace(vec![Statement::Loop { counter: *counter, iterations: 1, body: body.clone() }]);
+        }
+        for body in block_variants(body) {
+          replace(vec![Statement::Loop { counter: *counter, iterations: *iterations, body }]);
+        }
+      },
+      Statement::Return(expr) => {
+        for expr in expr_variants(expr) {
+          replace(vec![Statement::Return(expr)]);
+        }
+      },
+    }
+  }
+  variants
+}
+
+fn expr_variants(expr: &Expr) -> Vec<Expr> {
+  let mut variants = Vec::new();
+  if *expr != Expr::Constant(0) {
+    variants.push(Expr::Constant(0));
+  }
+  match expr {
+    Expr::Constant(value) => {
+      if *value / 2 != 0 {
+        variants.push(Expr::Constant(*value / 2));
+      }
+    },
+    Expr::Load(_) => (),
+    Expr::Unary(op, operand) => {
+      variants.push((**operand).clone());
+      for operand in expr_variants(operand) {
+        variants.push(Expr::Unary(*op, Box::new(operand)));
+      }
+    },
+    Expr::Binary(op, left, right) => {
+      variants.push((**left).clone());
+      variants.push((**right).clone());
+      for left in expr_variants(left) {
+        variants.push(Expr::Binary(*op, Box::new(left), right.clone()));
+      }
+      for right in expr_variants(right) {
+        variants.push(Expr::Binary(*op, left.clone(), Box::new(right)));
+      }
+    },
+    Expr::Call(callee, args) => {
+      for (i, arg) in args.iter().enumerate() {
+        for arg in expr_variants(arg) {
+          let mut args = args.clone();
+          args[i] = arg;
+          variants.push(Expr::Call(*callee, args));
+        }
+      }
+    },
+  }
+  variants
+}
+
+fn calls(function: &Function, callee: usize) -> bool {
+  let mut found = false;
+  visit_function(function, &mut |expr| {
+    if let Expr::Call(index, _) = expr {
+      found |= *index == callee;
+    }
+  });
+  found
+}
+
+fn visit_function<F: FnMut(&Expr)>(function: &Function, visit: &mut F) {
+  visit_block(&function.body, visit);
+  visit_expr(&function.result, visit);
+}
+
+fn visit_block<F: FnMut(&Expr)>(block: &[Statement], visit: &mut F) {
+  for statement in block {
+    match statement {
+      Statement::Assign(_, expr) | Statement::Return(expr) => visit_expr(expr, visit),
+      Statement::SetPointer(_, _) => (),
+      Statement::If(condition, then, otherwise) => {
+        visit_expr(condition, visit);
+        visit_block(then, visit);
+        visit_block(otherwise, visit);
+      },
+      Statement::Loop { body, .. } => visit_block(body, visit),
+    }
+  }
+}
+
+fn visit_expr<F: FnMut(&Expr)>(expr: &Expr, visit: &mut F) {
+  visit(expr);
+  match expr {
+    Expr::Constant(_) | Expr::Load(_) => (),
+    Expr::Unary(_, operand) => visit_expr(operand, visit),
+    Expr::Binary(_, left, right) => {
+      visit_expr(left, visit);
+      visit_expr(right, visit);
+    },
+    Expr::Call(_, args) => {
+      for arg in args {
+        visit_expr(arg, visit);
+      }
+    },
+  }
+}
+
+/// Fix up call indices after the function at `removed` was deleted.
+fn renumber_function(function: &mut Function, removed: usize) {
+  renumber_block(&mut function.body, removed);
+  renumber_expr(&mut function.result, removed);
+}
+
+fn renumber_block(block: &mut [Statement], removed: usize) {
+  for statement in block {
+    match statement {
+      Statement::Assign(_, expr) | Statement::Return(expr) => renumber_expr(expr, removed),
+      Statement::SetPointer(_, _) => (),
+      Statement::If(condition, then, otherwise) => {
+        renumber_expr(condition, removed);
+        renumber_block(then, removed);
+        renumber_block(otherwise, removed);
+      },
+      Statement::Loop { body, .. } => renumber_block(body, removed),
+    }
+  }
+}
+
+fn renumber_expr(expr: &mut Expr, removed: usize) {
+  match expr {
+    Expr::Constant(_) | Expr::Load(_) => (),
+    Expr::Unary(_, operand) => renumber_expr(operand, removed),
+    Expr::Binary(_, left, right) => {
+      renumber_expr(left, removed);
+      renumber_expr(right, removed);
+    },
+    Expr::Call(callee, args) => {
+      if *callee > removed {
+        *callee -= 1;
+      }
+      for arg in args {
+        renumber_expr(arg, removed);
+      }
+    },
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use super::super::generator::{generate, render};
+  use super::super::rng::Rng;
+
+  fn source(program: &Program) -> String {
+    render(program).into_iter().map(|(_, source)| source).collect()
+  }
+
+  /// Shrink generated programs that compare with `gt` for as long as they
+  /// still do.
+  #[test]
+  fn shrunk_program_still_fails() {
+    let fails = |program: &Program| source(program).contains("gt");
+    let mut shrunk_any = false;
+    for seed in 0..20 {
+      let program = generate(&mut Rng::new(seed));
+      if !fails(&program) {
+        continue;
+      }
+      let shrunk = shrink(program.clone(), fails);
+      assert!(fails(&shrunk), "seed {}: shrunk program no longer fails", seed);
+      assert!(source(&shrunk).len() <= source(&program).len());
+      // no single step simplifies it further
+      assert!(candidates(&shrunk).iter().all(|candidate| !fails(candidate)));
+      shrunk_any |= shrunk != program;
+    }
+    assert!(shrunk_any);
+  }
+
+  #[test]
+  fn keeps_a_program_nothing_simpler_reproduces() {
+    let program = generate(&mut Rng::new(7));
+    let shrunk = shrink(program.clone(), |candidate| *candidate == program);
+    assert_eq!(shrunk, program);
+  }
+}
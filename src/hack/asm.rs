@@ -0,0 +1,337 @@
+use std::convert::TryFrom;
+use std::fmt;
+
+/// The computation part of a C-instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Comp {
+  Zero,
+  One,
+  MinusOne,
+  D,
+  A,
+  M,
+  NotD,
+  NotA,
+  NotM,
+  NegD,
+  NegA,
+  NegM,
+  DPlusOne,
+  APlusOne,
+  MPlusOne,
+  DMinusOne,
+  AMinusOne,
+  MMinusOne,
+  DPlusA,
+  DPlusM,
+  DMinusA,
+  DMinusM,
+  AMinusD,
+  MMinusD,
+  DAndA,
+  DAndM,
+  DOrA,
+  DOrM,
+}
+
+const COMPS: [(Comp, &str, u16); 28] = [
+  // a-bit followed by the six ALU control bits
+  (Comp::Zero, "0", 0b0_101010),
+  (Comp::One, "1", 0b0_111111),
+  (Comp::MinusOne, "-1", 0b0_111010),
+  (Comp::D, "D", 0b0_001100),
+  (Comp::A, "A", 0b0_110000),
+  (Comp::M, "M", 0b1_110000),
+  (Comp::NotD, "!D", 0b0_001101),
+  (Comp::NotA, "!A", 0b0_110001),
+  (Comp::NotM, "!M", 0b1_110001),
+  (Comp::NegD, "-D", 0b0_001111),
+  (Comp::NegA, "-A", 0b0_110011),
+  (Comp::NegM, "-M", 0b1_110011),
+  (Comp::DPlusOne, "D+1", 0b0_011111),
+  (Comp::APlusOne, "A+1", 0b0_110111),
+  (Comp::MPlusOne, "M+1", 0b1_110111),
+  (Comp::DMinusOne, "D-1", 0b0_001110),
+  (Comp::AMinusOne, "A-1", 0b0_110010),
+  (Comp::MMinusOne, "M-1", 0b1_110010),
+  (Comp::DPlusA, "D+A", 0b0_000010),
+  (Comp::DPlusM, "D+M", 0b1_000010),
+  (Comp::DMinusA, "D-A", 0b0_010011),
+  (Comp::DMinusM, "D-M", 0b1_010011),
+  (Comp::AMinusD, "A-D", 0b0_000111),
+  (Comp::MMinusD, "M-D", 0b1_000111),
+  (Comp::DAndA, "D&A", 0b0_000000),
+  (Comp::DAndM, "D&M", 0b1_000000),
+  (Comp::DOrA, "D|A", 0b0_010101),
+  (Comp::DOrM, "D|M", 0b1_010101),
+];
+
+impl Comp {
+  pub fn parse(text: &str) -> Option<Comp> {
+    let text = match text {
+      "A+D" => "D+A",
+      "M+D" => "D+M",
+      "A&D" => "D&A",
+      "M&D" => "D&M",
+      "A|D" => "D|A",
+      "M|D" => "D|M",
+      _ => text,
+    };
+    COMPS.iter().find(|(_, name, _)| *name == text).map(|(comp, _, _)| *comp)
+  }
+
+  fn entry(self) -> &'static (Comp, &'static str, u16) {
+    COMPS.iter().find(|(comp, _, _)| *comp == self).unwrap()
+  }
+
+  pub fn as_str(self) -> &'static str {
+    self.entry().1
+  }
+
+  /// The a-bit and ALU control bits.
+  pub fn bits(self) -> u16 {
+    self.entry().2
+  }
+}
+
+/// The registers a C-instruction stores its result in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct Dest {
+  pub a: bool,
+  pub d: bool,
+  pub m: bool,
+}
+
+impl Dest {
+  pub const NONE: Dest = Dest { a: false, d: false, m: false };
+
+  pub fn parse(text: &str) -> Option<Dest> {
+    let mut dest = Dest::NONE;
+    for register in text.chars() {
+      let bit = match register {
+        'A' => &mut dest.a,
+        'D' => &mut dest.d,
+        'M' => &mut dest.m,
+        _ => return None,
+      };
+      if *bit {
+        return None;
+      }
+      *bit = true;
+    }
+    Some(dest)
+  }
+
+  pub fn bits(self) -> u16 {
+    (self.a as u16) << 2 | (self.d as u16) << 1 | self.m as u16
+  }
+
+  pub fn is_empty(self) -> bool {
+    self == Dest::NONE
+  }
+}
+
+impl fmt::Display for Dest {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    if self.a {
+      write!(f, "A")?;
+    }
+    if self.m {
+      write!(f, "M")?;
+    }
+    if self.d {
+      write!(f, "D")?;
+    }
+    Ok(())
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Jump {
+  Never,
+  Gt,
+  Eq,
+  Ge,
+  Lt,
+  Ne,
+  Le,
+  Always,
+}
+
+const JUMPS: [(Jump, &str); 8] = [
+  (Jump::Never, ""),
+  (Jump::Gt, "JGT"),
+  (Jump::Eq, "JEQ"),
+  (Jump::Ge, "JGE"),
+  (Jump::Lt, "JLT"),
+  (Jump::Ne, "JNE"),
+  (Jump::Le, "JLE"),
+  (Jump::Always, "JMP"),
+];
+
+impl Jump {
+  pub fn parse(text: &str) -> Option<Jump> {
+    JUMPS.iter().find(|(_, name)| *name == text).map(|(jump, _)| *jump)
+  }
+
+  pub fn as_str(self) -> &'static str {
+    JUMPS[self.bits() as usize].1
+  }
+
+  pub fn bits(self) -> u16 {
+    JUMPS.iter().position(|(jump, _)| *jump == self).unwrap() as u16
+  }
+}
+
+/// A single line of Hack assembly.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum AsmInstr {
+  /// `@123`
+  AConstant(u16),
+  /// `@LABEL`, `@SP`, `@variable`
+  ASymbol(String),
+  /// `dest=comp;jump`
+  C { dest: Dest, comp: Comp, jump: Jump },
+  /// `(LABEL)`
+  Label(String),
+  /// `// text`
+  Comment(String),
+}
+
+impl AsmInstr {
+  pub fn c(dest: Dest, comp: Comp, jump: Jump) -> AsmInstr {
+    AsmInstr::C { dest, comp, jump }
+  }
+
+  pub fn symbol<S: Into<String>>(symbol: S) -> AsmInstr {
+    AsmInstr::ASymbol(symbol.into())
+  }
+
+  /// Whether this takes up a word of ROM.
+  pub fn is_code(&self) -> bool {
+    matches!(self, AsmInstr::AConstant(_) | AsmInstr::ASymbol(_) | AsmInstr::C { .. })
+  }
+
+  /// Parse one line of assembly into at most one instruction followed by at
+  /// most one comment.
+  pub fn parse_line(line: &str) -> Result<Vec<AsmInstr>, String> {
+    let (code, comment) = match line.find("//") {
+      Some(index) => (line[..index].trim(), Some(line[index + 2..].trim())),
+      None => (line.trim(), None),
+    };
+
+    let mut instructions = Vec::new();
+    if code.starts_with('(') {
+      if !code.ends_with(')') || code.len() < 3 {
+        return Err(format!("Malformed label '{}'", code));
+      }
+      instructions.push(AsmInstr::Label(String::from(&code[1..code.len() - 1])));
+    } else if let Some(symbol) = code.strip_prefix('@') {
+      if symbol.is_empty() {
+        return Err(String::from("Missing A-instruction operand"));
+      }
+      instructions.push(match symbol.parse::<u16>() {
+        Ok(value) if value <= 0x7fff => AsmInstr::AConstant(value),
+        Ok(value) => return Err(format!("Constant {} out of range", value)),
+        Err(_) => AsmInstr::symbol(symbol),
+      });
+    } else if !code.is_empty() {
+      let (dest, rest) = match code.find('=') {
+        Some(index) => (&code[..index], &code[index + 1..]),
+        None => ("", code),
+      };
+      let (comp, jump) = match rest.find(';') {
+        Some(index) => (&rest[..index], &rest[index + 1..]),
+        None => (rest, ""),
+      };
+      match (Dest::parse(dest.trim()), Comp::parse(comp.trim()), Jump::parse(jump.trim())) {
+        (Some(dest), Some(comp), Some(jump)) => instructions.push(AsmInstr::c(dest, comp, jump)),
+        _ => return Err(format!("Invalid instruction '{}'", code)),
+      }
+    }
+
+    if let Some(comment) = comment {
+      instructions.push(AsmInstr::Comment(String::from(comment)));
+    }
+    Ok(instructions)
+  }
+
+  pub fn parse(source: &str) -> Result<Vec<AsmInstr>, String> {
+    let mut instructions = Vec::new();
+    for (line_number, line) in source.lines().enumerate() {
+      let parsed = AsmInstr::parse_line(line)
+        .map_err(|message| format!("{} on line {}", message, line_number + 1))?;
+      instructions.extend(parsed);
+    }
+    Ok(instructions)
+  }
+}
+
+/// What an A-instruction can load: a symbol, or a constant that fits in 15
+/// bits.
+pub trait Address {
+  fn load(self) -> AsmInstr;
+}
+
+impl Address for &str {
+  fn load(self) -> AsmInstr {
+    AsmInstr::symbol(self)
+  }
+}
+
+impl Address for String {
+  fn load(self) -> AsmInstr {
+    AsmInstr::ASymbol(self)
+  }
+}
+
+impl Address for &String {
+  fn load(self) -> AsmInstr {
+    AsmInstr::symbol(self.as_str())
+  }
+}
+
+macro_rules! constant_address {
+  ( $($integer:ty),* ) => {
+    $(impl Address for $integer {
+      fn load(self) -> AsmInstr {
+        match u16::try_from(self) {
+          Ok(value) if value <= 0x7fff => AsmInstr::AConstant(value),
+          _ => panic!("Constant {} out of range", self),
+        }
+      }
+    })*
+  };
+}
+
+constant_address!(u16, i16, i32, usize);
+
+impl fmt::Display for AsmInstr {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      AsmInstr::AConstant(value) => write!(f, "  @{}", value),
+      AsmInstr::ASymbol(symbol) => write!(f, "  @{}", symbol),
+      AsmInstr::C { dest, comp, jump } => {
+        write!(f, "  ")?;
+        if !dest.is_empty() {
+          write!(f, "{}=", dest)?;
+        }
+        write!(f, "{}", comp.as_str())?;
+        if *jump != Jump::Never {
+          write!(f, ";{}", jump.as_str())?;
+        }
+        Ok(())
+      },
+      AsmInstr::Label(label) => write!(f, "({})", label),
+      AsmInstr::Comment(text) => write!(f, "  // {}", text),
+    }
+  }
+}
+
+/// Render instructions as assembly source, one per line.
+pub fn print(instructions: &[AsmInstr], stream: &mut dyn std::io::Write) -> std::io::Result<()> {
+  for instruction in instructions {
+    writeln!(stream, "{}", instruction)?;
+  }
+  Ok(())
+}
@@ -0,0 +1,87 @@
+use std::collections::HashMap;
+
+use super::asm::{AsmInstr, Comp, Dest, Jump};
+
+/// Where user-defined variables start being allocated in RAM.
+pub const FIRST_VARIABLE_ADDRESS: u16 = 16;
+
+/// The result of assembling a Hack program.
+#[derive(Debug)]
+pub struct Program {
+  pub rom: Vec<u16>,
+  /// ROM address of every `(LABEL)` declaration.
+  pub labels: HashMap<String, u16>,
+  /// RAM address of every variable, in order of first use.
+  pub variables: HashMap<String, u16>,
+}
+
+pub fn predefined_symbol(name: &str) -> Option<u16> {
+  let address = match name {
+    "SP" => 0,
+    "LCL" => 1,
+    "ARG" => 2,
+    "THIS" => 3,
+    "THAT" => 4,
+    "SCREEN" => 16384,
+    "KBD" => 24576,
+    _ => {
+      let register = name.strip_prefix('R')?;
+      match register.parse::<u16>() {
+        Ok(n) if n < 16 && !register.starts_with('+') => n,
+        _ => return None,
+      }
+    },
+  };
+  Some(address)
+}
+
+/// Encode a C-instruction as a machine word.
+pub fn encode_c_instruction(dest: Dest, comp: Comp, jump: Jump) -> u16 {
+  0b111 << 13 | comp.bits() << 6 | dest.bits() << 3 | jump.bits()
+}
+
+pub fn assemble(instructions: &[AsmInstr]) -> Result<Program, String> {
+  let mut labels = HashMap::new();
+  let mut address: u16 = 0;
+
+  for instruction in instructions {
+    match instruction {
+      AsmInstr::Label(label) if labels.contains_key(label) => {
+        return Err(format!("Duplicate label '{}'", label));
+      },
+      AsmInstr::Label(label) => {
+        labels.insert(label.clone(), address);
+      },
+      _ if instruction.is_code() => address += 1,
+      _ => (),
+    }
+  }
+
+  let mut rom = Vec::with_capacity(address as usize);
+  let mut variables = HashMap::new();
+  let mut next_variable = FIRST_VARIABLE_ADDRESS;
+
+  for instruction in instructions {
+    let word = match instruction {
+      AsmInstr::AConstant(value) => *value,
+      AsmInstr::ASymbol(symbol) => {
+        if let Some(value) = predefined_symbol(symbol) {
+          value
+        } else if let Some(value) = labels.get(symbol) {
+          *value
+        } else {
+          *variables.entry(symbol.clone()).or_insert_with(|| {
+            let variable = next_variable;
+            next_variable += 1;
+            variable
+          })
+        }
+      },
+      AsmInstr::C { dest, comp, jump } => encode_c_instruction(*dest, *comp, *jump),
+      AsmInstr::Label(_) | AsmInstr::Comment(_) => continue,
+    };
+    rom.push(word);
+  }
+
+  Ok(Program { rom, labels, variables })
+}
@@ -0,0 +1,107 @@
+use std::collections::BTreeSet;
+
+pub const RAM_SIZE: usize = 32768;
+
+/// Lowest address that's considered heap memory rather than registers,
+/// statics or the stack.
+pub const HEAP_BASE: u16 = 2048;
+
+/// A Hack CPU attached to a ROM and a RAM.
+#[derive(Debug)]
+pub struct Emulator {
+  rom: Vec<u16>,
+  pub ram: Vec<i16>,
+  pub a: i16,
+  pub d: i16,
+  pub pc: u16,
+  pub cycles: u64,
+  /// Every address at or above `HEAP_BASE` that has been written to.
+  pub heap_writes: BTreeSet<u16>,
+}
+
+impl Emulator {
+  pub fn new(rom: Vec<u16>) -> Emulator {
+    Emulator {
+      rom,
+      ram: vec![0; RAM_SIZE],
+      a: 0,
+      d: 0,
+      pc: 0,
+      cycles: 0,
+      heap_writes: BTreeSet::new(),
+    }
+  }
+
+  fn address(&self) -> usize {
+    (self.a as u16 & 0x7fff) as usize
+  }
+
+  /// Execute a single instruction. Returns false if the program counter has
+  /// run off the end of the ROM.
+  pub fn step(&mut self) -> bool {
+    let instruction = match self.rom.get(self.pc as usize) {
+      Some(instruction) => *instruction,
+      None => return false,
+    };
+    self.cycles += 1;
+
+    if instruction & 0x8000 == 0 {
+      self.a = instruction as i16;
+      self.pc += 1;
+      return true;
+    }
+
+    let y = if instruction & 0x1000 != 0 {
+      self.ram[self.address()]
+    } else {
+      self.a
+    };
+    let out = alu(self.d, y, (instruction >> 6) & 0x3f);
+
+    let dest = (instruction >> 3) & 0b111;
+    let jump = instruction & 0b111;
+    // M is addressed by A as it was before this instruction
+    if dest & 0b001 != 0 {
+      let address = self.address();
+      self.ram[address] = out;
+      if address >= HEAP_BASE as usize {
+        self.heap_writes.insert(address as u16);
+      }
+    }
+    let target = self.a as u16 & 0x7fff;
+    if dest & 0b100 != 0 {
+      self.a = out;
+    }
+    if dest & 0b010 != 0 {
+      self.d = out;
+    }
+
+    let taken = (jump & 0b100 != 0 && out < 0)
+      || (jump & 0b010 != 0 && out == 0)
+      || (jump & 0b001 != 0 && out > 0);
+    self.pc = if taken { target } else { self.pc + 1 };
+    true
+  }
+}
+
+/// The Hack ALU, driven by the zx, nx, zy, ny, f and no control bits.
+fn alu(x: i16, y: i16, control: u16) -> i16 {
+  let mut x = if control & 0b100000 != 0 { 0 } else { x };
+  if control & 0b010000 != 0 {
+    x = !x;
+  }
+  let mut y = if control & 0b001000 != 0 { 0 } else { y };
+  if control & 0b000100 != 0 {
+    y = !y;
+  }
+  let out = if control & 0b000010 != 0 {
+    x.wrapping_add(y)
+  } else {
+    x & y
+  };
+  if control & 0b000001 != 0 {
+    !out
+  } else {
+    out
+  }
+}
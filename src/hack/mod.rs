@@ -0,0 +1,5 @@
+pub mod asm;
+pub mod assembler;
+pub mod emulator;
+pub mod outline;
+pub mod peephole;
@@ -0,0 +1,399 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::fmt;
+use std::hash::{Hash, Hasher};
+
+use super::asm::{AsmInstr, Jump};
+
+/// The variable outlined sequences keep their return address in. Unlike
+/// R13-R15, nothing else uses it, so it can't be live across a sequence.
+pub const RETURN: &str = "__VM_OUTLINE_RETURN";
+const PREFIX: &str = "__VM_OUTLINED_";
+/// Shorter sequences can't pay for their calls.
+const MIN_LENGTH: usize = 5;
+/// Longer sequences are outlined in pieces.
+const MAX_LENGTH: usize = 64;
+/// `@return D=A @sequence 0;JMP` at every call site.
+const CALL_SIZE: usize = 4;
+/// Storing the return address at the start of a sequence and jumping back
+/// to it at the end.
+const SEQUENCE_OVERHEAD: usize = 5;
+
+#[derive(Debug, Default)]
+pub struct Stats {
+  pub before: usize,
+  pub after: usize,
+  /// Functions whose code was the same as an earlier one's.
+  pub merged: Vec<String>,
+  pub sequences: usize,
+  /// The places the sequences were outlined from.
+  pub calls: usize,
+}
+
+impl fmt::Display for Stats {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(
+      f,
+      "outlining: {} -> {} instructions; merged {} identical functions, outlined {} sequences \
+       from {} places",
+      self.before, self.after, self.merged.len(), self.sequences, self.calls,
+    )?;
+    for name in &self.merged {
+      write!(f, "\n  {}", name)?;
+    }
+    Ok(())
+  }
+}
+
+fn code_size(instructions: &[AsmInstr]) -> usize {
+  instructions.iter().filter(|instruction| instruction.is_code()).count()
+}
+
+/// Shrink the program by merging functions with the same code and moving
+/// repeated straight-line sequences into subroutines. `functions` are the
+/// labels functions start at. Labels for which `is_marker` returns true
+/// aren't jump targets; markers in merged functions or outlined sequences
+/// are dropped.
+pub fn outline(
+  instructions: &mut Vec<AsmInstr>,
+  functions: &HashSet<String>,
+  is_marker: &dyn Fn(&str) -> bool,
+) -> Stats {
+  let mut stats = Stats { before: code_size(instructions), ..Stats::default() };
+  stats.merged = merge_functions(instructions, functions, is_marker);
+  outline_sequences(instructions, is_marker, &mut stats);
+  stats.after = code_size(instructions);
+  stats
+}
+
+/// Replace every function whose code is the same as an earlier one's, up to
+/// the names of the labels only it uses, with a label on the earlier one.
+/// Returns the names of the functions replaced.
+fn merge_functions(
+  instructions: &mut Vec<AsmInstr>,
+  functions: &HashSet<String>,
+  is_marker: &dyn Fn(&str) -> bool,
+) -> Vec<String> {
+  let names: Vec<(usize, &String)> = instructions.iter().enumerate()
+    .filter_map(|(index, instruction)| match instruction {
+      AsmInstr::Label(label) if functions.contains(label) => Some((index, label)),
+      _ => None,
+    })
+    .collect();
+  // along with the checkpoint and comments for its `function` command
+  let starts: Vec<usize> = names.iter().map(|(index, _)| {
+    let mut start = *index;
+    while start > 0 && match &instructions[start - 1] {
+      AsmInstr::Label(label) => is_marker(label),
+      AsmInstr::Comment(_) => true,
+      _ => false,
+    } {
+      start -= 1;
+    }
+    start
+  }).collect();
+  let extents: Vec<(usize, usize)> = starts.iter().enumerate()
+    .map(|(i, start)| (*start, starts.get(i + 1).cloned().unwrap_or(instructions.len())))
+    .collect();
+  let mut references: HashMap<&str, usize> = HashMap::new();
+  for instruction in instructions.iter() {
+    if let AsmInstr::ASymbol(symbol) = instruction {
+      *references.entry(symbol).or_insert(0) += 1;
+    }
+  }
+
+  // the first function with each normalised body, and what replaces later ones
+  let mut first: HashMap<Vec<AsmInstr>, usize> = HashMap::new();
+  let mut aliases: Vec<Vec<String>> = vec![Vec::new(); extents.len()];
+  let mut replaced = vec![false; extents.len()];
+  for (index, (start, end)) in extents.iter().enumerate() {
+    let body = &instructions[*start..*end];
+    let local: HashMap<&str, usize> = body.iter()
+      .filter_map(|instruction| match instruction {
+        AsmInstr::Label(label) if !is_marker(label) => Some(label.as_str()),
+        _ => None,
+      })
+      .enumerate()
+      .map(|(ordinal, label)| (label, ordinal))
+      .collect();
+    let normalised: Vec<AsmInstr> = body.iter().filter_map(|instruction| match instruction {
+      AsmInstr::Label(label) if is_marker(label) => None,
+      AsmInstr::Comment(_) => None,
+      AsmInstr::Label(label) => Some(AsmInstr::Label(format!("#{}", local[label.as_str()]))),
+      AsmInstr::ASymbol(symbol) => Some(match local.get(symbol.as_str()) {
+        Some(ordinal) => AsmInstr::symbol(format!("#{}", ordinal)),
+        None => instruction.clone(),
+      }),
+      _ => Some(instruction.clone()),
+    }).collect();
+    // its labels go with it, so nothing else may jump to them
+    let mut uses: HashMap<&str, usize> = HashMap::new();
+    for instruction in body {
+      if let AsmInstr::ASymbol(symbol) = instruction {
+        *uses.entry(symbol).or_insert(0) += 1;
+      }
+    }
+    let name = names[index].1;
+    let self_contained = local.keys()
+      .filter(|label| **label != name)
+      .all(|label| references.get(label) == uses.get(label));
+    match first.get(&normalised) {
+      Some(original) if self_contained => {
+        aliases[*original].push(name.clone());
+        replaced[index] = true;
+      },
+      Some(_) => (),
+      None => {
+        first.insert(normalised, index);
+      },
+    }
+  }
+  if !replaced.contains(&true) {
+    return Vec::new();
+  }
+
+  let mut output = instructions[..starts[0]].to_vec();
+  let mut merged = Vec::new();
+  for (index, (start, end)) in extents.into_iter().enumerate() {
+    if replaced[index] {
+      continue;
+    }
+    let shared = !aliases[index].is_empty();
+    for alias in aliases[index].drain(..) {
+      output.push(AsmInstr::Label(alias.clone()));
+      merged.push(alias);
+    }
+    // checkpoints in shared code would be reached from functions they're not in
+    output.extend(instructions[start..end].iter().filter(|instruction| {
+      !shared || !matches!(instruction, AsmInstr::Label(label) if is_marker(label))
+    }).cloned());
+  }
+  *instructions = output;
+  merged
+}
+
+/// Whether a sequence can be moved into a subroutine that's called with
+/// its return address in D and A: it must set each of them before reading
+/// it, and set D since the caller's D is gone.
+fn is_callable(sequence: &[&AsmInstr]) -> bool {
+  let (mut a, mut d) = (false, false);
+  for instruction in sequence {
+    match instruction {
+      AsmInstr::C { dest, comp, .. } => {
+        let comp = comp.as_str();
+        let reads_a = comp.contains('A') || comp.contains('M') || dest.m;
+        if comp.contains('D') && !d || reads_a && !a {
+          return false;
+        }
+        a |= dest.a;
+        d |= dest.d;
+      },
+      _ => a = true,
+    }
+  }
+  d
+}
+
+struct Candidate {
+  length: usize,
+  /// Where each occurrence starts, in code instructions.
+  starts: Vec<usize>,
+}
+
+fn savings(length: usize, count: usize) -> isize {
+  ((count - 1) * length) as isize - (CALL_SIZE * count + SEQUENCE_OVERHEAD) as isize
+}
+
+/// Outline the straight-line sequences that save the most instructions,
+/// as long as any do.
+fn outline_sequences(
+  instructions: &mut Vec<AsmInstr>,
+  is_marker: &dyn Fn(&str) -> bool,
+  stats: &mut Stats,
+) {
+  let code: Vec<usize> = (0..instructions.len())
+    .filter(|index| instructions[*index].is_code())
+    .collect();
+  let count = code.len();
+  // how many code instructions from each one on could be outlined together
+  let mut run = vec![0; count + 1];
+  for k in (0..count).rev() {
+    let jumps = matches!(instructions[code[k]], AsmInstr::C { jump, .. } if jump != Jump::Never);
+    let barrier = k + 1 < count && instructions[code[k] + 1..code[k + 1]].iter()
+      .any(|instruction| matches!(instruction, AsmInstr::Label(label) if !is_marker(label)));
+    run[k] = match (jumps, barrier) {
+      (true, _) => 0,
+      (false, true) => 1,
+      (false, false) => run[k + 1] + 1,
+    };
+  }
+  // A must be set again after an occurrence, since returning changes it
+  let reloads_a = |k: usize| match code.get(k) {
+    Some(index) => {
+      matches!(instructions[*index], AsmInstr::AConstant(_) | AsmInstr::ASymbol(_))
+    },
+    None => true,
+  };
+
+  // windows are compared by a rolling hash first
+  const BASE: u64 = 0x100000001b3;
+  let mut prefix = vec![0u64; count + 1];
+  for k in 0..count {
+    let mut hasher = DefaultHasher::new();
+    instructions[code[k]].hash(&mut hasher);
+    prefix[k + 1] = prefix[k].wrapping_mul(BASE).wrapping_add(hasher.finish());
+  }
+  let window = |k: usize, length: usize| -> Vec<&AsmInstr> {
+    code[k..k + length].iter().map(|index| &instructions[*index]).collect()
+  };
+
+  let mut candidates: Vec<Candidate> = Vec::new();
+  let mut heap: BinaryHeap<(isize, usize)> = BinaryHeap::new();
+  let mut power: u64 = 1;
+  for _ in 0..MIN_LENGTH {
+    power = power.wrapping_mul(BASE);
+  }
+  for length in MIN_LENGTH..=MAX_LENGTH {
+    let mut groups: HashMap<u64, Vec<usize>> = HashMap::new();
+    for k in (0..count).filter(|k| run[*k] >= length && reloads_a(k + length)) {
+      let hash = prefix[k + length].wrapping_sub(prefix[k].wrapping_mul(power));
+      groups.entry(hash).or_default().push(k);
+    }
+    power = power.wrapping_mul(BASE);
+    // in order, so that ties are always broken the same way
+    let mut groups: Vec<Vec<usize>> = groups.into_values()
+      .filter(|group| group.len() > 1)
+      .collect();
+    groups.sort();
+    for group in groups {
+      let sequence = window(group[0], length);
+      if !is_callable(&sequence) {
+        continue;
+      }
+      let mut starts: Vec<usize> = Vec::new();
+      for k in group {
+        let overlaps = starts.last().is_some_and(|last| k < last + length);
+        if !overlaps && window(k, length) == sequence {
+          starts.push(k);
+        }
+      }
+      if starts.len() > 1 && savings(length, starts.len()) > 0 {
+        heap.push((savings(length, starts.len()), candidates.len()));
+        candidates.push(Candidate { length, starts });
+      }
+    }
+  }
+
+  // take the best candidate, unless others took some of its occurrences
+  let mut used = vec![false; count];
+  let mut calls: HashMap<usize, (usize, usize)> = HashMap::new();
+  let mut sequences: Vec<Vec<AsmInstr>> = Vec::new();
+  while let Some((saved, index)) = heap.pop() {
+    let candidate = &mut candidates[index];
+    let length = candidate.length;
+    candidate.starts.retain(|k| !used[*k..*k + length].contains(&true));
+    if candidate.starts.len() < 2 {
+      continue;
+    }
+    let now = savings(length, candidate.starts.len());
+    if now != saved {
+      if now > 0 {
+        heap.push((now, index));
+      }
+      continue;
+    }
+    for k in &candidate.starts {
+      used[*k..*k + length].iter_mut().for_each(|used| *used = true);
+      calls.insert(code[*k], (sequences.len(), code[*k + length - 1]));
+    }
+    stats.calls += candidate.starts.len();
+    sequences.push(window(candidate.starts[0], length).into_iter().cloned().collect());
+  }
+  stats.sequences = sequences.len();
+  if sequences.is_empty() {
+    return;
+  }
+
+  let mut output = Vec::with_capacity(instructions.len());
+  let (mut index, mut sites) = (0, 0);
+  while index < instructions.len() {
+    match calls.get(&index) {
+      Some((sequence, last)) => {
+        // named like the translator's return labels
+        let label = format!("__VM_GENERATED_OUTLINED_{}", sites);
+        sites += 1;
+        hack!(output,
+          @(&label),
+          D=A,
+          @(format!("{}{}", PREFIX, sequence)),
+          0;JMP,
+          (label),
+        );
+        index = last + 1;
+      },
+      None => {
+        output.push(instructions[index].clone());
+        index += 1;
+      },
+    }
+  }
+  for (index, sequence) in sequences.into_iter().enumerate() {
+    hack!(output,
+      (format!("{}{}", PREFIX, index)),
+      @(RETURN),
+      M=D,
+      ..(sequence),
+      @(RETURN),
+      A=M,
+      0;JMP,
+    );
+  }
+  *instructions = output;
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use super::super::assembler::assemble;
+  use super::super::emulator::Emulator;
+
+  fn run(instructions: &[AsmInstr]) -> Vec<i16> {
+    let mut emulator = Emulator::new(assemble(instructions).unwrap().rom);
+    for _ in 0..1000 {
+      emulator.step();
+    }
+    emulator.ram[100..103].to_vec()
+  }
+
+  #[test]
+  fn outlines_repeated_sequences() {
+    let sequence = "@5\nD=A\n@100\nM=M+D\n@101\nM=D\n@102\nM=M+1\n";
+    let source = sequence.repeat(4) + "(END)\n@END\n0;JMP";
+    let original = AsmInstr::parse(&source).unwrap();
+    let mut instructions = original.clone();
+    let stats = outline(&mut instructions, &HashSet::new(), &|_| false);
+    assert!(stats.sequences > 0);
+    assert!(stats.after < stats.before, "{}", stats);
+    assert_eq!(stats.after, code_size(&instructions));
+    assert_eq!(run(&instructions), run(&original));
+    assert_eq!(run(&instructions), [20, 5, 4]);
+  }
+
+  #[test]
+  fn merges_identical_functions() {
+    let function = |name: &str| format!(
+      "({name})\n@{name}$LOOP\n({name}$LOOP)\nM=M+1\n@{name}$LOOP\n0;JMP\n",
+      name = name,
+    );
+    let mut instructions = AsmInstr::parse(&(function("Main.f") + &function("Main.g"))).unwrap();
+    let functions: HashSet<String> =
+      ["Main.f", "Main.g"].iter().map(|name| name.to_string()).collect();
+    let stats = outline(&mut instructions, &functions, &|_| false);
+    assert_eq!(stats.merged, ["Main.g"]);
+    assert_eq!(instructions[..2], [
+      AsmInstr::Label(String::from("Main.g")),
+      AsmInstr::Label(String::from("Main.f")),
+    ]);
+    assert_eq!((stats.before, stats.after), (8, 4));
+  }
+}
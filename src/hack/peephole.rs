@@ -0,0 +1,327 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use super::asm::{AsmInstr, Comp, Dest, Jump};
+
+/// A rewrite of a fixed sequence of instructions. `@$0` in `pattern`
+/// matches any A-instruction and stands for it in `replacement`.
+struct Template {
+  name: &'static str,
+  pattern: &'static str,
+  replacement: &'static str,
+}
+
+// Neither replacement leaves A where the original did, so these only apply
+// when the next instruction executed loads A again.
+const TEMPLATES: [Template; 2] = [
+  Template {
+    name: "push D, push constant, sub, pop D",
+    pattern: "
+  @SP
+  A=M
+  M=D
+  @SP
+  M=M+1
+  @$0
+  D=A
+  @SP
+  A=M
+  M=D
+  @SP
+  M=M+1
+  @SP
+  AM=M-1
+  D=M
+  @SP
+  A=M-1
+  M=D-M
+  M=-M
+  @SP
+  AM=M-1
+  D=M
+",
+    replacement: "
+  @$0
+  D=D-A
+",
+  },
+  Template {
+    name: "push D, pop D",
+    pattern: "
+  @SP
+  A=M
+  M=D
+  @SP
+  M=M+1
+  @SP
+  AM=M-1
+  D=M
+",
+    replacement: "",
+  },
+];
+
+#[derive(Debug, Default)]
+pub struct Stats {
+  pub before: usize,
+  pub after: usize,
+  /// How many times each rewrite fired.
+  pub rewrites: BTreeMap<&'static str, usize>,
+}
+
+impl fmt::Display for Stats {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(
+      f,
+      "peephole: {} -> {} instructions ({} saved)",
+      self.before, self.after, self.before - self.after,
+    )?;
+    for (name, count) in &self.rewrites {
+      write!(f, "\n  {:>6}  {}", count, name)?;
+    }
+    Ok(())
+  }
+}
+
+struct Optimiser<'m> {
+  templates: Vec<(&'static str, Vec<AsmInstr>, Vec<AsmInstr>)>,
+  is_marker: &'m dyn Fn(&str) -> bool,
+  stats: Stats,
+}
+
+fn code_size(instructions: &[AsmInstr]) -> usize {
+  instructions.iter().filter(|instruction| instruction.is_code()).count()
+}
+
+/// Remove redundant sequences from straight-line code. Labels for which
+/// `is_marker` returns true aren't jump targets, so code may be rewritten
+/// across them; a marker inside a rewritten sequence is dropped along with it.
+pub fn optimise(instructions: &mut Vec<AsmInstr>, is_marker: &dyn Fn(&str) -> bool) -> Stats {
+  let mut optimiser = Optimiser {
+    templates: TEMPLATES.iter().map(|template| (
+      template.name,
+      AsmInstr::parse(template.pattern).unwrap(),
+      AsmInstr::parse(template.replacement).unwrap(),
+    )).collect(),
+    is_marker,
+    stats: Stats { before: code_size(instructions), ..Stats::default() },
+  };
+
+  loop {
+    let before = code_size(instructions);
+    *instructions = optimiser.apply_templates(instructions);
+    *instructions = optimiser.remove_redundant_loads(instructions);
+    *instructions = optimiser.fold_pairs(instructions);
+    if code_size(instructions) == before {
+      break;
+    }
+  }
+
+  optimiser.stats.after = code_size(instructions);
+  optimiser.stats
+}
+
+impl<'m> Optimiser<'m> {
+  fn is_barrier(&self, instruction: &AsmInstr) -> bool {
+    match instruction {
+      AsmInstr::Label(label) => !(self.is_marker)(label),
+      _ => false,
+    }
+  }
+
+  /// Indices of the next `count` code instructions at or after `start`,
+  /// provided no real label comes between them.
+  fn window(&self, instructions: &[AsmInstr], start: usize, count: usize) -> Option<Vec<usize>> {
+    let mut indices = Vec::with_capacity(count);
+    let mut i = start;
+    while indices.len() < count {
+      let instruction = instructions.get(i)?;
+      if self.is_barrier(instruction) {
+        return None;
+      }
+      if instruction.is_code() {
+        indices.push(i);
+      }
+      i += 1;
+    }
+    Some(indices)
+  }
+
+  /// Whether the next instruction executed after `start`, falling through,
+  /// overwrites A without reading it.
+  fn reloads_a(instructions: &[AsmInstr], start: usize) -> bool {
+    match instructions[start..].iter().find(|instruction| instruction.is_code()) {
+      Some(instruction) => matches!(instruction, AsmInstr::AConstant(_) | AsmInstr::ASymbol(_)),
+      None => true,
+    }
+  }
+
+  fn count(&mut self, name: &'static str) {
+    *self.stats.rewrites.entry(name).or_insert(0) += 1;
+  }
+
+  fn apply_templates(&mut self, instructions: &[AsmInstr]) -> Vec<AsmInstr> {
+    let mut output = Vec::with_capacity(instructions.len());
+    let mut i = 0;
+    'next: while i < instructions.len() {
+      if instructions[i].is_code() {
+        for t in 0..self.templates.len() {
+          let (name, pattern, replacement) = &self.templates[t];
+          let window = match self.window(instructions, i, pattern.len()) {
+            Some(window) => window,
+            None => continue,
+          };
+          let mut operand = None;
+          let matched = window.iter().zip(pattern).all(|(index, expected)| {
+            let actual = &instructions[*index];
+            match expected {
+              AsmInstr::ASymbol(symbol) if symbol == "$0" => {
+                operand = Some(actual.clone());
+                matches!(actual, AsmInstr::AConstant(_) | AsmInstr::ASymbol(_))
+              },
+              _ => actual == expected,
+            }
+          });
+          let end = window.last().unwrap() + 1;
+          if matched && Self::reloads_a(instructions, end) {
+            for instruction in replacement {
+              output.push(match instruction {
+                AsmInstr::ASymbol(symbol) if symbol == "$0" => operand.clone().unwrap(),
+                _ => instruction.clone(),
+              });
+            }
+            let name = *name;
+            self.count(name);
+            i = end;
+            continue 'next;
+          }
+        }
+      }
+      output.push(instructions[i].clone());
+      i += 1;
+    }
+    output
+  }
+
+  /// Remove A-instructions whose value is either already in A, or is
+  /// replaced by another A-instruction before anything uses it.
+  fn remove_redundant_loads(&mut self, instructions: &[AsmInstr]) -> Vec<AsmInstr> {
+    let mut output = Vec::with_capacity(instructions.len());
+    let mut known: Option<&AsmInstr> = None;
+    for (i, instruction) in instructions.iter().enumerate() {
+      match instruction {
+        AsmInstr::AConstant(_) | AsmInstr::ASymbol(_) => {
+          if known == Some(instruction) {
+            self.count("reload of the value already in A");
+            continue;
+          }
+          let next = self.window(instructions, i + 1, 1).map(|window| &instructions[window[0]]);
+          if let Some(AsmInstr::AConstant(_)) | Some(AsmInstr::ASymbol(_)) = next {
+            self.count("A-instruction overwritten before use");
+            continue;
+          }
+          known = Some(instruction);
+        },
+        AsmInstr::C { dest, .. } if dest.a => known = None,
+        _ if self.is_barrier(instruction) => known = None,
+        _ => (),
+      }
+      output.push(instruction.clone());
+    }
+    output
+  }
+
+  /// Simplify pairs of consecutive C-instructions that act on the same M.
+  fn fold_pairs(&mut self, instructions: &[AsmInstr]) -> Vec<AsmInstr> {
+    let m = |dest: Dest, comp: Comp| AsmInstr::c(dest, comp, Jump::Never);
+    let increment = m(Dest { m: true, ..Dest::NONE }, Comp::MPlusOne);
+    let decrement = m(Dest { m: true, ..Dest::NONE }, Comp::MMinusOne);
+    let decrement_to_a = m(Dest { a: true, m: true, ..Dest::NONE }, Comp::MMinusOne);
+    let store_d = m(Dest { m: true, ..Dest::NONE }, Comp::D);
+    let load_d = m(Dest { d: true, ..Dest::NONE }, Comp::M);
+    let load_a = m(Dest { a: true, ..Dest::NONE }, Comp::M);
+
+    let mut output = Vec::with_capacity(instructions.len());
+    let mut i = 0;
+    while i < instructions.len() {
+      if instructions[i].is_code() {
+        if let Some(window) = self.window(instructions, i, 2) {
+          let pair = (&instructions[window[0]], &instructions[window[1]]);
+          let rewrite: Option<(&'static str, Vec<AsmInstr>)> =
+            if (pair.0, pair.1) == (&increment, &decrement_to_a) {
+              Some(("M=M+1 then AM=M-1", vec![load_a.clone()]))
+            } else if (pair.0, pair.1) == (&increment, &decrement)
+              || (pair.0, pair.1) == (&decrement, &increment) {
+              Some(("M=M+1 and M=M-1", Vec::new()))
+            } else if (pair.0, pair.1) == (&store_d, &load_d) {
+              Some(("M=D then D=M", vec![store_d.clone()]))
+            } else {
+              None
+            };
+          if let Some((name, replacement)) = rewrite {
+            self.count(name);
+            output.extend(replacement);
+            i = window[1] + 1;
+            continue;
+          }
+        }
+      }
+      output.push(instructions[i].clone());
+      i += 1;
+    }
+    output
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn optimised(source: &str) -> (Vec<AsmInstr>, Stats) {
+    let mut instructions = AsmInstr::parse(source).unwrap();
+    let stats = optimise(&mut instructions, &|label| label.starts_with('$'));
+    (instructions, stats)
+  }
+
+  #[test]
+  fn rewrites_push_and_pop_of_d() {
+    let (instructions, stats) = optimised("
+      @LCL
+      D=M
+      @SP
+      A=M
+      M=D
+      @SP
+      M=M+1
+      ($marker)
+      @SP
+      AM=M-1
+      D=M
+      @THAT
+      M=D
+    ");
+    assert_eq!(instructions, AsmInstr::parse("@LCL\nD=M\n@THAT\nM=D").unwrap());
+    assert_eq!((stats.before, stats.after), (12, 4));
+    assert_eq!(stats.rewrites["push D, pop D"], 1);
+  }
+
+  #[test]
+  fn keeps_code_either_side_of_a_jump_target() {
+    let source = "
+      @SP
+      A=M
+      M=D
+      @SP
+      M=M+1
+      (LOOP)
+      @SP
+      AM=M-1
+      D=M
+      @THAT
+      M=D
+    ";
+    let (instructions, stats) = optimised(source);
+    assert_eq!(instructions, AsmInstr::parse(source).unwrap());
+    assert!(stats.rewrites.is_empty());
+  }
+}
@@ -0,0 +1,265 @@
+use super::command::Command;
+use super::token::{TokenType, Value};
+use super::code_gen::segment::Segment;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Operation {
+  Add,
+  Sub,
+  Neg,
+  Eq,
+  Gt,
+  Lt,
+  And,
+  Or,
+  Not,
+}
+
+impl Operation {
+  pub fn from_name(name: &str) -> Option<Operation> {
+    match name {
+      "add" => Some(Operation::Add),
+      "sub" => Some(Operation::Sub),
+      "neg" => Some(Operation::Neg),
+      "eq" => Some(Operation::Eq),
+      "gt" => Some(Operation::Gt),
+      "lt" => Some(Operation::Lt),
+      "and" => Some(Operation::And),
+      "or" => Some(Operation::Or),
+      "not" => Some(Operation::Not),
+      _ => None,
+    }
+  }
+
+  pub fn name(self) -> &'static str {
+    match self {
+      Operation::Add => "add",
+      Operation::Sub => "sub",
+      Operation::Neg => "neg",
+      Operation::Eq => "eq",
+      Operation::Gt => "gt",
+      Operation::Lt => "lt",
+      Operation::And => "and",
+      Operation::Or => "or",
+      Operation::Not => "not",
+    }
+  }
+
+  pub fn is_unary(self) -> bool {
+    matches!(self, Operation::Neg | Operation::Not)
+  }
+
+  /// Evaluate the operation with Hack's 16-bit semantics. `x` is the deeper
+  /// stack operand and is ignored for unary operations.
+  pub fn apply(self, x: i16, y: i16) -> i16 {
+    match self {
+      Operation::Add => x.wrapping_add(y),
+      Operation::Sub => x.wrapping_sub(y),
+      Operation::Neg => y.wrapping_neg(),
+      Operation::Eq => -((x == y) as i16),
+      Operation::Gt => -((x > y) as i16),
+      Operation::Lt => -((x < y) as i16),
+      Operation::And => x & y,
+      Operation::Or => x | y,
+      Operation::Not => !y,
+    }
+  }
+}
+
+/// A decoded VM command, independent of the tokens it was parsed from.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Instruction {
+  Push(Segment, i16),
+  Pop(Segment, i16),
+  Arithmetic(Operation),
+  Label(String),
+  Goto(String),
+  IfGoto(String),
+  Function(String, i16),
+  Call(String, i16),
+  Return,
+}
+
+impl std::fmt::Display for Instruction {
+  fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    match self {
+      Instruction::Push(segment, index) => write!(f, "push {} {}", segment.name(), index),
+      Instruction::Pop(segment, index) => write!(f, "pop {} {}", segment.name(), index),
+      Instruction::Arithmetic(op) => write!(f, "{}", op.name()),
+      Instruction::Label(label) => write!(f, "label {}", label),
+      Instruction::Goto(label) => write!(f, "goto {}", label),
+      Instruction::IfGoto(label) => write!(f, "if-goto {}", label),
+      Instruction::Function(name, num_vars) => write!(f, "function {} {}", name, num_vars),
+      Instruction::Call(name, num_args) => write!(f, "call {} {}", name, num_args),
+      Instruction::Return => write!(f, "return"),
+    }
+  }
+}
+
+/// An instruction along with the source position of the command it came from.
+/// Instructions made up by an optimisation pass have line 0.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Located {
+  pub instruction: Instruction,
+  pub line: usize,
+  pub column: usize,
+}
+
+impl Located {
+  pub fn new(instruction: Instruction, line: usize, column: usize) -> Located {
+    Located { instruction, line, column }
+  }
+}
+
+/// The decoded contents of a single `.vm` file.
+#[derive(Debug, Clone)]
+pub struct Module {
+  pub name: String,
+  pub instructions: Vec<Located>,
+}
+
+pub fn decode(filename: &str, commands: &[Command]) -> Result<Module, String> {
+  let mut instructions = Vec::new();
+
+  for command in commands {
+    macro_rules! err {
+      ( $message:expr ) => {
+        Err(new_error(String::from($message), filename, command))
+      };
+      ( $fmtstr:expr, $($fmtargs:expr),* ) => {
+        Err(new_error(
+          format!($fmtstr, $($fmtargs),*),
+          filename,
+          command,
+        ))
+      };
+    }
+
+    let instruction = match command.name.lexeme {
+      "push" | "pop" => {
+        if command.num_args() != 2 {
+          return err!("Expected 2 arguments for {}", command.name.lexeme);
+        }
+        let first_arg = command.arg(0);
+        let second_arg = command.arg(1);
+        if let TokenType::Identifier = first_arg.type_ {
+          if !Segment::is_valid_name(first_arg.lexeme) {
+            return err!("Unknown segment '{}'", first_arg.lexeme);
+          }
+          if let Value::Integer(index) = second_arg.value {
+            let segment = Segment::from_name(first_arg.lexeme, filename)?;
+            if !segment.is_valid_index(index) {
+              return err!("Index {} out of range for segment {}", index, segment.name());
+            }
+
+            if command.name.lexeme == "pop" {
+              if !segment.is_writable() {
+                return err!("Can't pop into read-only segment {}", segment);
+              }
+              Instruction::Pop(segment, index)
+            } else {
+              Instruction::Push(segment, index)
+            }
+          } else {
+            return err!("Expected second argument to be integer");
+          }
+        } else {
+          return err!("Expected first argument to be identifier");
+        }
+      }
+
+      "add" | "sub" | "neg" | "eq" | "gt" | "lt" | "and" | "or" | "not" => {
+        if command.num_args() > 0 {
+          return err!("Expected no arguments for command {}", command.name.lexeme);
+        }
+        Instruction::Arithmetic(Operation::from_name(command.name.lexeme).unwrap())
+      },
+
+      "label" | "goto" | "if-goto" => {
+        if command.num_args() != 1 {
+          return err!("Expected 1 argument for {}",
+            command.name.lexeme);
+        }
+        let first_arg = command.arg(0);
+
+        if let TokenType::Identifier = first_arg.type_ {
+          let label = String::from(first_arg.lexeme);
+          match command.name.lexeme {
+            "label" => Instruction::Label(label),
+            "goto" => Instruction::Goto(label),
+            "if-goto" => Instruction::IfGoto(label),
+            _ => unreachable!(),
+          }
+        } else {
+          return err!("Expected argument to {} to be identifier", command.name.lexeme);
+        }
+      },
+
+      "function" | "call" => {
+        if command.num_args() != 2 {
+          return err!("Expected 2 arguments for {}", command.name.lexeme);
+        }
+        let first_arg = command.arg(0);
+        let second_arg = command.arg(1);
+
+        let function_name = if let TokenType::Identifier = first_arg.type_ {
+          String::from(first_arg.lexeme)
+        } else {
+          return err!("Expected first argument of {} to be identifier", command.name.lexeme);
+        };
+
+        let num_vars = if let Value::Integer(index) = second_arg.value {
+          index
+        } else {
+          return err!("Expected second argument of {} to be integer", command.name.lexeme);
+        };
+
+        match command.name.lexeme {
+          "function" => Instruction::Function(function_name, num_vars),
+          "call" => Instruction::Call(function_name, num_vars),
+          _ => unreachable!(),
+        }
+      },
+
+      "return" => Instruction::Return,
+
+      _ => {
+        return err!("Unknown command '{}'", command.name.lexeme);
+      },
+    };
+
+    instructions.push(Located::new(instruction, command.name.line, command.name.column));
+  }
+
+  Ok(Module {
+    name: String::from(filename),
+    instructions,
+  })
+}
+
+fn new_error(message: String, filename: &str, command: &Command) -> String {
+  error_at(message, filename, command.name.line, command.name.column)
+}
+
+pub fn error_at(message: String, filename: &str, line: usize, column: usize) -> String {
+  format!("{} at {}.vm line {}, column {}", message, filename, line, column)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use super::super::lexer::lex;
+  use super::super::parser::parse;
+
+  #[test]
+  fn rejects_indexes_outside_the_segment() {
+    for source in ["push pointer 2", "pop temp 8", "push static 240"] {
+      assert!(decode("Main", &parse(&lex(source))).is_err(), "{}", source);
+    }
+    for source in ["push pointer 1", "pop temp 7", "push static 239", "push local 32767"] {
+      assert!(decode("Main", &parse(&lex(source))).is_ok(), "{}", source);
+    }
+    let error = decode("Main", &parse(&lex("pop temp 8"))).unwrap_err();
+    assert!(error.starts_with("Index 8 out of range for segment temp"), "{}", error);
+  }
+}
@@ -0,0 +1,291 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use super::code_gen::segment::Segment;
+use super::hack::emulator::{HEAP_BASE, RAM_SIZE};
+use super::instruction::{Instruction, Located, Module};
+
+pub const STACK_BASE: i16 = 256;
+
+/// A whole VM program, flattened across modules with every jump and call
+/// target resolved to an instruction index.
+#[derive(Debug)]
+pub struct Program<'a> {
+  pub instructions: Vec<&'a Located>,
+  /// Name of the module each instruction came from.
+  pub modules: Vec<&'a str>,
+  /// Resolved target of each `goto`, `if-goto` and `call`.
+  targets: Vec<Option<usize>>,
+  pub functions: HashMap<&'a str, usize>,
+  /// Every `File.index` static referenced anywhere in the program.
+  pub statics: BTreeSet<String>,
+}
+
+impl<'a> Program<'a> {
+  pub fn load(modules: &'a [Module]) -> Result<Program<'a>, String> {
+    let mut instructions = Vec::new();
+    let mut module_names = Vec::new();
+    let mut scopes = Vec::new();
+    let mut functions = HashMap::new();
+    let mut labels = HashMap::new();
+    let mut statics = BTreeSet::new();
+
+    let mut current_function: Option<&str> = None;
+    for module in modules {
+      for located in &module.instructions {
+        let index = instructions.len();
+        match &located.instruction {
+          Instruction::Function(name, _) => {
+            if functions.insert(name.as_str(), index).is_some() {
+              return Err(format!("Function {} is defined more than once", name));
+            }
+            current_function = Some(name);
+          },
+          Instruction::Label(label) => {
+            labels.insert(format!("{}${}", current_function.unwrap_or(""), label), index);
+          },
+          Instruction::Push(segment, index)
+          | Instruction::Pop(segment, index) => {
+            if let Segment::Static(_) = segment {
+              statics.insert(static_name(segment, *index));
+            }
+          },
+          _ => (),
+        }
+        instructions.push(located);
+        module_names.push(module.name.as_str());
+        scopes.push(current_function);
+      }
+    }
+
+    let mut targets = Vec::with_capacity(instructions.len());
+    for (located, scope) in instructions.iter().zip(&scopes) {
+      targets.push(match &located.instruction {
+        Instruction::Goto(label) | Instruction::IfGoto(label) => {
+          let name = format!("{}${}", scope.unwrap_or(""), label);
+          match labels.get(&name) {
+            Some(target) => Some(*target),
+            None => return Err(format!("Undefined label {}", name)),
+          }
+        },
+        Instruction::Call(name, _) => match functions.get(name.as_str()) {
+          Some(target) => Some(*target),
+          None => return Err(format!("Call to undefined function {}", name)),
+        },
+        _ => None,
+      });
+    }
+
+    Ok(Program {
+      instructions,
+      modules: module_names,
+      targets,
+      functions,
+      statics,
+    })
+  }
+
+  pub fn describe(&self, index: usize) -> String {
+    let located = self.instructions[index];
+    format!(
+      "{}.vm:{}:{}: {}",
+      self.modules[index], located.line, located.column, located.instruction,
+    )
+  }
+}
+
+pub fn static_name(segment: &Segment, index: i16) -> String {
+  format!("{}.{}", segment, index)
+}
+
+#[derive(Debug, Clone)]
+pub struct Frame {
+  pub num_args: i16,
+  /// Where execution resumes once this frame returns, or `None` for the
+  /// frame set up by the bootstrap code.
+  pub return_pc: Option<usize>,
+}
+
+/// Executes VM instructions directly, laying the stack and segments out in
+/// RAM exactly like the translated code does.
+#[derive(Debug)]
+pub struct Interpreter<'p, 'a> {
+  program: &'p Program<'a>,
+  pub ram: Vec<i16>,
+  pub statics: BTreeMap<String, i16>,
+  pub pc: usize,
+  pub frames: Vec<Frame>,
+  pub steps: u64,
+  /// Every address at or above `HEAP_BASE` that has been written to.
+  pub heap_writes: BTreeSet<u16>,
+}
+
+impl<'p, 'a> Interpreter<'p, 'a> {
+  /// Set up the machine the way the bootstrap code does: SP at 256 and a
+  /// call to `Sys.init` with no arguments.
+  pub fn new(program: &'p Program<'a>) -> Result<Interpreter<'p, 'a>, String> {
+    let entry = match program.functions.get("Sys.init") {
+      Some(entry) => *entry,
+      None => return Err(String::from("Program has no Sys.init function")),
+    };
+    let mut interpreter = Interpreter {
+      program,
+      ram: vec![0; RAM_SIZE],
+      statics: BTreeMap::new(),
+      pc: entry,
+      frames: Vec::new(),
+      steps: 0,
+      heap_writes: BTreeSet::new(),
+    };
+    interpreter.ram[0] = STACK_BASE;
+    interpreter.enter(0, None);
+    Ok(interpreter)
+  }
+
+  pub fn sp(&self) -> i16 {
+    self.ram[0]
+  }
+
+  pub fn num_args(&self) -> i16 {
+    self.frames.last().map_or(0, |frame| frame.num_args)
+  }
+
+  /// Whether the program has finished: either the bootstrap frame returned,
+  /// execution ran off the end, or the next instruction is a `goto` to the
+  /// label right before it.
+  pub fn is_halted(&self) -> bool {
+    if self.frames.is_empty() || self.pc >= self.program.instructions.len() {
+      return true;
+    }
+    if let Instruction::Goto(_) = self.program.instructions[self.pc].instruction {
+      let target = self.program.targets[self.pc].unwrap();
+      return target <= self.pc
+        && self.program.instructions[target..self.pc].iter()
+          .all(|located| matches!(located.instruction, Instruction::Label(_)));
+    }
+    false
+  }
+
+  fn read(&self, address: i16) -> i16 {
+    self.ram[(address as u16 & 0x7fff) as usize]
+  }
+
+  fn write(&mut self, address: i16, value: i16) {
+    let address = address as u16 & 0x7fff;
+    self.ram[address as usize] = value;
+    if address >= HEAP_BASE {
+      self.heap_writes.insert(address);
+    }
+  }
+
+  fn push(&mut self, value: i16) {
+    let sp = self.sp();
+    self.write(sp, value);
+    self.ram[0] = sp.wrapping_add(1);
+  }
+
+  fn pop(&mut self) -> i16 {
+    let sp = self.sp().wrapping_sub(1);
+    self.ram[0] = sp;
+    self.read(sp)
+  }
+
+  fn address(&self, segment: &Segment, index: i16) -> i16 {
+    match segment {
+      Segment::Argument => self.ram[2].wrapping_add(index),
+      Segment::Local => self.ram[1].wrapping_add(index),
+      Segment::This => self.ram[3].wrapping_add(index),
+      Segment::That => self.ram[4].wrapping_add(index),
+      Segment::Pointer => 3i16.checked_add(index).expect("pointer index"),
+      Segment::Temp => 5i16.checked_add(index).expect("temp index"),
+      Segment::Constant | Segment::Static(_) => unreachable!(),
+    }
+  }
+
+  fn enter(&mut self, num_args: i16, return_pc: Option<usize>) {
+    self.push(return_pc.map_or(0, |pc| pc as i16));
+    for register in 1..=4 {
+      let value = self.ram[register];
+      self.push(value);
+    }
+    let sp = self.sp();
+    self.ram[2] = sp.wrapping_sub(num_args + 5);
+    self.ram[1] = sp;
+    self.frames.push(Frame {
+      num_args,
+      return_pc,
+    });
+  }
+
+  /// Execute the instruction at `pc`.
+  pub fn step(&mut self) -> Result<(), String> {
+    let program = self.program;
+    let located = match program.instructions.get(self.pc) {
+      Some(located) => *located,
+      None => return Err(String::from("Execution ran past the last instruction")),
+    };
+    self.steps += 1;
+    let mut next = self.pc + 1;
+
+    match &located.instruction {
+      Instruction::Push(segment, index) => {
+        let value = match segment {
+          Segment::Constant => *index,
+          Segment::Static(_) => *self.statics.get(&static_name(segment, *index)).unwrap_or(&0),
+          _ => self.read(self.address(segment, *index)),
+        };
+        self.push(value);
+      },
+      Instruction::Pop(segment, index) => {
+        let value = self.pop();
+        match segment {
+          Segment::Static(_) => {
+            self.statics.insert(static_name(segment, *index), value);
+          },
+          _ => {
+            let address = self.address(segment, *index);
+            self.write(address, value);
+          },
+        }
+      },
+      Instruction::Arithmetic(op) => {
+        let y = self.pop();
+        let x = if op.is_unary() { 0 } else { self.pop() };
+        self.push(op.apply(x, y));
+      },
+      Instruction::Label(_) => (),
+      Instruction::Goto(_) => next = program.targets[self.pc].unwrap(),
+      Instruction::IfGoto(_) => {
+        if self.pop() != 0 {
+          next = program.targets[self.pc].unwrap();
+        }
+      },
+      Instruction::Function(_, num_vars) => {
+        for _ in 0..*num_vars {
+          self.push(0);
+        }
+      },
+      Instruction::Call(_, num_args) => {
+        self.enter(*num_args, Some(next));
+        next = program.targets[self.pc].unwrap();
+      },
+      Instruction::Return => {
+        let frame = self.ram[1];
+        let value = self.pop();
+        let arg = self.ram[2];
+        self.write(arg, value);
+        self.ram[0] = arg.wrapping_add(1);
+        for register in 1..=4 {
+          self.ram[5 - register] = self.read(frame.wrapping_sub(register as i16));
+        }
+        let returned = self.frames.pop().unwrap();
+        match returned.return_pc {
+          Some(pc) => next = pc,
+          None => next = program.instructions.len(),
+        }
+      },
+    }
+
+    self.pc = next;
+    Ok(())
+  }
+}
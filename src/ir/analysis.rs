@@ -0,0 +1,247 @@
+use std::collections::{HashMap, HashSet};
+
+use super::super::code_gen::segment::Segment;
+use super::{Function, Inst, Terminator, Value};
+
+/// A word of memory that a segment and index always refer to within a
+/// function. `this` and `that` aren't variables, since their pointers move,
+/// and they may point at any variable, so storing through them, or calling
+/// a function that might, may change every variable.
+pub type Var = (Segment, i16);
+
+pub fn is_variable(segment: &Segment) -> bool {
+  matches!(
+    segment,
+    Segment::Local | Segment::Argument | Segment::Temp | Segment::Static(_) | Segment::Pointer,
+  )
+}
+
+/// Whether the instruction may change variables other than the one it
+/// stores to.
+fn clobbers_variables(function: &Function, inst: &Inst) -> bool {
+  match inst {
+    Inst::Def(value) => matches!(function.values[*value], Value::Call(_, _)),
+    Inst::Store(segment, _, _) => matches!(segment, Segment::This | Segment::That),
+    Inst::DeadStore(_, _) => false,
+  }
+}
+
+/// Whether the instruction may read variables other than the one it
+/// loads.
+fn reads_variables(function: &Function, inst: &Inst) -> bool {
+  match inst {
+    Inst::Def(value) => matches!(
+      function.values[*value],
+      Value::Call(_, _) | Value::Load(Segment::This | Segment::That, _),
+    ),
+    _ => false,
+  }
+}
+
+/// The blocks control can reach from the entry.
+pub fn reachable(function: &Function) -> Vec<bool> {
+  let mut reachable = vec![false; function.blocks.len()];
+  let mut work = vec![0];
+  while let Some(block) = work.pop() {
+    if block < reachable.len() && !reachable[block] {
+      reachable[block] = true;
+      work.extend(function.successors(block));
+    }
+  }
+  reachable
+}
+
+/// What's known about a variable's contents.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Fact {
+  Constant(i16),
+  /// The same as another variable.
+  Copy(Var),
+}
+
+pub type Facts = HashMap<Var, Fact>;
+
+fn forget(facts: &mut Facts, var: &Var) {
+  facts.retain(|known, fact| known != var && *fact != Fact::Copy(var.clone()));
+}
+
+/// Update `facts` for the effect of instruction `index` of `block`.
+pub fn transfer(function: &Function, block: usize, index: usize, facts: &mut Facts) {
+  let insts = &function.blocks[block].insts;
+  if clobbers_variables(function, &insts[index].0) {
+    facts.clear();
+  }
+  match &insts[index].0 {
+    Inst::Def(_) => (),
+    Inst::Store(segment, index_in_segment, value) => {
+      let var = (segment.clone(), *index_in_segment);
+      // a load right before the store reads what the variable is set to
+      let loaded_just_before = index > 0 && insts[index - 1].0 == Inst::Def(*value);
+      let fact = match &function.values[*value] {
+        Value::Constant(constant) => Some(Fact::Constant(*constant)),
+        Value::Load(segment, index) if loaded_just_before && is_variable(segment) => {
+          let source = (segment.clone(), *index);
+          if source == var {
+            return;
+          }
+          Some(Fact::Copy(source))
+        },
+        _ => None,
+      };
+      forget(facts, &var);
+      if let Some(fact) = fact.filter(|_| is_variable(segment)) {
+        facts.insert(var, fact);
+      }
+    },
+    Inst::DeadStore(segment, index) => forget(facts, &(segment.clone(), *index)),
+  }
+}
+
+/// The facts that hold on entry to every reachable block: locals start at
+/// 0, and anything else is known only if it's known on every path there.
+pub fn facts(function: &Function) -> Vec<Option<Facts>> {
+  let mut facts_in: Vec<Option<Facts>> = vec![None; function.blocks.len()];
+  if function.blocks.is_empty() {
+    return facts_in;
+  }
+  facts_in[0] = Some((0..function.num_locals).map(|index| {
+    ((Segment::Local, index), Fact::Constant(0))
+  }).collect());
+
+  let mut work = vec![0];
+  while let Some(block) = work.pop() {
+    let mut facts = facts_in[block].clone().unwrap();
+    for index in 0..function.blocks[block].insts.len() {
+      transfer(function, block, index, &mut facts);
+    }
+    for successor in function.successors(block) {
+      let joined = match &facts_in[successor] {
+        None => facts.clone(),
+        Some(known) => known.iter()
+          .filter(|(var, fact)| facts.get(*var) == Some(*fact))
+          .map(|(var, fact)| (var.clone(), fact.clone()))
+          .collect(),
+      };
+      if facts_in[successor].as_ref() != Some(&joined) {
+        facts_in[successor] = Some(joined);
+        work.push(successor);
+      }
+    }
+  }
+  facts_in
+}
+
+/// Whether dead store elimination considers the variable. Values in other
+/// segments can outlive the function.
+pub fn is_local(var: &Var) -> bool {
+  matches!(var.0, Segment::Local | Segment::Temp)
+}
+
+/// Every local and temp variable the function uses.
+fn locals(function: &Function) -> HashSet<Var> {
+  let mut locals = HashSet::new();
+  for block in &function.blocks {
+    for (inst, _) in &block.insts {
+      let var = match inst {
+        Inst::Def(value) => match &function.values[*value] {
+          Value::Load(segment, index) => (segment.clone(), *index),
+          _ => continue,
+        },
+        Inst::Store(segment, index, _) | Inst::DeadStore(segment, index) => {
+          (segment.clone(), *index)
+        },
+      };
+      if is_local(&var) {
+        locals.insert(var);
+      }
+    }
+  }
+  locals
+}
+
+/// Update `live` to what's live before instruction `index` of `block`,
+/// given what's live after it. Calls and loads through `this` and `that`
+/// may read any of `locals`.
+pub fn transfer_back(
+  function: &Function,
+  block: usize,
+  index: usize,
+  live: &mut HashSet<Var>,
+  locals: &HashSet<Var>,
+) {
+  let inst = &function.blocks[block].insts[index].0;
+  if reads_variables(function, inst) {
+    live.extend(locals.iter().cloned());
+  }
+  match inst {
+    Inst::Def(value) => match &function.values[*value] {
+      Value::Load(segment, index) if is_local(&(segment.clone(), *index)) => {
+        live.insert((segment.clone(), *index));
+      },
+      _ => (),
+    },
+    Inst::Store(segment, index, _) | Inst::DeadStore(segment, index) => {
+      live.remove(&(segment.clone(), *index));
+    },
+  }
+}
+
+/// The local and temp variables live on exit from every block, along with
+/// all of them. Locals die when the function returns, but temps may still be
+/// read by its caller, and everything may be read once the program halts.
+pub fn liveness(function: &Function) -> (Vec<HashSet<Var>>, HashSet<Var>) {
+  let all = locals(function);
+  let temps: HashSet<Var> = all.iter().filter(|var| var.0 == Segment::Temp).cloned().collect();
+  let mut live_in = vec![HashSet::new(); function.blocks.len()];
+  let mut live_out = vec![HashSet::new(); function.blocks.len()];
+  loop {
+    let mut changed = false;
+    for block in (0..function.blocks.len()).rev() {
+      let mut live: HashSet<Var> = match function.blocks[block].terminator.0 {
+        Terminator::Return(_) => temps.clone(),
+        _ if function.falls_off(block) || function.halts(block) => all.clone(),
+        _ => function.successors(block).iter()
+          .flat_map(|successor| live_in[*successor].iter().cloned())
+          .collect(),
+      };
+      live_out[block] = live.clone();
+      for index in (0..function.blocks[block].insts.len()).rev() {
+        transfer_back(function, block, index, &mut live, &all);
+      }
+      if live != live_in[block] {
+        live_in[block] = live;
+        changed = true;
+      }
+    }
+    if !changed {
+      return (live_out, all);
+    }
+  }
+}
+
+/// For every block, the variables whose removed stores may have left memory
+/// different from the original program's on entry to it.
+pub fn stale(function: &Function, reachable: &[bool]) -> Vec<HashSet<Var>> {
+  let mut stale_in = vec![HashSet::new(); function.blocks.len()];
+  loop {
+    let mut changed = false;
+    for (block, _) in reachable.iter().enumerate().filter(|(_, reachable)| **reachable) {
+      let mut stale = stale_in[block].clone();
+      for (inst, _) in &function.blocks[block].insts {
+        match inst {
+          Inst::Store(segment, index, _) => stale.remove(&(segment.clone(), *index)),
+          Inst::DeadStore(segment, index) => stale.insert((segment.clone(), *index)),
+          Inst::Def(_) => continue,
+        };
+      }
+      for successor in function.successors(block) {
+        let before = stale_in[successor].len();
+        stale_in[successor].extend(stale.iter().cloned());
+        changed |= stale_in[successor].len() != before;
+      }
+    }
+    if !changed {
+      return stale_in;
+    }
+  }
+}
@@ -0,0 +1,368 @@
+use std::collections::HashMap;
+
+use super::super::assembly_builder::{
+  AssemblyBuilder, FRAME, RET, SHARED_EQ, SHARED_GT, SHARED_LT, SHARED_RETURN,
+};
+use super::super::code_gen::segment::Segment;
+use super::super::hack::asm::{AsmInstr, Comp, Jump};
+use super::super::instruction::{Module, Operation};
+use super::super::passes::spans;
+use super::super::translator::Options;
+use super::{lift, Function, Inst, Position, Terminator, Value, ValueId};
+
+/// How far up from a segment's base an operand may be to be read without
+/// going through D. Walking up takes an instruction per word, which is still
+/// shorter than saving D on the stack and taking it back.
+const OPERAND_BUDGET: usize = 8;
+
+/// An expression tree without calls, taken out of the IR to translate.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+  Constant(i16),
+  Load(Segment, i16),
+  Unary(Operation, Box<Expr>),
+  Binary(Operation, Box<Expr>, Box<Expr>),
+}
+
+/// What a statement does with the value it computes.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Effect {
+  Store(Segment, i16),
+  IfGoto(String),
+  Return,
+}
+
+/// Commands that start with the function's stack empty, compute one value
+/// without calling anything and use it up, which can be translated from
+/// their expression tree instead of one by one.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Statement {
+  /// The index of the last command in the module.
+  pub end: usize,
+  pub expr: Expr,
+  pub effect: Effect,
+}
+
+/// The statements of every function in the module that can be lifted, by
+/// the index of their first command.
+pub fn statements(module: &Module) -> HashMap<usize, Statement> {
+  let mut statements = HashMap::new();
+  for span in spans(module) {
+    let function = match lift(&module.instructions[span.start..span.end]) {
+      Some(function) => function,
+      None => continue,
+    };
+    let mut add = |before: &[(Inst, Position)], value, position: Position, effect| {
+      if let Some((start, end)) = extent(&function, before, value, position) {
+        let expr = expr(&function, value);
+        statements.insert(span.start + start, Statement { end: span.start + end, expr, effect });
+      }
+    };
+    for block in &function.blocks {
+      for (index, (inst, position)) in block.insts.iter().enumerate() {
+        if let Inst::Store(segment, offset, value) = inst {
+          add(&block.insts[..index], *value, *position, Effect::Store(segment.clone(), *offset));
+        }
+      }
+      match &block.terminator {
+        (Terminator::IfGoto(value, label), position) => {
+          add(&block.insts, *value, *position, Effect::IfGoto(label.clone()));
+        },
+        (Terminator::Return(value), position) => {
+          add(&block.insts, *value, *position, Effect::Return);
+        },
+        _ => (),
+      }
+    }
+  }
+  statements
+}
+
+/// The indexes in the function of the first and last commands of the
+/// statement that uses `value` at `position`, if `before`, the instructions
+/// before that, end with everything that computes the value, starting from
+/// an empty stack.
+fn extent(
+  function: &Function,
+  before: &[(Inst, Position)],
+  value: ValueId,
+  position: Position,
+) -> Option<(usize, usize)> {
+  if !function.is_pure(value) {
+    return None;
+  }
+  let tree = function.tree(value);
+  let start = before.len().checked_sub(tree.len())?;
+  let computed = &before[start..];
+  let adjacent = computed.iter().all(|(inst, _)| {
+    matches!(inst, Inst::Def(operand) if tree.contains(operand))
+  });
+  let first = computed.first()?.1;
+  if !adjacent || !first.statement || position.index - first.index != tree.len() {
+    return None;
+  }
+  Some((first.index, position.index))
+}
+
+fn expr(function: &Function, value: ValueId) -> Expr {
+  let operand = |value: &ValueId| Box::new(expr(function, *value));
+  match &function.values[value] {
+    Value::Constant(constant) => Expr::Constant(*constant),
+    Value::Load(segment, index) => Expr::Load(segment.clone(), *index),
+    Value::Unary(op, x) => Expr::Unary(*op, operand(x)),
+    Value::Binary(op, x, y) => Expr::Binary(*op, operand(x), operand(y)),
+    Value::Param(_) | Value::Call(_, _) => unreachable!("statements are pure"),
+  }
+}
+
+impl Statement {
+  /// Translate the statement, in `function_name` and with `options`.
+  pub fn translate(&self, a: &mut AssemblyBuilder, function_name: &str, options: &Options) {
+    match &self.effect {
+      Effect::Store(segment, index) => {
+        if let Some((address, delta)) = increment(&self.expr, segment, *index) {
+          increment!(*a, address, delta);
+          return;
+        }
+        compute(a, &self.expr, options);
+        store_D!(*a, segment, *index);
+      },
+      Effect::IfGoto(label) => match fused_branch(&self.expr).filter(|_| options.fused_branches) {
+        Some((jump @ (Jump::Eq | Jump::Ne), x, y)) => {
+          let difference = Expr::Binary(Operation::Sub, Box::new(x.clone()), Box::new(y.clone()));
+          compute(a, &difference, options);
+          let target = format!("{}${}", function_name, label);
+          hack!(*a,
+            #("BRANCH TO {}", target),
+            @(target),
+            D;(jump),
+          );
+        },
+        Some((jump, x, y)) => {
+          compute(a, x, options);
+          push_D!(*a);
+          compute(a, y, options);
+          push_D!(*a);
+          compare_branch!(*a, jump, function_name, label);
+        },
+        None => {
+          compute(a, &self.expr, options);
+          if_goto_D!(*a, function_name, label);
+        },
+      },
+      Effect::Return => {
+        compute(a, &self.expr, options);
+        push_D!(*a);
+        if options.shared_calls {
+          return_shared!(*a);
+        } else {
+          return_!(*a);
+        }
+      },
+    }
+  }
+}
+
+/// `s i + c` or `s i - c` stored back to `s i`: the address of `s i` and
+/// the change, as `Superinstruction::Increment` takes them.
+fn increment(expr: &Expr, segment: &Segment, index: i16) -> Option<(Vec<AsmInstr>, i16)> {
+  let same = |operand: &Expr| *operand == Expr::Load(segment.clone(), index);
+  let delta = match expr {
+    Expr::Binary(Operation::Add, x, y) => match (&**x, &**y) {
+      (x, Expr::Constant(c)) if same(x) => *c,
+      (Expr::Constant(c), y) if same(y) => *c,
+      _ => return None,
+    },
+    Expr::Binary(Operation::Sub, x, y) => match &**y {
+      Expr::Constant(c) if same(x) => c.wrapping_neg(),
+      _ => return None,
+    },
+    _ => return None,
+  };
+  let address = match delta {
+    _ if !segment.is_writable() => None,
+    1 | -1 => Some(segment.resolve_address(index)),
+    _ => segment.address_without_d(index, OPERAND_BUDGET),
+  };
+  address.map(|address| (address, delta))
+}
+
+/// A comparison, or one negated with `not`, as the jump on x-y that branches
+/// when it's true, and x and y.
+fn fused_branch(expr: &Expr) -> Option<(Jump, &Expr, &Expr)> {
+  let (comparison, negated) = match expr {
+    Expr::Unary(Operation::Not, operand) => (&**operand, true),
+    _ => (expr, false),
+  };
+  let (op, x, y) = match comparison {
+    Expr::Binary(op @ (Operation::Eq | Operation::Gt | Operation::Lt), x, y) => (op, x, y),
+    _ => return None,
+  };
+  let jump = match (op, negated) {
+    (Operation::Eq, false) => Jump::Eq,
+    (Operation::Eq, true) => Jump::Ne,
+    (Operation::Gt, false) => Jump::Gt,
+    (Operation::Gt, true) => Jump::Le,
+    (Operation::Lt, false) => Jump::Lt,
+    (Operation::Lt, true) => Jump::Ge,
+    _ => unreachable!(),
+  };
+  Some((jump, x, y))
+}
+
+/// Compute the expression into D, keeping intermediate values on the stack.
+/// An operand that's a constant or a variable near its segment's base is
+/// combined with D straight from A or memory instead.
+fn compute(a: &mut AssemblyBuilder, expr: &Expr, options: &Options) {
+  match expr {
+    Expr::Constant(constant) => load_constant!(*a, *constant),
+    Expr::Load(segment, index) => load!(*a, segment, *index),
+    Expr::Unary(op, x) => {
+      compute(a, x, options);
+      match op {
+        Operation::Neg => unary_D!(*a, "NEG", Comp::NegD),
+        _ => unary_D!(*a, "NOT", Comp::NotD),
+      };
+    },
+    Expr::Binary(op @ (Operation::Eq | Operation::Gt | Operation::Lt), x, y) => {
+      compute(a, x, options);
+      push_D!(*a);
+      compute(a, y, options);
+      push_D!(*a);
+      match op {
+        Operation::Eq if options.shared_comparisons => call_routine!(*a, SHARED_EQ),
+        Operation::Gt if options.shared_comparisons => call_routine!(*a, SHARED_GT),
+        Operation::Lt if options.shared_comparisons => call_routine!(*a, SHARED_LT),
+        Operation::Eq => eq!(*a),
+        Operation::Gt => gt!(*a),
+        _ => lt!(*a),
+      };
+      pop_D!(*a);
+    },
+    Expr::Binary(op, x, y) => {
+      if let Some(combine) = combine(*op, y, false) {
+        compute(a, x, options);
+        a.extend(combine);
+      } else if let Some(combine) = combine(*op, x, true) {
+        compute(a, y, options);
+        a.extend(combine);
+      } else {
+        compute(a, x, options);
+        push_D!(*a);
+        compute(a, y, options);
+        let (name, comp) = match op {
+          Operation::Add => ("ADD", Comp::DPlusM),
+          Operation::Sub => ("SUB", Comp::MMinusD),
+          Operation::And => ("AND", Comp::DAndM),
+          _ => ("OR", Comp::DOrM),
+        };
+        binary_D!(*a, name, comp);
+      }
+    },
+  }
+}
+
+/// The instructions that set D to D `op` `operand`, or `operand` `op` D if
+/// `swapped`, if the operand can be read without D.
+fn combine(op: Operation, operand: &Expr, swapped: bool) -> Option<Vec<AsmInstr>> {
+  let mut combine = Vec::new();
+  let in_memory = match operand {
+    Expr::Constant(constant) => {
+      // D+c is D-(-c)
+      let (op, constant) = match (op, *constant) {
+        (_, 0..) => (op, *constant),
+        (Operation::Add, i16::MIN) | (Operation::Sub, i16::MIN) => return None,
+        (Operation::Add, _) if !swapped => (Operation::Sub, -constant),
+        (Operation::Sub, _) if !swapped => (Operation::Add, -constant),
+        _ => return None,
+      };
+      hack!(combine, @(constant));
+      return Some(with_comp(combine, op, false, swapped));
+    },
+    Expr::Load(segment, index) => segment.address_without_d(*index, OPERAND_BUDGET)?,
+    _ => return None,
+  };
+  combine.extend(in_memory);
+  Some(with_comp(combine, op, true, swapped))
+}
+
+/// `instructions` followed by the one applying `op` to D and A, or to D and
+/// M if `memory`.
+fn with_comp(
+  mut instructions: Vec<AsmInstr>,
+  op: Operation,
+  memory: bool,
+  swapped: bool,
+) -> Vec<AsmInstr> {
+  let comp = match (op, memory, swapped) {
+    (Operation::Add, false, _) => Comp::DPlusA,
+    (Operation::Add, true, _) => Comp::DPlusM,
+    (Operation::Sub, false, false) => Comp::DMinusA,
+    (Operation::Sub, true, false) => Comp::DMinusM,
+    (Operation::Sub, false, true) => Comp::AMinusD,
+    (Operation::Sub, true, true) => Comp::MMinusD,
+    (Operation::And, false, _) => Comp::DAndA,
+    (Operation::And, true, _) => Comp::DAndM,
+    (Operation::Or, false, _) => Comp::DOrA,
+    (Operation::Or, true, _) => Comp::DOrM,
+    _ => unreachable!("only add, sub, and and or combine D with an operand"),
+  };
+  hack!(instructions, D=(comp));
+  instructions
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use super::super::super::instruction::decode;
+  use super::super::super::lexer::lex;
+  use super::super::super::parser::parse;
+
+  #[test]
+  fn finds_statements_without_calls() {
+    let source = [
+      "function Sys.init 2", "push local 0", "push constant 2", "add", "neg", "pop local 1",
+      "push local 1", "call Main.f 1", "pop local 0", "label LOOP", "push local 0",
+      "push constant 3", "lt", "if-goto LOOP", "push local 0", "return",
+    ].join("\n");
+    let module = decode("Sys", &parse(&lex(&source))).unwrap();
+    let load = |index| Box::new(Expr::Load(Segment::Local, index));
+    let found = statements(&module);
+    assert_eq!(found.len(), 3);
+    assert_eq!(found[&1], Statement {
+      end: 5,
+      expr: Expr::Unary(
+        Operation::Neg,
+        Box::new(Expr::Binary(Operation::Add, load(0), Box::new(Expr::Constant(2)))),
+      ),
+      effect: Effect::Store(Segment::Local, 1),
+    });
+    assert_eq!(found[&10], Statement {
+      end: 13,
+      expr: Expr::Binary(Operation::Lt, load(0), Box::new(Expr::Constant(3))),
+      effect: Effect::IfGoto(String::from("LOOP")),
+    });
+    assert_eq!(found[&14], Statement { end: 15, expr: *load(0), effect: Effect::Return });
+  }
+
+  #[test]
+  fn combines_simple_operands_without_the_stack() {
+    let mut a = AssemblyBuilder::new();
+    let start = a.instructions.len();
+    let expr = Expr::Binary(
+      Operation::Sub,
+      Box::new(Expr::Constant(7)),
+      Box::new(Expr::Binary(
+        Operation::And,
+        Box::new(Expr::Load(Segment::Local, 1)),
+        Box::new(Expr::Load(Segment::Static(String::from("Sys")), 0)),
+      )),
+    );
+    compute(&mut a, &expr, &Options::default());
+    let code: Vec<String> = a.instructions[start..].iter()
+      .filter(|instruction| instruction.is_code())
+      .map(|instruction| instruction.to_string().trim().to_string())
+      .collect();
+    assert_eq!(code, ["@LCL", "A=M+1", "D=M", "@Sys.0", "D=D&M", "@7", "D=A-D"]);
+  }
+}
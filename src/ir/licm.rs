@@ -0,0 +1,518 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use super::super::assembly_builder::SCRATCH_VARIABLES;
+use super::super::code_gen::segment::{Segment, STATIC_WORDS};
+use super::super::instruction::{Instruction, Module};
+use super::super::passes::spans;
+use super::analysis::Var;
+use super::loops::{self, Loop};
+use super::{Block, Function, Inst, Position, Terminator, Value, ValueId};
+
+/// Hoisted values live in statics of this pseudo-file. A loop may call a
+/// function with loops of its own, so every hoisted value gets its own.
+const SCRATCH: &str = "__VM_LICM";
+
+/// Functions of the Jack OS whose results depend only on their arguments,
+/// for programs linked without the OS. `Math.divide` and `Math.sqrt` aren't:
+/// they call `Sys.error` for some arguments.
+const PURE_OS_FUNCTIONS: [&str; 4] = ["Math.abs", "Math.max", "Math.min", "Math.multiply"];
+
+fn scratch() -> Segment {
+  Segment::Static(String::from(SCRATCH))
+}
+
+/// What hoisting needs to know about the rest of the program.
+pub struct Program {
+  /// The functions each function calls.
+  calls: HashMap<String, HashSet<String>>,
+  /// Functions without effects whose results depend only on their
+  /// arguments: they only use constants, arguments and locals, and only
+  /// call functions like them.
+  pure: HashSet<String>,
+  /// How many more statics are free for hoisted values.
+  free: usize,
+  next: i16,
+}
+
+impl Program {
+  pub fn new(modules: &[Module]) -> Program {
+    let mut calls: HashMap<String, HashSet<String>> = HashMap::new();
+    let mut pure = HashSet::new();
+    let mut statics = HashSet::new();
+    for module in modules {
+      for span in spans(module) {
+        let mut called = HashSet::new();
+        let mut has_effects = false;
+        for located in &module.instructions[span.start..span.end] {
+          match &located.instruction {
+            Instruction::Push(segment, index) | Instruction::Pop(segment, index) => {
+              if let Segment::Static(_) = segment {
+                statics.insert((segment.clone(), *index));
+              }
+              has_effects |= match located.instruction {
+                Instruction::Push(_, _) => {
+                  !matches!(segment, Segment::Constant | Segment::Local | Segment::Argument)
+                },
+                _ => !matches!(segment, Segment::Local | Segment::Argument),
+              };
+            },
+            Instruction::Call(name, _) => {
+              called.insert(name.clone());
+            },
+            _ => (),
+          }
+        }
+        if !has_effects {
+          pure.insert(span.name.clone());
+        }
+        calls.insert(span.name, called);
+      }
+    }
+    pure.extend(PURE_OS_FUNCTIONS.iter()
+      .filter(|name| !calls.contains_key(**name))
+      .map(|name| name.to_string()));
+    // a function calling one with effects has them too
+    loop {
+      let impure: Vec<String> = pure.iter()
+        .filter(|name| calls.get(*name).into_iter().flatten().any(|callee| !pure.contains(callee)))
+        .cloned()
+        .collect();
+      if impure.is_empty() {
+        break;
+      }
+      for name in impure {
+        pure.remove(&name);
+      }
+    }
+    let free = STATIC_WORDS.saturating_sub(statics.len() + SCRATCH_VARIABLES.len());
+    Program { calls, pure, free, next: 0 }
+  }
+
+  fn is_pure(&self, function: &str) -> bool {
+    self.pure.contains(function)
+  }
+
+  /// Whether calling `from` may lead to a call to `to`.
+  fn reaches(&self, from: &str, to: &str) -> bool {
+    let mut seen = HashSet::new();
+    let mut work = vec![from];
+    while let Some(name) = work.pop() {
+      if name == to {
+        return true;
+      }
+      if seen.insert(name) {
+        work.extend(self.calls.get(name).into_iter().flatten().map(|callee| callee.as_str()));
+      }
+    }
+    false
+  }
+
+  fn allocate(&mut self) -> Option<i16> {
+    self.free = self.free.checked_sub(1)?;
+    self.next += 1;
+    Some(self.next - 1)
+  }
+}
+
+/// What the loop changes, for telling which values stay the same.
+struct Effects {
+  stored: HashSet<Var>,
+  /// Whether it stores through `this` or `that`.
+  heap: bool,
+  calls: bool,
+  impure_calls: bool,
+}
+
+impl Effects {
+  fn of(function: &Function, found: &Loop, program: &Program) -> Effects {
+    let mut effects = Effects {
+      stored: HashSet::new(),
+      heap: false,
+      calls: false,
+      impure_calls: false,
+    };
+    for block in &found.blocks {
+      for (inst, _) in &function.blocks[*block].insts {
+        match inst {
+          Inst::Store(segment, index, _) | Inst::DeadStore(segment, index) => {
+            effects.heap |= matches!(segment, Segment::This | Segment::That);
+            effects.stored.insert((segment.clone(), *index));
+          },
+          Inst::Def(value) => {
+            if let Value::Call(name, _) = &function.values[*value] {
+              effects.calls = true;
+              effects.impure_calls |= !program.is_pure(name);
+            }
+          },
+        }
+      }
+    }
+    effects
+  }
+
+  /// Whether the value is the same on every iteration of the loop. Pure
+  /// functions may still use temps, as the Jack compiler does. Storing
+  /// through `this` or `that` may change anything, and so may functions that
+  /// aren't pure.
+  fn is_invariant(&self, function: &Function, value: ValueId, program: &Program) -> bool {
+    let invariant = |value: &ValueId| self.is_invariant(function, *value, program);
+    let aliased = self.heap || self.impure_calls;
+    match &function.values[value] {
+      Value::Param(_) => false,
+      Value::Constant(_) => true,
+      Value::Load(segment, index) => {
+        let stored = self.stored.contains(&(segment.clone(), *index));
+        !stored && !aliased && match segment {
+          Segment::Temp => !self.calls,
+          Segment::This => invariant_pointer(self, 0),
+          Segment::That => invariant_pointer(self, 1),
+          _ => true,
+        }
+      },
+      Value::Unary(_, operand) => invariant(operand),
+      Value::Binary(_, x, y) => invariant(x) && invariant(y),
+      Value::Call(name, args) => program.is_pure(name) && args.iter().all(invariant),
+    }
+  }
+}
+
+fn invariant_pointer(effects: &Effects, index: i16) -> bool {
+  !effects.stored.contains(&(Segment::Pointer, index))
+}
+
+fn has_call(function: &Function, value: ValueId) -> bool {
+  function.tree(value).iter().any(|value| matches!(function.values[*value], Value::Call(_, _)))
+}
+
+/// Whether replacing the value with a load saves anything: it must be
+/// computed, and not only from constants.
+fn is_worth_hoisting(function: &Function, value: ValueId) -> bool {
+  let is_constant = |value: &ValueId| matches!(function.values[*value], Value::Constant(_));
+  !matches!(function.values[value], Value::Constant(_) | Value::Load(_, _))
+    && !function.tree(value).iter().all(is_constant)
+}
+
+/// What LICM did, over all functions.
+#[derive(Debug, Default)]
+pub struct Stats {
+  pub loops: usize,
+  pub hoisted_from: usize,
+  pub expressions: usize,
+  pub calls: usize,
+}
+
+impl fmt::Display for Stats {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(
+      f,
+      "licm: hoisted {} expressions ({} with calls) out of {} of {} loops",
+      self.expressions, self.calls, self.hoisted_from, self.loops,
+    )
+  }
+}
+
+/// Hoist invariant expressions out of every loop in the function, innermost
+/// loops first.
+pub fn optimise(function: &mut Function, program: &mut Program, stats: &mut Stats) {
+  stats.loops += loops::find(function).len();
+  let mut done: HashSet<String> = HashSet::new();
+  loop {
+    let found = loops::find(function).into_iter().find(|found| {
+      matches!(&function.blocks[found.header].label, Some((label, _)) if !done.contains(label))
+    });
+    let found = match found {
+      Some(found) => found,
+      None => return,
+    };
+    let (label, _) = function.blocks[found.header].label.clone().unwrap();
+    done.insert(label);
+    if let Some(label) = hoist(function, &found, program, stats) {
+      stats.hoisted_from += 1;
+      done.insert(label);
+    }
+  }
+}
+
+/// A label the function doesn't use yet.
+fn fresh_label(function: &Function) -> String {
+  (0..).map(|n| format!("__VM_LOOP_{}", n))
+    .find(|label| function.block_labelled(label).is_none())
+    .unwrap()
+}
+
+/// Hoist the loop's invariant expressions, returning the header's new label
+/// if anything was hoisted.
+///
+/// Expressions without calls are computed before the loop, even if the loop
+/// wouldn't have computed them at all. Calls may not return, so they're only
+/// hoisted from the header, which runs at least once: the header is peeled,
+/// and its first copy computes and saves what the second copy, which the
+/// loop jumps back to, only loads. Either way, the loop is entered under the
+/// header's old label and continues from its new one.
+fn hoist(
+  function: &mut Function,
+  found: &Loop,
+  program: &mut Program,
+  stats: &mut Stats,
+) -> Option<String> {
+  let header = found.header;
+  let (label, label_position) = function.blocks[header].label.clone()?;
+  // peeling the header would take the values it's passed with it
+  if loops::falls_into_header(function, found) || function.has_params() {
+    return None;
+  }
+  let name = match &function.header.instruction {
+    Instruction::Function(name, _) => name.clone(),
+    _ => return None,
+  };
+  // the loop's saved values mustn't be overwritten by a call back into it
+  let reentrant = found.blocks.iter()
+    .flat_map(|block| &function.blocks[*block].insts)
+    .any(|(inst, _)| match inst {
+      Inst::Def(value) => {
+        matches!(&function.values[*value], Value::Call(callee, _) if program.reaches(callee, &name))
+      },
+      _ => false,
+    });
+  if reentrant {
+    return None;
+  }
+
+  let effects = Effects::of(function, found, program);
+  let hoistable = |value: ValueId, block: usize| {
+    effects.is_invariant(function, value, program)
+      && (block == header || !has_call(function, value))
+  };
+  let mut consumers: HashMap<ValueId, ValueId> = HashMap::new();
+  for (value, computed) in function.values.iter().enumerate() {
+    let operands = match computed {
+      Value::Unary(_, operand) => vec![*operand],
+      Value::Binary(_, x, y) => vec![*x, *y],
+      Value::Call(_, args) => args.clone(),
+      _ => Vec::new(),
+    };
+    for operand in operands {
+      consumers.insert(operand, value);
+    }
+  }
+  let mut before: Vec<ValueId> = Vec::new();
+  let mut peeled: Vec<ValueId> = Vec::new();
+  for block in &found.blocks {
+    for (inst, _) in &function.blocks[*block].insts {
+      let value = match inst {
+        Inst::Def(value) => *value,
+        _ => continue,
+      };
+      let maximal = consumers.get(&value).is_none_or(|consumer| !hoistable(*consumer, *block));
+      if maximal && hoistable(value, *block) && is_worth_hoisting(function, value) {
+        match has_call(function, value) {
+          true => peeled.push(value),
+          false => before.push(value),
+        }
+      }
+    }
+  }
+  // the peeled header has to continue with the block after it
+  let next = match &function.blocks[header].terminator.0 {
+    Terminator::FallThrough | Terminator::IfGoto(_, _) => Some(header + 1),
+    Terminator::Goto(_) => None,
+    Terminator::Return(_) => return None,
+  };
+  if next.is_some_and(|next| next >= function.blocks.len()) {
+    peeled.clear();
+  }
+  before.truncate(program.free);
+  peeled.truncate(program.free - before.len());
+  if before.is_empty() && peeled.is_empty() {
+    return None;
+  }
+
+  let mut preheader = Vec::new();
+  if !before.is_empty() {
+    let mut block = Block::new(None);
+    for value in before {
+      let index = program.allocate().unwrap();
+      let copy = copy_tree(function, value, &mut block.insts);
+      block.insts.push((Inst::Store(scratch(), index, copy), Position::NONE));
+      function.replace(value, Value::Load(scratch(), index));
+      stats.expressions += 1;
+    }
+    preheader.push(block);
+  }
+
+  let next_label = next.filter(|_| !peeled.is_empty()).map(|next| {
+    match &function.blocks[next].label {
+      Some((next_label, _)) => next_label.clone(),
+      None => {
+        let next_label = fresh_label(function);
+        function.blocks[next].label = Some((next_label.clone(), Position::NONE));
+        next_label
+      },
+    }
+  });
+  let new_label = fresh_label(function);
+  let retarget = |target: &String| match *target == label {
+    true => new_label.clone(),
+    false => target.clone(),
+  };
+  if !peeled.is_empty() {
+    let mut block = Block::new(None);
+    let mut copies: HashMap<ValueId, ValueId> = HashMap::new();
+    let original = function.blocks[header].clone();
+    for (inst, position) in &original.insts {
+      match inst {
+        Inst::Def(value) => {
+          let copy = function.define(copy_value(&function.values[*value], &copies));
+          block.insts.push((Inst::Def(copy), *position));
+          copies.insert(*value, copy);
+          if peeled.contains(value) {
+            let index = program.allocate().unwrap();
+            block.insts.push((Inst::Store(scratch(), index, copy), Position::NONE));
+            let saved = function.define(Value::Load(scratch(), index));
+            block.insts.push((Inst::Def(saved), Position::NONE));
+            copies.insert(*value, saved);
+            function.replace(*value, Value::Load(scratch(), index));
+            stats.expressions += 1;
+            stats.calls += 1;
+          }
+        },
+        Inst::Store(segment, index, value) => {
+          block.insts.push((Inst::Store(segment.clone(), *index, copies[value]), *position));
+        },
+        Inst::DeadStore(_, _) => block.insts.push((inst.clone(), *position)),
+      }
+    }
+    let (terminator, position) = &original.terminator;
+    block.terminator = (match terminator {
+      Terminator::Goto(target) => Terminator::Goto(retarget(target)),
+      Terminator::IfGoto(value, target) => Terminator::IfGoto(copies[value], retarget(target)),
+      _ => Terminator::FallThrough,
+    }, *position);
+    preheader.push(block);
+    if let Some(next_label) = next_label {
+      let mut jump = Block::new(None);
+      jump.terminator = (Terminator::Goto(next_label), Position::NONE);
+      preheader.push(jump);
+    }
+  }
+
+  for block in &found.blocks {
+    let terminator = &mut function.blocks[*block].terminator.0;
+    match terminator {
+      Terminator::Goto(target) | Terminator::IfGoto(_, target) => *target = retarget(target),
+      _ => (),
+    }
+  }
+  function.blocks[header].label = Some((new_label.clone(), label_position));
+  preheader[0].label = Some((label, label_position));
+  function.blocks.splice(header..header, preheader);
+  Some(new_label)
+}
+
+/// Define a copy of the value's tree at the end of `insts`.
+fn copy_tree(
+  function: &mut Function,
+  value: ValueId,
+  insts: &mut Vec<(Inst, Position)>,
+) -> ValueId {
+  let copy = match function.values[value].clone() {
+    Value::Unary(op, operand) => Value::Unary(op, copy_tree(function, operand, insts)),
+    Value::Binary(op, x, y) => {
+      let x = copy_tree(function, x, insts);
+      Value::Binary(op, x, copy_tree(function, y, insts))
+    },
+    Value::Call(name, args) => {
+      Value::Call(name, args.iter().map(|arg| copy_tree(function, *arg, insts)).collect())
+    },
+    leaf => leaf,
+  };
+  let copy = function.define(copy);
+  insts.push((Inst::Def(copy), Position::NONE));
+  copy
+}
+
+/// The value computed from the copies of its operands.
+fn copy_value(value: &Value, copies: &HashMap<ValueId, ValueId>) -> Value {
+  match value {
+    Value::Unary(op, operand) => Value::Unary(*op, copies[operand]),
+    Value::Binary(op, x, y) => Value::Binary(*op, copies[x], copies[y]),
+    Value::Call(name, args) => {
+      Value::Call(name.clone(), args.iter().map(|arg| copies[arg]).collect())
+    },
+    leaf => leaf.clone(),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::super::super::hack::assembler::{self, FIRST_VARIABLE_ADDRESS};
+  use super::super::super::instruction::decode;
+  use super::super::super::lexer::lex;
+  use super::super::super::parser::parse;
+  use super::super::super::pipeline;
+  use super::super::super::translator::Options;
+
+  /// With 230 statics, 20 invariant sums in a loop and the passes that
+  /// allocate variables, the variables must still end below the stack.
+  #[test]
+  fn hoists_only_into_free_statics() {
+    let mut lines = vec![String::from("function Sys.init 0")];
+    for index in 0..230 {
+      lines.push(format!("push constant {}\npop static {}", index, index));
+    }
+    lines.push(String::from("label LOOP\npush temp 0\npush constant 3\nlt\nnot\nif-goto END"));
+    for index in 0..20 {
+      lines.push(format!(
+        "push static {}\npush static {}\nadd\npop static {}",
+        index, index + 1, 200 + index,
+      ));
+    }
+    lines.push(String::from("push temp 0\npush constant 1\nadd\npop temp 0\ngoto LOOP"));
+    lines.push(String::from("label END\nlabel HALT\ngoto HALT"));
+    let source = lines.join("\n");
+    let modules = vec![decode("Sys", &parse(&lex(&source))).unwrap()];
+
+    let mut options = Options::default();
+    for pass in ["licm", "tail-calls", "outline"] {
+      pipeline::find(pass).unwrap().set(&mut options, true);
+    }
+    let compiled = pipeline::compile(&modules, options).unwrap();
+    assert!(compiled.reports.iter().any(|report| report.starts_with("licm: hoisted 7 ")),
+      "{:?}", compiled.reports);
+    let program = assembler::assemble(compiled.translator.instructions()).unwrap();
+    let last = program.variables.values().max().unwrap();
+    assert!(*last < FIRST_VARIABLE_ADDRESS + 240, "variable at {}", last);
+  }
+
+  /// A loop calling `Math.multiply` with the same arguments, and optionally
+  /// the program's own `Math.multiply`, which counts its calls.
+  fn hoisted_calls(with_math: bool) -> String {
+    let mut sources = vec![("Sys", [
+      "function Sys.init 0", "goto LOOP", "label LOOP", "push local 0", "push constant 7",
+      "call Math.multiply 2", "pop static 0", "push temp 0", "push constant 1", "add",
+      "pop temp 0", "push temp 0", "push constant 3", "lt", "if-goto LOOP",
+      "label END", "goto END",
+    ].join("\n"))];
+    if with_math {
+      sources.push(("Math", [
+        "function Math.multiply 0", "push static 0", "push constant 1", "add", "pop static 0",
+        "push constant 0", "return",
+      ].join("\n")));
+    }
+    let modules: Vec<_> = sources.iter()
+      .map(|(name, source)| decode(name, &parse(&lex(source))).unwrap())
+      .collect();
+    let mut options = Options::default();
+    pipeline::find("licm").unwrap().set(&mut options, true);
+    let compiled = pipeline::compile(&modules, options).unwrap();
+    compiled.reports.into_iter().find(|report| report.starts_with("licm")).unwrap()
+  }
+
+  #[test]
+  fn hoists_os_calls_only_if_the_program_defines_none() {
+    let report = hoisted_calls(false);
+    assert!(report.starts_with("licm: hoisted 1 expressions (1 with calls)"), "{}", report);
+    assert!(hoisted_calls(true).starts_with("licm: hoisted 0 "), "{}", hoisted_calls(true));
+  }
+}
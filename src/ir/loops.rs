@@ -0,0 +1,103 @@
+use std::collections::BTreeSet;
+
+use super::{analysis, Function, Terminator};
+
+/// A natural loop: the blocks that can reach a back edge to `header`
+/// without passing through it.
+#[derive(Debug)]
+pub struct Loop {
+  pub header: usize,
+  pub blocks: BTreeSet<usize>,
+}
+
+impl Loop {
+  pub fn contains(&self, block: usize) -> bool {
+    self.blocks.contains(&block)
+  }
+}
+
+/// For every block, whether each other block dominates it, i.e. every path
+/// from the entry to it passes through that block. Unreachable blocks are
+/// dominated by nothing.
+pub fn dominators(function: &Function, reachable: &[bool]) -> Vec<Vec<bool>> {
+  let count = function.blocks.len();
+  let mut dominators: Vec<Vec<bool>> = (0..count)
+    .map(|block| vec![reachable[block] && block != 0; count])
+    .collect();
+  if count > 0 {
+    dominators[0][0] = true;
+  }
+  let predecessors = predecessors(function);
+  loop {
+    let mut changed = false;
+    for block in (1..count).filter(|block| reachable[*block]) {
+      let mut dominated: Vec<bool> = vec![true; count];
+      for predecessor in predecessors[block].iter().filter(|predecessor| reachable[**predecessor]) {
+        for (other, dominates) in dominated.iter_mut().enumerate() {
+          *dominates &= dominators[*predecessor][other];
+        }
+      }
+      dominated[block] = true;
+      if dominated != dominators[block] {
+        dominators[block] = dominated;
+        changed = true;
+      }
+    }
+    if !changed {
+      return dominators;
+    }
+  }
+}
+
+pub fn predecessors(function: &Function) -> Vec<Vec<usize>> {
+  let mut predecessors = vec![Vec::new(); function.blocks.len()];
+  for block in 0..function.blocks.len() {
+    for successor in function.successors(block) {
+      predecessors[successor].push(block);
+    }
+  }
+  predecessors
+}
+
+/// Every loop in the function, innermost first. Loops sharing a header are
+/// merged; jumps into the middle of a loop don't make one.
+pub fn find(function: &Function) -> Vec<Loop> {
+  let reachable = analysis::reachable(function);
+  let dominators = dominators(function, &reachable);
+  let predecessors = predecessors(function);
+  let mut loops: Vec<Loop> = Vec::new();
+  for block in (0..function.blocks.len()).filter(|block| reachable[*block]) {
+    for header in function.successors(block) {
+      if !dominators[block][header] {
+        continue;
+      }
+      let mut blocks = BTreeSet::from([header]);
+      let mut work = vec![block];
+      while let Some(member) = work.pop() {
+        if blocks.insert(member) {
+          work.extend(predecessors[member].iter().filter(|predecessor| reachable[**predecessor]));
+        }
+      }
+      match loops.iter_mut().find(|known| known.header == header) {
+        Some(known) => known.blocks.extend(blocks),
+        None => loops.push(Loop { header, blocks }),
+      }
+    }
+  }
+  loops.sort_by_key(|found| found.blocks.len());
+  loops
+}
+
+/// Whether the block before the loop's header is in the loop and falls into
+/// it, which leaves no room for code that only runs on entering the loop.
+pub fn falls_into_header(function: &Function, found: &Loop) -> bool {
+  let previous = match found.header.checked_sub(1) {
+    Some(previous) => previous,
+    None => return false,
+  };
+  found.contains(previous)
+    && matches!(
+      function.blocks[previous].terminator.0,
+      Terminator::FallThrough | Terminator::IfGoto(_, _),
+    )
+}
@@ -0,0 +1,595 @@
+pub mod analysis;
+pub mod codegen;
+pub mod licm;
+pub mod loops;
+pub mod transform;
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use super::code_gen::segment::Segment;
+use super::instruction::{Instruction, Located, Module, Operation};
+use super::passes::spans;
+use super::translator::Options;
+
+pub type ValueId = usize;
+
+/// What a value is computed from. A value is defined once and used at most
+/// once, and its operands are always the values pushed right before it, so
+/// lowering each definition in order rebuilds the original stack.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+  /// The n-th value from the bottom of the stack on entry to a block, which
+  /// every block leading there leaves on the stack.
+  Param(usize),
+  Constant(i16),
+  /// The contents of memory where the value is defined.
+  Load(Segment, i16),
+  Unary(Operation, ValueId),
+  Binary(Operation, ValueId, ValueId),
+  Call(String, Vec<ValueId>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Inst {
+  /// Compute the value and push it.
+  Def(ValueId),
+  Store(Segment, i16, ValueId),
+  /// A store that was removed because nothing reads what it wrote. Memory
+  /// differs from the original program until the next store there.
+  DeadStore(Segment, i16),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Terminator {
+  /// Carry on with the next block, or off the end of the function.
+  FallThrough,
+  Goto(String),
+  /// Jump if the value isn't zero, otherwise carry on with the next block.
+  IfGoto(ValueId, String),
+  /// Return the value, discarding anything else on the stack.
+  Return(ValueId),
+}
+
+/// Where an instruction came from.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Position {
+  pub line: usize,
+  pub column: usize,
+  /// The command's index in the function, counting from its header.
+  pub index: usize,
+  /// Whether the stack was empty before the original command, so that the
+  /// lowered code may be compared with the original there.
+  pub statement: bool,
+}
+
+impl Position {
+  const NONE: Position = Position { line: 0, column: 0, index: 0, statement: false };
+}
+
+#[derive(Debug, Clone)]
+pub struct Block {
+  pub label: Option<(String, Position)>,
+  /// The values on the stack on entry, bottom first.
+  pub params: Vec<ValueId>,
+  pub insts: Vec<(Inst, Position)>,
+  pub terminator: (Terminator, Position),
+}
+
+impl Block {
+  fn new(label: Option<(String, Position)>) -> Block {
+    Block {
+      label,
+      params: Vec::new(),
+      insts: Vec::new(),
+      terminator: (Terminator::FallThrough, Position::NONE),
+    }
+  }
+}
+
+/// A function lifted out of stack code: straight-line blocks of
+/// instructions on values. Values a block leaves on the stack become the
+/// parameters of the blocks it leads to.
+#[derive(Debug, Clone)]
+pub struct Function {
+  /// The `function` command.
+  pub header: Located,
+  pub num_locals: i16,
+  pub values: Vec<Value>,
+  pub blocks: Vec<Block>,
+}
+
+impl Function {
+  fn define(&mut self, value: Value) -> ValueId {
+    self.values.push(value);
+    self.values.len() - 1
+  }
+
+  pub fn block_labelled(&self, label: &str) -> Option<usize> {
+    self.blocks.iter().position(|block| matches!(&block.label, Some((name, _)) if name == label))
+  }
+
+  /// The blocks control can pass to from `block`. A block that falls off
+  /// the end of the function has none, but isn't an exit either.
+  pub fn successors(&self, block: usize) -> Vec<usize> {
+    let next = Some(block + 1).filter(|next| *next < self.blocks.len());
+    let target = |label: &str| self.block_labelled(label).unwrap();
+    match &self.blocks[block].terminator.0 {
+      Terminator::FallThrough => next.into_iter().collect(),
+      Terminator::Goto(label) => vec![target(label)],
+      Terminator::IfGoto(_, label) => std::iter::once(target(label)).chain(next).collect(),
+      Terminator::Return(_) => Vec::new(),
+    }
+  }
+
+  pub fn falls_off(&self, block: usize) -> bool {
+    block + 1 == self.blocks.len() && self.blocks[block].terminator.0 == Terminator::FallThrough
+  }
+
+  /// Whether the block is an empty loop that a program ends with, after
+  /// which its memory may be inspected.
+  pub fn halts(&self, block: usize) -> bool {
+    let block = &self.blocks[block];
+    match (&block.label, &block.terminator.0) {
+      (Some((label, _)), Terminator::Goto(target)) => block.insts.is_empty() && label == target,
+      _ => false,
+    }
+  }
+
+  /// Whether computing the value has no effect besides pushing it, and
+  /// doesn't take anything from the block's parameters, so that removing it
+  /// leaves the stack as it was.
+  pub fn is_pure(&self, value: ValueId) -> bool {
+    match &self.values[value] {
+      Value::Constant(_) | Value::Load(_, _) => true,
+      Value::Unary(_, operand) => self.is_pure(*operand),
+      Value::Binary(_, x, y) => self.is_pure(*x) && self.is_pure(*y),
+      Value::Param(_) | Value::Call(_, _) => false,
+    }
+  }
+
+  /// Whether any block takes parameters.
+  pub fn has_params(&self) -> bool {
+    self.blocks.iter().any(|block| !block.params.is_empty())
+  }
+
+  /// The value and everything it's computed from.
+  pub fn tree(&self, value: ValueId) -> Vec<ValueId> {
+    let mut tree = vec![value];
+    match &self.values[value] {
+      Value::Param(_) | Value::Constant(_) | Value::Load(_, _) => (),
+      Value::Unary(_, operand) => tree.extend(self.tree(*operand)),
+      Value::Binary(_, x, y) => {
+        tree.extend(self.tree(*x));
+        tree.extend(self.tree(*y));
+      },
+      Value::Call(_, args) => tree.extend(args.iter().flat_map(|arg| self.tree(*arg))),
+    }
+    tree
+  }
+
+  /// Replace the value with one that needs nothing else on the stack,
+  /// removing what it was computed from. If that came right before it, the
+  /// new value takes the position of the first of it.
+  pub fn replace(&mut self, value: ValueId, replacement: Value) {
+    let tree = self.tree(value);
+    for block in &mut self.blocks {
+      let index = match block.insts.iter().position(|(inst, _)| *inst == Inst::Def(value)) {
+        Some(index) => index,
+        None => continue,
+      };
+      if let Some(start) = index.checked_sub(tree.len() - 1) {
+        let adjacent = block.insts[start..index].iter().all(|(inst, _)| {
+          matches!(inst, Inst::Def(operand) if tree.contains(operand))
+        });
+        if adjacent {
+          block.insts[index].1 = block.insts[start].1;
+        }
+      }
+      break;
+    }
+    self.values[value] = replacement;
+    self.remove(&tree[1..]);
+  }
+
+  /// Remove the definitions of `values`.
+  pub fn remove(&mut self, values: &[ValueId]) {
+    for block in &mut self.blocks {
+      block.insts.retain(|(inst, _)| !matches!(inst, Inst::Def(value) if values.contains(value)));
+    }
+  }
+}
+
+/// How many values are on the stack on entry to each label that control can
+/// reach, or `None` if the ways in disagree.
+fn entry_depths(instructions: &[Located]) -> Option<HashMap<String, usize>> {
+  let mut depths: HashMap<String, usize> = HashMap::new();
+  // a jump back to a label gives its depth only on the next pass
+  loop {
+    let known = depths.len();
+    // `None` where control doesn't fall in and the depth isn't known yet
+    let mut depth = Some(0);
+    for located in instructions.iter().skip(1) {
+      let (pops, pushes) = match &located.instruction {
+        Instruction::Label(label) => {
+          depth = match (depth, depths.get(label)) {
+            (Some(depth), Some(known)) if depth != *known => return None,
+            (Some(depth), _) => Some(depth),
+            (None, known) => known.copied(),
+          };
+          if let Some(depth) = depth {
+            depths.insert(label.clone(), depth);
+          }
+          continue;
+        },
+        Instruction::Goto(label) | Instruction::IfGoto(label) => {
+          let pops = matches!(located.instruction, Instruction::IfGoto(_)) as usize;
+          if let Some(before) = depth {
+            let after = before.checked_sub(pops)?;
+            if *depths.entry(label.clone()).or_insert(after) != after {
+              return None;
+            }
+            depth = Some(after);
+          }
+          if let Instruction::Goto(_) = located.instruction {
+            depth = None;
+          }
+          continue;
+        },
+        Instruction::Return => {
+          depth = None;
+          continue;
+        },
+        Instruction::Push(_, _) => (0, 1),
+        Instruction::Pop(_, _) => (1, 0),
+        Instruction::Arithmetic(op) if op.is_unary() => (1, 1),
+        Instruction::Arithmetic(_) => (2, 1),
+        Instruction::Call(_, num_args) => (*num_args as usize, 1),
+        Instruction::Function(_, _) => return None,
+      };
+      if let Some(before) = depth {
+        depth = Some(before.checked_sub(pops)? + pushes);
+      }
+    }
+    if depths.len() == known {
+      return Some(depths);
+    }
+  }
+}
+
+/// Lift a function, from its `function` command up to the next one.
+/// Returns `None` if the stack differs between the ways into a label, the
+/// function pops more than it pushes, or it jumps to a missing label.
+pub fn lift(instructions: &[Located]) -> Option<Function> {
+  let header = instructions.first()?.clone();
+  let num_locals = match header.instruction {
+    Instruction::Function(_, num_locals) => num_locals,
+    _ => return None,
+  };
+  let depths = entry_depths(instructions)?;
+  let mut function = Function { header, num_locals, values: Vec::new(), blocks: Vec::new() };
+  let mut block = Block::new(None);
+  let mut stack: Vec<ValueId> = Vec::new();
+  // whether control can carry on into the next command
+  let mut flows = true;
+
+  for (index, located) in instructions.iter().enumerate().skip(1) {
+    let position = Position {
+      line: located.line,
+      column: located.column,
+      index,
+      statement: stack.is_empty(),
+    };
+    let value = match &located.instruction {
+      Instruction::Push(Segment::Constant, value) => Value::Constant(*value),
+      Instruction::Push(segment, index) => Value::Load(segment.clone(), *index),
+      Instruction::Pop(segment, index) => {
+        let value = stack.pop()?;
+        block.insts.push((Inst::Store(segment.clone(), *index, value), position));
+        continue;
+      },
+      Instruction::Arithmetic(op) if op.is_unary() => Value::Unary(*op, stack.pop()?),
+      Instruction::Arithmetic(op) => {
+        let y = stack.pop()?;
+        Value::Binary(*op, stack.pop()?, y)
+      },
+      Instruction::Call(name, num_args) => {
+        let args = stack.split_off(stack.len().checked_sub(*num_args as usize)?);
+        Value::Call(name.clone(), args)
+      },
+      Instruction::Label(label) => {
+        let depth = depths.get(label).copied().unwrap_or(0);
+        if flows && stack.len() != depth {
+          return None;
+        }
+        // an empty block without a label can only be the entry, which the
+        // label can start instead, or after a jump
+        if block.label.is_some() || !block.insts.is_empty() {
+          function.blocks.push(block);
+        }
+        block = Block::new(Some((label.clone(), position)));
+        stack = (0..depth).map(|n| function.define(Value::Param(n))).collect();
+        block.params = stack.clone();
+        flows = true;
+        continue;
+      },
+      Instruction::Goto(_) | Instruction::IfGoto(_) | Instruction::Return => {
+        let terminator = match &located.instruction {
+          Instruction::Goto(label) => Terminator::Goto(label.clone()),
+          Instruction::IfGoto(label) => Terminator::IfGoto(stack.pop()?, label.clone()),
+          _ => {
+            let value = stack.pop()?;
+            stack.clear();
+            Terminator::Return(value)
+          },
+        };
+        if let Terminator::Goto(label) | Terminator::IfGoto(_, label) = &terminator {
+          if depths.get(label).copied().unwrap_or(0) != stack.len() {
+            return None;
+          }
+        }
+        flows = matches!(terminator, Terminator::IfGoto(_, _));
+        block.terminator = (terminator, position);
+        function.blocks.push(std::mem::replace(&mut block, Block::new(None)));
+        // what stays on the stack is passed on to the next block
+        stack = match flows {
+          true => stack.iter().enumerate().map(|(n, _)| function.define(Value::Param(n))).collect(),
+          false => Vec::new(),
+        };
+        block.params = stack.clone();
+        continue;
+      },
+      Instruction::Function(_, _) => return None,
+    };
+    let value = function.define(value);
+    block.insts.push((Inst::Def(value), position));
+    stack.push(value);
+    // even code after a jump, which only matters if it runs into a label
+    flows = true;
+  }
+  if !stack.is_empty() {
+    return None;
+  }
+  let falls_in = function.blocks.last().is_none_or(|last| {
+    matches!(last.terminator.0, Terminator::FallThrough | Terminator::IfGoto(_, _))
+  });
+  if block.label.is_some() || !block.insts.is_empty() || falls_in {
+    function.blocks.push(block);
+  }
+
+  let mut labels = HashSet::new();
+  for block in &function.blocks {
+    if let Some((label, _)) = &block.label {
+      if !labels.insert(label.as_str()) {
+        return None;
+      }
+    }
+  }
+  for block in &function.blocks {
+    if let Terminator::Goto(label) | Terminator::IfGoto(_, label) = &block.terminator.0 {
+      if !labels.contains(label.as_str()) {
+        return None;
+      }
+    }
+  }
+  Some(function)
+}
+
+/// Lower a function back to stack code. Unreachable blocks are left out.
+/// Instructions keep the position of their original command only where the
+/// stack and memory are known to match the original program.
+pub fn lower(function: &Function) -> Vec<Located> {
+  let reachable = analysis::reachable(function);
+  let stale = analysis::stale(function, &reachable);
+
+  let mut output = vec![function.header.clone()];
+  let mut origins = vec![Position { line: function.header.line, ..Position::NONE }];
+  let mut emit = |instruction: Instruction, position: Position, stale: &HashSet<analysis::Var>| {
+    let (line, column) = match position.statement && stale.is_empty() {
+      true => (position.line, position.column),
+      false => (0, 0),
+    };
+    output.push(Located::new(instruction, line, column));
+    origins.push(position);
+  };
+  for (index, block) in function.blocks.iter().enumerate().filter(|(index, _)| reachable[*index]) {
+    let mut stale = stale[index].clone();
+    if let Some((label, position)) = &block.label {
+      emit(Instruction::Label(label.clone()), *position, &stale);
+    }
+    for (inst, position) in &block.insts {
+      match inst {
+        Inst::Def(value) => emit(match &function.values[*value] {
+          Value::Param(_) => unreachable!("parameters are on the stack already"),
+          Value::Constant(value) => Instruction::Push(Segment::Constant, *value),
+          Value::Load(segment, index) => Instruction::Push(segment.clone(), *index),
+          Value::Unary(op, _) | Value::Binary(op, _, _) => Instruction::Arithmetic(*op),
+          Value::Call(name, args) => Instruction::Call(name.clone(), args.len() as i16),
+        }, *position, &stale),
+        Inst::Store(segment, index, _) => {
+          stale.remove(&(segment.clone(), *index));
+          emit(Instruction::Pop(segment.clone(), *index), *position, &stale);
+        },
+        Inst::DeadStore(segment, index) => {
+          stale.insert((segment.clone(), *index));
+        },
+      }
+    }
+    let (terminator, position) = &block.terminator;
+    match terminator {
+      Terminator::FallThrough => (),
+      Terminator::Goto(label) => emit(Instruction::Goto(label.clone()), *position, &stale),
+      Terminator::IfGoto(_, label) => emit(Instruction::IfGoto(label.clone()), *position, &stale),
+      Terminator::Return(_) => emit(Instruction::Return, *position, &stale),
+    }
+  }
+
+  let no_code = |located: &Located| match located.instruction {
+    Instruction::Label(_) => true,
+    Instruction::Function(_, num_locals) => num_locals == 0,
+    _ => false,
+  };
+  loop {
+    let mut dropped: HashSet<(usize, usize)> = HashSet::new();
+    // a command without code shares its address, and so its checkpoint, with
+    // the next one. That only works if no command between them was removed,
+    // or if the next one is new code, which has no checkpoint of its own.
+    for next in (1..output.len()).rev() {
+      let keep = match output[next].line {
+        0 => !no_code(&output[next]),
+        _ => origins[next].index == origins[next - 1].index + 1,
+      };
+      if no_code(&output[next - 1]) && !keep && output[next - 1].line != 0 {
+        output[next - 1].line = 0;
+        dropped.insert((origins[next - 1].line, origins[next - 1].column));
+      }
+    }
+    // the VM program can only be compared with a command that was copied
+    // if every copy has a checkpoint
+    for (located, origin) in output.iter().zip(&origins) {
+      if located.line == 0 && origin.line != 0 {
+        dropped.insert((origin.line, origin.column));
+      }
+    }
+    let mut changed = false;
+    for (located, origin) in output.iter_mut().zip(&origins) {
+      if located.line != 0 && dropped.contains(&(origin.line, origin.column)) {
+        located.line = 0;
+        changed = true;
+      }
+    }
+    if !changed {
+      break;
+    }
+  }
+  for located in &mut output {
+    if located.line == 0 {
+      located.column = 0;
+    }
+  }
+  output
+}
+
+/// What the IR optimisations did, over all functions.
+#[derive(Debug, Default)]
+pub struct Stats {
+  pub functions: usize,
+  pub lifted: usize,
+  pub constants: usize,
+  pub copies: usize,
+  pub folded: usize,
+  pub branches: usize,
+  pub dead_stores: usize,
+  pub redundant_stores: usize,
+}
+
+impl fmt::Display for Stats {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(
+      f,
+      "propagation: lifted {} of {} functions; propagated {} constants and {} copies, \
+       folded {} operations and {} branches, removed {} dead and {} redundant stores",
+      self.lifted, self.functions, self.constants, self.copies,
+      self.folded, self.branches, self.dead_stores, self.redundant_stores,
+    )
+  }
+}
+
+/// Lift every function into the IR, apply the optimisations enabled in
+/// `options` there and lower it back, returning a summary of what each of
+/// them did. Functions that can't be lifted are left as they are.
+pub fn optimise(modules: &mut [Module], options: Options) -> Vec<String> {
+  let mut stats = Stats::default();
+  let mut loop_stats = licm::Stats::default();
+  let mut program = licm::Program::new(modules);
+  for module in modules {
+    let spans = spans(module);
+    let first = spans.first().map_or(module.instructions.len(), |span| span.start);
+    let mut instructions = module.instructions[..first].to_vec();
+    let mut lowered: HashMap<usize, Vec<Located>> = HashMap::new();
+    for span in &spans {
+      stats.functions += 1;
+      if let Some(mut function) = lift(&module.instructions[span.start..span.end]) {
+        stats.lifted += 1;
+        if options.propagate {
+          transform::optimise(&mut function, &mut stats);
+        }
+        if options.licm {
+          licm::optimise(&mut function, &mut program, &mut loop_stats);
+        }
+        lowered.insert(span.start, lower(&function));
+      }
+    }
+    for span in &spans {
+      match lowered.remove(&span.start) {
+        Some(function) => instructions.extend(function),
+        None => instructions.extend_from_slice(&module.instructions[span.start..span.end]),
+      }
+    }
+    module.instructions = instructions;
+  }
+  let mut reports = Vec::new();
+  if options.propagate {
+    reports.push(stats.to_string());
+  }
+  if options.licm {
+    reports.push(loop_stats.to_string());
+  }
+  reports
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use super::super::instruction::decode;
+  use super::super::lexer::lex;
+  use super::super::parser::parse;
+  use super::super::translator::Options;
+
+  fn module(lines: &[&str]) -> Module {
+    decode("Sys", &parse(&lex(&lines.join("\n")))).unwrap()
+  }
+
+  fn commands(module: &Module) -> Vec<Instruction> {
+    module.instructions.iter().map(|located| located.instruction.clone()).collect()
+  }
+
+  #[test]
+  fn passes_values_across_blocks_as_parameters() {
+    let module = module(&[
+      "function Sys.init 2", "push constant 10", "push local 0", "if-goto A",
+      "push constant 1", "goto B", "label A", "push constant 2", "label B", "add",
+      "pop local 1", "label END", "goto END",
+    ]);
+    let function = lift(&module.instructions).unwrap();
+    let params: Vec<usize> = function.blocks.iter().map(|block| block.params.len()).collect();
+    assert_eq!(params, [0, 1, 1, 2, 0]);
+    let lowered: Vec<Instruction> =
+      lower(&function).into_iter().map(|located| located.instruction).collect();
+    assert_eq!(lowered, commands(&module));
+  }
+
+  #[test]
+  fn rejects_stacks_that_differ_between_ways_in() {
+    let module = module(&[
+      "function Sys.init 0", "push constant 1", "if-goto A", "push constant 2", "label A",
+      "label END", "goto END",
+    ]);
+    assert!(lift(&module.instructions).is_none());
+  }
+
+  /// Storing through `that` may change a local, so the local's constant
+  /// mustn't be propagated past it, nor its store removed.
+  #[test]
+  fn that_writes_change_locals() {
+    let mut modules = vec![module(&[
+      "function Sys.init 1", "push constant 5", "pop local 0", "push constant 261",
+      "pop pointer 1", "push constant 9", "pop that 0", "push local 0", "pop static 0",
+      "push that 0", "pop static 1", "label END", "goto END",
+    ])];
+    let original = commands(&modules[0]);
+    let options = Options { propagate: true, ..Options::default() };
+    optimise(&mut modules, options);
+    assert_eq!(commands(&modules[0]), original);
+  }
+}
@@ -0,0 +1,178 @@
+use super::analysis::{self, Fact};
+use super::{Function, Inst, Stats, Terminator, Value, ValueId};
+
+/// Optimise the function until nothing changes.
+pub fn optimise(function: &mut Function, stats: &mut Stats) {
+  loop {
+    let changed = propagate(function, stats)
+      | fold(function, stats)
+      | branches(function, stats)
+      | dead_stores(function, stats);
+    if !changed {
+      return;
+    }
+  }
+}
+
+/// Replace loads of variables known to hold a constant or another
+/// variable's value, and remove stores of what a variable already holds.
+fn propagate(function: &mut Function, stats: &mut Stats) -> bool {
+  let facts_in = analysis::facts(function);
+  let mut replaced: Vec<(ValueId, Value)> = Vec::new();
+  let mut redundant: Vec<(usize, usize)> = Vec::new();
+  for (block, facts) in facts_in.into_iter().enumerate() {
+    let mut facts = match facts {
+      Some(facts) => facts,
+      None => continue,
+    };
+    for index in 0..function.blocks[block].insts.len() {
+      match &function.blocks[block].insts[index].0 {
+        Inst::Def(value) => {
+          if let Value::Load(segment, offset) = &function.values[*value] {
+            match facts.get(&(segment.clone(), *offset)) {
+              Some(Fact::Constant(constant)) => {
+                stats.constants += 1;
+                replaced.push((*value, Value::Constant(*constant)));
+              },
+              Some(Fact::Copy((segment, offset))) => {
+                stats.copies += 1;
+                replaced.push((*value, Value::Load(segment.clone(), *offset)));
+              },
+              None => (),
+            }
+          }
+        },
+        Inst::Store(segment, offset, value) => {
+          let var = (segment.clone(), *offset);
+          let holds = match (&function.values[*value], facts.get(&var)) {
+            (Value::Constant(constant), Some(Fact::Constant(known))) => constant == known,
+            (Value::Load(segment, offset), fact) => {
+              let source = (segment.clone(), *offset);
+              source == var || fact == Some(&Fact::Copy(source))
+            },
+            _ => false,
+          };
+          // a load must come right before the store to read the same value
+          let insts = &function.blocks[block].insts;
+          let adjacent = index > 0 && insts[index - 1].0 == Inst::Def(*value);
+          if holds && (adjacent || matches!(function.values[*value], Value::Constant(_))) {
+            redundant.push((block, index));
+          }
+        },
+        Inst::DeadStore(_, _) => (),
+      }
+      analysis::transfer(function, block, index, &mut facts);
+    }
+  }
+
+  let changed = !replaced.is_empty() || !redundant.is_empty();
+  for (value, replacement) in replaced {
+    function.values[value] = replacement;
+  }
+  let mut removed = Vec::new();
+  for (block, index) in redundant.into_iter().rev() {
+    if let (Inst::Store(_, _, value), _) = function.blocks[block].insts.remove(index) {
+      stats.redundant_stores += 1;
+      removed.push(value);
+    }
+  }
+  function.remove(&removed);
+  changed
+}
+
+fn constant(function: &Function, value: ValueId) -> Option<i16> {
+  match function.values[value] {
+    Value::Constant(constant) => Some(constant),
+    _ => None,
+  }
+}
+
+/// Evaluate operations on constants. Like `passes::fold`, a folded value
+/// takes the position of the first operand it replaces, if they're adjacent.
+fn fold(function: &mut Function, stats: &mut Stats) -> bool {
+  let defined: Vec<ValueId> = function.blocks.iter()
+    .flat_map(|block| &block.insts)
+    .filter_map(|(inst, _)| match inst {
+      Inst::Def(value) => Some(*value),
+      _ => None,
+    })
+    .collect();
+  let mut changed = false;
+  for value in defined {
+    let folded = match function.values[value] {
+      Value::Unary(op, y) => match constant(function, y) {
+        Some(y) => op.apply(0, y),
+        None => continue,
+      },
+      Value::Binary(op, x, y) => match (constant(function, x), constant(function, y)) {
+        (Some(x), Some(y)) => op.apply(x, y),
+        _ => continue,
+      },
+      _ => continue,
+    };
+    stats.folded += 1;
+    function.replace(value, Value::Constant(folded));
+    changed = true;
+  }
+  changed
+}
+
+/// Resolve conditional jumps on constants. The jump takes the position of
+/// the condition if it's defined right before.
+fn branches(function: &mut Function, stats: &mut Stats) -> bool {
+  let mut conditions = Vec::new();
+  for index in 0..function.blocks.len() {
+    let block = &function.blocks[index];
+    let (condition, label) = match &block.terminator.0 {
+      Terminator::IfGoto(condition, label) => (*condition, label.clone()),
+      _ => continue,
+    };
+    let taken = match function.values[condition] {
+      Value::Constant(constant) => constant != 0,
+      _ => continue,
+    };
+    let position = match block.insts.last() {
+      Some((inst, position)) if *inst == Inst::Def(condition) => *position,
+      _ => block.terminator.1,
+    };
+    stats.branches += 1;
+    function.blocks[index].terminator = match taken {
+      true => (Terminator::Goto(label), position),
+      false => (Terminator::FallThrough, position),
+    };
+    conditions.push(condition);
+  }
+  function.remove(&conditions);
+  !conditions.is_empty()
+}
+
+/// Remove stores to locals and temps that nothing reads before they're
+/// overwritten or the function returns, along with the values stored.
+fn dead_stores(function: &mut Function, stats: &mut Stats) -> bool {
+  let (live_out, locals) = analysis::liveness(function);
+  let mut dead = Vec::new();
+  for (block, live_out) in live_out.into_iter().enumerate() {
+    let mut live = live_out;
+    for index in (0..function.blocks[block].insts.len()).rev() {
+      if let Inst::Store(segment, offset, value) = &function.blocks[block].insts[index].0 {
+        let var = (segment.clone(), *offset);
+        if analysis::is_local(&var) && !live.contains(&var) && function.is_pure(*value) {
+          dead.push((block, index));
+        }
+      }
+      analysis::transfer_back(function, block, index, &mut live, &locals);
+    }
+  }
+
+  let mut removed = Vec::new();
+  for &(block, index) in &dead {
+    let inst = &mut function.blocks[block].insts[index].0;
+    if let Inst::Store(segment, offset, value) = inst.clone() {
+      stats.dead_stores += 1;
+      *inst = Inst::DeadStore(segment, offset);
+      removed.extend(function.tree(value));
+    }
+  }
+  function.remove(&removed);
+  !dead.is_empty()
+}
@@ -9,7 +9,7 @@ enum State {
   InComment,
 }
 
-pub fn lex<'a>(source: &'a String) -> Vec<Token<'a>> {
+pub fn lex<'a>(source: &'a str) -> Vec<Token<'a>> {
   let mut tokens: Vec<Token<'a>> = Vec::new();
   let mut line = 1;
   let mut column = 0;
@@ -39,7 +39,7 @@ pub fn lex<'a>(source: &'a String) -> Vec<Token<'a>> {
           '\n' => {
             tokens.push(Token::new(
               TokenType::Newline,
-              &"\n",
+              "\n",
               current_line,
               current_column,
             ));
@@ -48,8 +48,8 @@ pub fn lex<'a>(source: &'a String) -> Vec<Token<'a>> {
             do_column = false;
             State::None
           }
-          '0'...'9' => State::InNumber,
-          'a'...'z' | 'A'...'Z' | '_' | '-' | ':' | '.' => State::InIdentifier,
+          '0'..='9' => State::InNumber,
+          'a'..='z' | 'A'..='Z' | '_' | '-' | ':' | '.' => State::InIdentifier,
           '\0' => break,
           '/' if current == '/' => {
             State::InComment
@@ -65,7 +65,7 @@ pub fn lex<'a>(source: &'a String) -> Vec<Token<'a>> {
             column = 0;
             tokens.push(Token::new(
               TokenType::Newline,
-              &"\n",
+              "\n",
               current_line,
               current_column,
             ));
@@ -78,7 +78,7 @@ pub fn lex<'a>(source: &'a String) -> Vec<Token<'a>> {
       State::InIdentifier => {
         lexeme.push(current);
         match next {
-          'a'...'z' | 'A'...'Z' | '0'...'9'
+          'a'..='z' | 'A'..='Z' | '0'..='9'
           | '_' | '-' | ':' | '.' => State::InIdentifier,
           _ => {
             tokens.push(Token::new(
@@ -92,7 +92,7 @@ pub fn lex<'a>(source: &'a String) -> Vec<Token<'a>> {
               column = 0;
               tokens.push(Token::new(
                 TokenType::Newline,
-                &"\n",
+                "\n",
                 current_line,
                 current_column,
               ));
@@ -105,7 +105,7 @@ pub fn lex<'a>(source: &'a String) -> Vec<Token<'a>> {
       State::InNumber => {
         lexeme.push(current);
         match next {
-          '0'...'9' => State::InNumber,
+          '0'..='9' => State::InNumber,
           _ => {
             tokens.push(Token::new(
               TokenType::Integer,
@@ -113,14 +113,14 @@ pub fn lex<'a>(source: &'a String) -> Vec<Token<'a>> {
               current_line,
               current_column,
             ).with_value(
-              Value::Integer(i16::from_str_radix(&lexeme, 10).unwrap()),
+              Value::Integer(lexeme.parse::<i16>().unwrap()),
             ));
             if *next == '\n' {
               line += 1;
               column = 0;
               tokens.push(Token::new(
                 TokenType::Newline,
-                &"\n",
+                "\n",
                 current_line,
                 current_column,
               ));
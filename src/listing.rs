@@ -0,0 +1,101 @@
+use std::collections::HashMap;
+use std::io::{Result, Write};
+
+use super::hack::asm::AsmInstr;
+use super::instruction::Module;
+use super::rom::{attribute, commands};
+use super::translator::CHECKPOINT_PREFIX;
+
+/// Write `instructions`, translated from `modules` with checkpoints and
+/// assembled into `rom`, with the source line of each command from
+/// `sources` (by module name) before its code, and the ROM address and
+/// encoding of every instruction. Commands made up by a pass show the
+/// command instead, and the translator's comments are left out.
+pub fn write(
+  instructions: &[AsmInstr],
+  rom: &[u16],
+  modules: &[Module],
+  sources: &HashMap<String, String>,
+  stream: &mut dyn Write,
+) -> Result<()> {
+  let commands = commands(modules);
+  let mut address = 0;
+  let mut current = None;
+  for (instruction, owner) in instructions.iter().zip(attribute(instructions, &commands)) {
+    match instruction {
+      AsmInstr::Label(label) if label.starts_with(CHECKPOINT_PREFIX) => continue,
+      AsmInstr::Comment(_) => continue,
+      _ => (),
+    }
+    if current != Some(owner) {
+      if current.is_some() {
+        writeln!(stream)?;
+      }
+      current = Some(owner);
+      match owner.map(|number| &commands[number]) {
+        None => writeln!(stream, "// bootstrap and shared code")?,
+        Some(command) if command.located.line == 0 => writeln!(
+          stream, "// {}.vm: {} (added by a pass)", command.module, command.located.instruction,
+        )?,
+        Some(command) => {
+          let line = sources.get(command.module)
+            .and_then(|source| source.lines().nth(command.located.line - 1))
+            .unwrap_or("");
+          writeln!(stream, "// {}.vm:{}: {}", command.module, command.located.line, line.trim())?;
+        },
+      }
+    }
+    match instruction {
+      AsmInstr::Label(_) => writeln!(stream, "{:23}{}", "", instruction)?,
+      _ => {
+        writeln!(stream, "{:>5}  {:016b}{}", address, rom[address], instruction)?;
+        address += 1;
+      },
+    }
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use super::super::hack::assembler::assemble;
+  use super::super::instruction::decode;
+  use super::super::lexer::lex;
+  use super::super::parser::parse;
+  use super::super::pipeline;
+  use super::super::translator::Options;
+
+  #[test]
+  fn interleaves_source_lines_with_code() {
+    let source = "function Sys.init 0\n  push constant 1 // one\npop static 0\nlabel END\ngoto END";
+    let modules = vec![decode("Sys", &parse(&lex(source))).unwrap()];
+    let options = Options { checkpoints: true, ..Options::default() };
+    let compiled = pipeline::compile(&modules, options).unwrap();
+    let instructions = compiled.translator.instructions();
+    let rom = assemble(instructions).unwrap().rom;
+    let sources = HashMap::from([(String::from("Sys"), String::from(source))]);
+
+    let mut output = Vec::new();
+    write(instructions, &rom, &modules, &sources, &mut output).unwrap();
+    let output = String::from_utf8(output).unwrap();
+    let lines: Vec<&str> = output.lines().collect();
+    assert_eq!(lines[0], "// bootstrap and shared code");
+    let push = lines.iter().position(|line| line.starts_with("// Sys.vm:2:")).unwrap();
+    assert_eq!(lines[push], "// Sys.vm:2: push constant 1 // one");
+    let first = lines[push + 1..].iter().find(|line| !line.trim_start().starts_with('(')).unwrap();
+    assert_eq!(first.split_whitespace().last(), Some("@1"));
+    // every instruction is listed once, at its address, with its encoding
+    let code: Vec<&str> = lines.iter()
+      .filter(|line| line.trim_start().starts_with(char::is_numeric))
+      .copied()
+      .collect();
+    assert_eq!(code.len(), rom.len());
+    for (address, line) in code.iter().enumerate() {
+      let fields: Vec<&str> = line.split_whitespace().collect();
+      assert_eq!(fields[0], address.to_string());
+      assert_eq!(fields[1], format!("{:016b}", rom[address]));
+    }
+    assert!(!output.contains(CHECKPOINT_PREFIX));
+  }
+}
@@ -1,43 +1,129 @@
 #[macro_use]
 mod assembly_builder;
+mod analysis;
 mod command;
+mod differential;
+mod fuzz;
+mod hack;
+mod instruction;
+mod interpreter;
+mod ir;
 mod lexer;
+mod listing;
 mod parser;
+mod passes;
+mod pipeline;
+mod profile;
+mod rom;
+mod source_map;
 mod token;
 mod translator;
 mod code_gen;
 
+use std::collections::HashMap;
 use std::env;
 use std::fs::{File};
 use std::io;
 use std::io::prelude::*;
 use std::path::{Path, PathBuf};
+use self::hack::assembler;
+use self::instruction::{decode, Module};
 use self::lexer::lex;
 use self::parser::parse;
-use self::translator::Translator;
+
+fn usage_error(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+fn parse_number(value: &str, arg: &str) -> io::Result<u64> {
+    value.parse().map_err(|_| usage_error(format!("Invalid number in {}", arg)))
+}
+
+fn run_fuzzer(seed: u64, count: u64, options: translator::Options) -> io::Result<()> {
+    match fuzz::run(seed, count, options, differential::Limits::default()) {
+        None => {
+            println!("OK: {} random programs agreed", count);
+            Ok(())
+        },
+        Some(failure) => {
+            println!("FAILED: replay with --fuzz=1 --seed={}", failure.seed);
+            println!("{}", failure.report);
+            for (name, source) in failure.sources {
+                println!("\n// {}.vm\n{}", name, source);
+            }
+            std::process::exit(1);
+        },
+    }
+}
 
 fn main() -> io::Result<()> {
-    let args: Vec<String> = env::args().collect();
-    let pathstr = match args.get(1) {
+    let mut pathstr: Option<String> = None;
+    let mut diff_test = false;
+    let mut fuzz: Option<u64> = None;
+    let mut seed: u64 = 0;
+    let mut size_report = false;
+    let mut write_source_map = false;
+    let mut write_listing = false;
+    let mut rom_warn: Option<usize> = None;
+    let args: Vec<String> = env::args().skip(1).collect();
+    // the last preset applies first, wherever it is, and the other flags
+    // adjust it
+    let mut options = args.iter().rev()
+        .find_map(|arg| arg.strip_prefix("-O").and_then(pipeline::preset))
+        .unwrap_or_default();
+    for arg in args {
+        match arg.as_str() {
+            "--diff-test" => diff_test = true,
+            "--fuzz" => fuzz = Some(100),
+            "--size" => size_report = true,
+            "--source-map" => write_source_map = true,
+            "--listing" => write_listing = true,
+            "-O0" | "-O1" | "-O2" | "-Os" => (),
+            _ if arg.starts_with("--fuzz=") => {
+                fuzz = Some(parse_number(&arg["--fuzz=".len()..], &arg)?);
+            },
+            _ if arg.starts_with("--inline=") => {
+                let threshold = parse_number(&arg["--inline=".len()..], &arg)? as usize;
+                pipeline::find("inline").unwrap().pin(&mut options, threshold > 0);
+                options.inline_threshold = threshold;
+            },
+            _ if arg.starts_with("--pass=") => {
+                pipeline::toggle(&mut options, &arg["--pass=".len()..]).map_err(usage_error)?;
+            },
+            _ if arg.starts_with("--rom-warn=") => {
+                rom_warn = Some(parse_number(&arg["--rom-warn=".len()..], &arg)? as usize);
+            },
+            _ if arg.starts_with("--seed=") => {
+                seed = parse_number(&arg["--seed=".len()..], &arg)?;
+            },
+            _ if arg.starts_with("--") => match pipeline::find(&arg["--".len()..]) {
+                Some(pass) => pass.pin(&mut options, true),
+                None => return Err(usage_error(format!("Unknown option {}", arg))),
+            },
+            _ => pathstr = Some(arg),
+        }
+    }
+    if let Some(count) = fuzz {
+        return run_fuzzer(seed, count, options);
+    }
+
+    let pathstr = match pathstr {
         Some(s) => s,
-        None => return Err(io::Error::new(
-            io::ErrorKind::InvalidInput,
-            "Missing filename argument",
-        )),
+        None => return Err(usage_error(String::from("Missing filename argument"))),
     };
 
     // FIXME: properly handle things, not unwrap
-    let path = Path::new(pathstr);
+    let path = Path::new(&pathstr);
     let dir: &Path;
     let filename = match path.file_stem() {
         Some(name) => name,
         None => unimplemented!(),
     };
 
-    let meta = std::fs::metadata(pathstr)?;
+    let meta = std::fs::metadata(&pathstr)?;
     let paths: Vec<PathBuf> = if meta.is_file() {
         dir = path.parent().unwrap();
-        std::iter::once(PathBuf::from(pathstr)).collect()
+        std::iter::once(PathBuf::from(&pathstr)).collect()
     } else {
         dir = path;
         path.read_dir()?.filter(|child| {
@@ -56,9 +142,11 @@ fn main() -> io::Result<()> {
     // output directory is *in* the argument location if that location is a dir
     let output_file = Path::new(dir).join(Path::new(filename).with_extension("asm"));
 
-    let mut translator = Translator::new();
+    let mut modules: Vec<Module> = Vec::new();
+    // by module name, for the listing
+    let mut sources: HashMap<String, String> = HashMap::new();
     for path in &paths {
-        let mut file = File::open(&path)?;
+        let mut file = File::open(path)?;
 
         let mut contents = String::new();
         file.read_to_string(&mut contents)?;
@@ -67,14 +155,69 @@ fn main() -> io::Result<()> {
 
         let commands = parse(&tokens);
 
-        translator.translate_file(
+        let module = decode(
             path.file_stem().unwrap().to_str().unwrap(),
-            commands
-        ).unwrap();
+            &commands,
+        ).map_err(usage_error)?;
+        sources.insert(module.name.clone(), contents);
+        modules.push(module);
+    }
+
+    if diff_test {
+        let report = differential::run(&modules, options, differential::Limits::default())
+            .map_err(usage_error)?;
+        println!("{}", report);
+        if report.diverged() {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    // checkpoints show which command each instruction came from, without
+    // changing any; they're left out of the output
+    let checkpointed = translator::Options { checkpoints: true, ..options };
+    let mut compiled = pipeline::compile(&modules, checkpointed).map_err(usage_error)?;
+    for report in &compiled.reports {
+        eprintln!("{}", report);
+    }
+    if size_report {
+        for effect in pipeline::effects(&modules, options, &compiled).map_err(usage_error)? {
+            eprintln!("{}", effect);
+        }
+    }
+
+    let usage = rom::Usage::measure(compiled.translator.instructions(), &compiled.modules);
+    match rom::check(usage.total, rom_warn) {
+        Ok(warning) => {
+            if size_report {
+                eprintln!("{}", usage);
+            }
+            if let Some(warning) = warning {
+                eprintln!("{}", warning);
+            }
+        },
+        Err(message) => {
+            eprintln!("{}", usage);
+            return Err(usage_error(message));
+        },
+    }
+
+    if write_source_map {
+        let map_file = Path::new(dir).join(Path::new(filename).with_extension("map"));
+        let instructions = compiled.translator.instructions();
+        source_map::write(instructions, &compiled.modules, &mut File::create(map_file)?)?;
+    }
+    if write_listing {
+        let listing_file = Path::new(dir).join(Path::new(filename).with_extension("lst"));
+        let instructions = compiled.translator.instructions();
+        let hack = assembler::assemble(instructions).map_err(usage_error)?;
+        let mut stream = File::create(listing_file)?;
+        listing::write(instructions, &hack.rom, &compiled.modules, &sources, &mut stream)?;
     }
 
+    compiled.translator.drop_checkpoints();
     let mut output_file_stream = File::create(output_file)?;
-    translator.write(&mut output_file_stream)?;
+    compiled.translator.write(&mut output_file_stream)?;
 
     Ok(())
 }
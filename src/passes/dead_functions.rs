@@ -0,0 +1,101 @@
+use std::collections::{HashMap, HashSet};
+
+use super::super::instruction::{Instruction, Module};
+use super::{spans, Span};
+
+/// Remove every function that can't be reached from `entry` by following
+/// `call`s. Returns the name of each function removed, in the order they were
+/// defined, along with a module of its file holding only that function.
+/// Calls that intrinsics replace still count, which keeps the `Math.divide`
+/// they fall back to for dividing by 0.
+pub fn eliminate(modules: &mut [Module], entry: &str) -> Vec<(String, Module)> {
+  let spans: Vec<Vec<Span>> = modules.iter().map(spans).collect();
+
+  let mut callees: HashMap<&str, Vec<&str>> = HashMap::new();
+  for (module, spans) in modules.iter().zip(&spans) {
+    for span in spans {
+      let calls = module.instructions[span.start..span.end].iter().filter_map(|located| {
+        match &located.instruction {
+          Instruction::Call(name, _) => Some(name.as_str()),
+          _ => None,
+        }
+      });
+      callees.entry(span.name.as_str()).or_default().extend(calls);
+    }
+  }
+
+  let mut reachable: HashSet<&str> = HashSet::new();
+  let mut pending = vec![entry];
+  while let Some(name) = pending.pop() {
+    if reachable.insert(name) {
+      pending.extend(callees.get(name).into_iter().flatten().copied());
+    }
+  }
+  let reachable: HashSet<String> = reachable.into_iter().map(String::from).collect();
+
+  let mut removed = Vec::new();
+  for (module, spans) in modules.iter_mut().zip(spans) {
+    // anything before the first function isn't part of one, so it stays
+    let mut kept = match spans.first() {
+      Some(first) => module.instructions[..first.start].to_vec(),
+      None => continue,
+    };
+    for span in spans {
+      if reachable.contains(&span.name) {
+        kept.extend_from_slice(&module.instructions[span.start..span.end]);
+      } else {
+        let function = Module {
+          name: module.name.clone(),
+          instructions: module.instructions[span.start..span.end].to_vec(),
+        };
+        removed.push((span.name, function));
+      }
+    }
+    module.instructions = kept;
+  }
+  removed
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use super::super::super::instruction::decode;
+  use super::super::super::lexer::lex;
+  use super::super::super::parser::parse;
+  use super::super::super::translator::Options;
+
+  fn modules() -> Vec<Module> {
+    let sys = "function Sys.init 0\ncall Main.used 0\nlabel END\ngoto END";
+    let main = [
+      "function Main.used 0", "call Main.helper 0", "return",
+      "function Main.unused 0", "call Main.helper 0", "call Main.unused 0", "return",
+      "function Main.helper 0", "push constant 1", "return",
+    ].join("\n");
+    vec![
+      decode("Sys", &parse(&lex(sys))).unwrap(),
+      decode("Main", &parse(&lex(&main))).unwrap(),
+    ]
+  }
+
+  #[test]
+  fn removes_unreachable_functions() {
+    let mut modules = modules();
+    let removed = eliminate(&mut modules, "Sys.init");
+    assert_eq!(removed.len(), 1);
+    assert_eq!(removed[0].0, "Main.unused");
+    assert_eq!(removed[0].1.instructions.len(), 4);
+    let kept: Vec<String> = spans(&modules[1]).into_iter().map(|span| span.name).collect();
+    assert_eq!(kept, ["Main.used", "Main.helper"]);
+  }
+
+  #[test]
+  fn reports_instructions_saved() {
+    let options = Options { drop_unused_functions: true, ..Options::default() };
+    let reports = super::super::optimise(&mut modules(), options, None).unwrap();
+    assert!(reports[0].starts_with("unused functions: dropped 1 ("), "{}", reports[0]);
+    let saved: usize = reports[0]["unused functions: dropped 1 (".len()..]
+      .split(' ').next().unwrap().parse().unwrap();
+    assert!(saved > 4, "{}", reports[0]);
+    assert!(reports[0].ends_with(&format!("{:>6}  Main.unused", saved)), "{}", reports[0]);
+  }
+}
@@ -0,0 +1,104 @@
+use super::super::code_gen::segment::Segment;
+use super::super::instruction::{Instruction, Located, Operation};
+
+/// The value pushed by the instruction `back` places from the end, if it
+/// pushes a constant.
+fn constant(output: &[Located], back: usize) -> Option<i16> {
+  match output.len().checked_sub(back).map(|index| &output[index].instruction) {
+    Some(Instruction::Push(Segment::Constant, value)) => Some(*value),
+    _ => None,
+  }
+}
+
+/// Whether applying `op` with `y` on top of the stack leaves the value
+/// below it unchanged.
+fn is_identity(op: Operation, y: i16) -> bool {
+  match op {
+    Operation::Add | Operation::Sub | Operation::Or => y == 0,
+    Operation::And => y == -1,
+    _ => false,
+  }
+}
+
+/// Evaluate arithmetic on constants at translation time and remove
+/// operations that don't change their operand. Folded constants take the
+/// position of the first push they replace.
+pub fn fold(instructions: &[Located]) -> Vec<Located> {
+  let mut output: Vec<Located> = Vec::with_capacity(instructions.len());
+  for located in instructions {
+    let op = match located.instruction {
+      Instruction::Arithmetic(op) => op,
+      _ => {
+        output.push(located.clone());
+        continue;
+      },
+    };
+    let length = output.len();
+
+    if op.is_unary() {
+      if let Some(y) = constant(&output, 1) {
+        output[length - 1].instruction = Instruction::Push(Segment::Constant, op.apply(0, y));
+      } else if matches!(output.last(), Some(previous) if previous.instruction == located.instruction) {
+        // neg; neg and not; not
+        output.pop();
+      } else {
+        output.push(located.clone());
+      }
+      continue;
+    }
+
+    match (constant(&output, 2), constant(&output, 1)) {
+      (Some(x), Some(y)) => {
+        output.pop();
+        output[length - 2].instruction = Instruction::Push(Segment::Constant, op.apply(x, y));
+      },
+      (_, Some(y)) if is_identity(op, y) => {
+        output.pop();
+      },
+      _ => output.push(located.clone()),
+    }
+  }
+  output
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use super::super::super::instruction::decode;
+  use super::super::super::lexer::lex;
+  use super::super::super::parser::parse;
+
+  fn folded(source: &[&str]) -> Vec<Instruction> {
+    let module = decode("Main", &parse(&lex(&source.join("\n")))).unwrap();
+    fold(&module.instructions).into_iter().map(|located| located.instruction).collect()
+  }
+
+  #[test]
+  fn evaluates_constant_arithmetic() {
+    let instructions = folded(&[
+      "push constant 3", "push constant 4", "add", "neg", "push constant 2", "sub", "not",
+    ]);
+    assert_eq!(instructions, [Instruction::Push(Segment::Constant, !(-7 - 2))]);
+  }
+
+  #[test]
+  fn removes_identities_and_double_negation() {
+    let instructions = folded(&[
+      "push local 0", "push constant 0", "add", "push constant 1", "neg", "and", "neg", "neg",
+      "push local 1", "sub",
+    ]);
+    assert_eq!(instructions, [
+      Instruction::Push(Segment::Local, 0),
+      Instruction::Push(Segment::Local, 1),
+      Instruction::Arithmetic(Operation::Sub),
+    ]);
+  }
+
+  #[test]
+  fn keeps_the_position_of_the_first_push() {
+    let module = decode("Main", &parse(&lex("push constant 1\npush constant 2\nadd"))).unwrap();
+    let output = fold(&module.instructions);
+    assert_eq!(output.len(), 1);
+    assert_eq!(output[0].line, module.instructions[0].line);
+  }
+}
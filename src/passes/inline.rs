@@ -0,0 +1,262 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use super::super::assembly_builder::SCRATCH_VARIABLES;
+use super::super::code_gen::segment::{Segment, STATIC_WORDS};
+use super::super::instruction::{Instruction, Located, Module};
+use super::spans;
+
+/// The largest function `--inline` inlines, in commands.
+pub const DEFAULT_THRESHOLD: usize = 8;
+
+/// Inlined arguments and locals live in statics of this pseudo-file. Inlined
+/// functions never call anything, so one set is enough for every call site.
+const SCRATCH: &str = "__VM_INLINE";
+
+/// A function that can be substituted for its calls.
+struct Candidate {
+  num_locals: i16,
+  /// One more than the highest argument index used.
+  num_args: i16,
+  /// Everything between `function` and `return`.
+  body: Vec<Instruction>,
+}
+
+/// A function can be inlined if it's straight-line code that calls nothing,
+/// ends in its only `return` and never pops below its own working stack,
+/// which then holds exactly the return value.
+fn candidate(instructions: &[Located], threshold: usize) -> Option<Candidate> {
+  let num_locals = match instructions.first()?.instruction {
+    Instruction::Function(_, num_locals) => num_locals,
+    _ => return None,
+  };
+  let body = match &instructions[1..] {
+    [body @ .., last] if last.instruction == Instruction::Return => body,
+    _ => return None,
+  };
+  if body.len() + num_locals.max(0) as usize > threshold {
+    return None;
+  }
+
+  let mut depth = 0;
+  let mut num_args = 0;
+  for located in body {
+    let segment = match &located.instruction {
+      Instruction::Push(segment, index) | Instruction::Pop(segment, index) => Some((segment, *index)),
+      _ => None,
+    };
+    match segment {
+      Some((Segment::Argument, index)) => num_args = num_args.max(index + 1),
+      Some((Segment::Local, index)) if index >= num_locals => return None,
+      _ => (),
+    }
+    depth += match &located.instruction {
+      Instruction::Push(_, _) => 1,
+      Instruction::Pop(_, _) => -1,
+      Instruction::Arithmetic(op) if op.is_unary() => 0,
+      Instruction::Arithmetic(_) => -1,
+      _ => return None,
+    };
+    // arithmetic mustn't use values from below the function's own stack
+    if depth < 0 || depth == 0 && matches!(located.instruction, Instruction::Arithmetic(_)) {
+      return None;
+    }
+  }
+  if depth != 1 {
+    return None;
+  }
+
+  Some(Candidate {
+    num_locals,
+    num_args,
+    body: body.iter().map(|located| located.instruction.clone()).collect(),
+  })
+}
+
+fn scratch() -> Segment {
+  Segment::Static(String::from(SCRATCH))
+}
+
+/// The pointers the body of `candidate` changes.
+fn written_pointers(candidate: &Candidate) -> Vec<i16> {
+  (0..2).filter(|pointer| {
+    candidate.body.contains(&Instruction::Pop(Segment::Pointer, *pointer))
+  }).collect()
+}
+
+/// How many scratch statics expanding `candidate` with `num_args` arguments
+/// takes.
+fn scratch_words(candidate: &Candidate, num_args: i16) -> usize {
+  let pointers = written_pointers(candidate).last().map_or(0, |pointer| pointer + 1);
+  (num_args + candidate.num_locals + pointers) as usize
+}
+
+/// The instructions that replace `call` of `candidate` with `num_args`
+/// arguments: the arguments are popped into scratch statics, followed by the
+/// locals, and the body then uses those instead. Pointers the body changes
+/// are saved around it.
+fn expand(candidate: &Candidate, num_args: i16) -> Vec<Instruction> {
+  let mut expansion = Vec::new();
+  for index in (0..num_args).rev() {
+    expansion.push(Instruction::Pop(scratch(), index));
+  }
+  for index in num_args..num_args + candidate.num_locals {
+    expansion.push(Instruction::Push(Segment::Constant, 0));
+    expansion.push(Instruction::Pop(scratch(), index));
+  }
+  // `return` would have restored THIS and THAT
+  let saved = num_args + candidate.num_locals;
+  let written = written_pointers(candidate);
+  for pointer in &written {
+    expansion.push(Instruction::Push(Segment::Pointer, *pointer));
+    expansion.push(Instruction::Pop(scratch(), saved + pointer));
+  }
+  let rewrite = |segment: &Segment, index: i16| match segment {
+    Segment::Argument => (scratch(), index),
+    Segment::Local => (scratch(), num_args + index),
+    _ => (segment.clone(), index),
+  };
+  for instruction in &candidate.body {
+    expansion.push(match instruction {
+      Instruction::Push(segment, index) => {
+        let (segment, index) = rewrite(segment, *index);
+        Instruction::Push(segment, index)
+      },
+      Instruction::Pop(segment, index) => {
+        let (segment, index) = rewrite(segment, *index);
+        Instruction::Pop(segment, index)
+      },
+      _ => instruction.clone(),
+    });
+  }
+  for pointer in &written {
+    expansion.push(Instruction::Push(scratch(), saved + pointer));
+    expansion.push(Instruction::Pop(Segment::Pointer, *pointer));
+  }
+  expansion
+}
+
+/// Replace calls to small leaf functions with their bodies, if the body and
+/// the locals it initialises come to at most `threshold` commands, within
+/// the functions `into` accepts and as long as the scratch statics fit next
+/// to the program's own. The first instruction of an expansion takes the
+/// position of the call; the rest have line 0. Returns how many calls to
+/// each function were inlined.
+pub fn inline(
+  modules: &mut [Module],
+  threshold: usize,
+  into: &dyn Fn(&str) -> bool,
+) -> BTreeMap<String, usize> {
+  let mut candidates: HashMap<String, Candidate> = HashMap::new();
+  let mut statics = HashSet::new();
+  for module in modules.iter() {
+    for located in &module.instructions {
+      match &located.instruction {
+        Instruction::Push(segment @ Segment::Static(_), index)
+          | Instruction::Pop(segment @ Segment::Static(_), index) => {
+          statics.insert((segment.clone(), *index));
+        },
+        _ => (),
+      }
+    }
+    for span in spans(module) {
+      if let Some(candidate) = candidate(&module.instructions[span.start..span.end], threshold) {
+        candidates.insert(span.name, candidate);
+      }
+    }
+  }
+
+  let free = STATIC_WORDS.saturating_sub(statics.len() + SCRATCH_VARIABLES.len());
+  let mut inlined = BTreeMap::new();
+  for module in modules.iter_mut() {
+    let mut instructions = Vec::with_capacity(module.instructions.len());
+    let mut caller = "";
+    for located in &module.instructions {
+      let expansion = match &located.instruction {
+        Instruction::Function(name, _) => {
+          caller = name;
+          Vec::new()
+        },
+        Instruction::Call(name, num_args) => match candidates.get(name) {
+          Some(candidate) if candidate.num_args <= *num_args && into(caller)
+            && scratch_words(candidate, *num_args) <= free => {
+            *inlined.entry(name.clone()).or_insert(0) += 1;
+            expand(candidate, *num_args)
+          },
+          _ => Vec::new(),
+        },
+        _ => Vec::new(),
+      };
+      if expansion.is_empty() {
+        instructions.push(located.clone());
+        continue;
+      }
+      for (i, instruction) in expansion.into_iter().enumerate() {
+        instructions.push(if i == 0 {
+          Located::new(instruction, located.line, located.column)
+        } else {
+          Located::new(instruction, 0, 0)
+        });
+      }
+    }
+    module.instructions = instructions;
+  }
+  inlined
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use super::super::super::instruction::{decode, Operation};
+  use super::super::super::lexer::lex;
+  use super::super::super::parser::parse;
+
+  /// `Sys.init` adds 2 and 3 with `Main.add`, after setting `statics` statics.
+  fn program(statics: i16) -> Vec<Module> {
+    let mut lines = vec![String::from("function Sys.init 0")];
+    for index in 0..statics {
+      lines.push(format!("push constant 0\npop static {}", index));
+    }
+    lines.push(String::from("push constant 2\npush constant 3\ncall Main.add 2\npop temp 0"));
+    let add = "function Main.add 0\npush argument 0\npush argument 1\nadd\nreturn";
+    vec![
+      decode("Sys", &parse(&lex(&lines.join("\n")))).unwrap(),
+      decode("Main", &parse(&lex(add))).unwrap(),
+    ]
+  }
+
+  fn calls(modules: &[Module]) -> usize {
+    modules[0].instructions.iter()
+      .filter(|located| matches!(located.instruction, Instruction::Call(_, _)))
+      .count()
+  }
+
+  #[test]
+  fn replaces_calls_with_the_body() {
+    let mut modules = program(0);
+    let inlined = inline(&mut modules, DEFAULT_THRESHOLD, &|_| true);
+    assert_eq!(inlined["Main.add"], 1);
+    assert_eq!(calls(&modules), 0);
+    let body: Vec<Instruction> = modules[0].instructions[3..8].iter()
+      .map(|located| located.instruction.clone())
+      .collect();
+    assert_eq!(body, vec![
+      Instruction::Pop(scratch(), 1),
+      Instruction::Pop(scratch(), 0),
+      Instruction::Push(scratch(), 0),
+      Instruction::Push(scratch(), 1),
+      Instruction::Arithmetic(Operation::Add),
+    ]);
+  }
+
+  #[test]
+  fn keeps_calls_once_the_statics_are_full() {
+    let free = (STATIC_WORDS - SCRATCH_VARIABLES.len()) as i16;
+    let mut modules = program(free - 2);
+    inline(&mut modules, DEFAULT_THRESHOLD, &|_| true);
+    assert_eq!(calls(&modules), 0);
+
+    let mut modules = program(free - 1);
+    assert!(inline(&mut modules, DEFAULT_THRESHOLD, &|_| true).is_empty());
+    assert_eq!(calls(&modules), 1);
+  }
+}
@@ -0,0 +1,95 @@
+pub mod dead_functions;
+pub mod fold;
+pub mod inline;
+
+use super::instruction::{Instruction, Module};
+use super::ir;
+use super::pipeline;
+use super::profile::Profile;
+use super::translator::Options;
+
+/// The function the bootstrap code calls.
+const ENTRY: &str = "Sys.init";
+
+/// A function's name and the range of its module's instructions it spans,
+/// from its `function` command up to the next one.
+pub struct Span {
+  pub name: String,
+  pub start: usize,
+  pub end: usize,
+}
+
+pub fn spans(module: &Module) -> Vec<Span> {
+  let mut spans: Vec<Span> = Vec::new();
+  for (index, located) in module.instructions.iter().enumerate() {
+    if let Instruction::Function(name, _) = &located.instruction {
+      if let Some(last) = spans.last_mut() {
+        last.end = index;
+      }
+      spans.push(Span { name: name.clone(), start: index, end: module.instructions.len() });
+    }
+  }
+  spans
+}
+
+fn size(modules: &[Module]) -> usize {
+  modules.iter().map(|module| module.instructions.len()).sum()
+}
+
+/// Apply the VM-to-VM passes enabled in `options`, returning a summary of
+/// what each of them did. Every instruction keeps the source position of the
+/// command it came from, or has line 0 if there isn't one.
+/// With a `profile`, calls in hot functions are inlined even if inlining
+/// isn't enabled, unless it was switched off by name.
+pub fn optimise(
+  modules: &mut [Module],
+  options: Options,
+  profile: Option<&Profile>,
+) -> Result<Vec<String>, String> {
+  let mut reports = Vec::new();
+  let everywhere = |_: &str| true;
+  let hot = |caller: &str| profile.is_some_and(|profile| profile.is_hot(caller));
+  let pinned = pipeline::find("inline").unwrap().is_pinned(&options);
+  let (threshold, into): (usize, &dyn Fn(&str) -> bool) = match profile {
+    Some(_) if options.inline_threshold == 0 && !pinned => (inline::DEFAULT_THRESHOLD, &hot),
+    _ => (options.inline_threshold, &everywhere),
+  };
+  if threshold > 0 {
+    let inlined = inline::inline(modules, threshold, into);
+    let mut report = format!(
+      "inlining: {} calls to {} functions",
+      inlined.values().sum::<usize>(),
+      inlined.len(),
+    );
+    for (name, count) in inlined {
+      report += &format!("\n  {:>6}  {}", count, name);
+    }
+    reports.push(report);
+  }
+  if options.drop_unused_functions {
+    let before = pipeline::rom_size(modules, options)?;
+    let removed = dead_functions::eliminate(modules, ENTRY);
+    let mut report = format!(
+      "unused functions: dropped {} ({} instructions)",
+      removed.len(),
+      before.saturating_sub(pipeline::rom_size(modules, options)?),
+    );
+    let empty = pipeline::rom_size(&[], options)?;
+    for (name, function) in removed {
+      let size = pipeline::rom_size(&[function], options)? - empty;
+      report += &format!("\n  {:>6}  {}", size, name);
+    }
+    reports.push(report);
+  }
+  if options.propagate || options.licm {
+    reports.extend(ir::optimise(modules, options));
+  }
+  if options.fold_constants {
+    let before = size(modules);
+    for module in modules.iter_mut() {
+      module.instructions = fold::fold(&module.instructions);
+    }
+    reports.push(format!("constant folding: {} -> {} VM commands", before, size(modules)));
+  }
+  Ok(reports)
+}
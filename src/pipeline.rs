@@ -0,0 +1,239 @@
+use super::instruction::Module;
+use super::passes::{self, inline};
+use super::profile::{self, Profile};
+use super::translator::{Options, Translator};
+
+/// A transformation that can be switched on and off by name, with
+/// `--<name>` or `--pass=+<name>,-<name>`.
+pub struct Pass {
+  pub name: &'static str,
+  enable: fn(&mut Options, bool),
+  enabled: fn(&Options) -> bool,
+}
+
+macro_rules! pass {
+  ( $name:expr, $field:ident ) => {
+    Pass {
+      name: $name,
+      enable: |options, on| options.$field = on,
+      enabled: |options| options.$field,
+    }
+  };
+}
+
+/// Every pass. The VM-level ones run in this order.
+pub const PASSES: [Pass; 16] = [
+  Pass {
+    name: "inline",
+    enable: |options, on| {
+      options.inline_threshold = if on { inline::DEFAULT_THRESHOLD } else { 0 };
+    },
+    enabled: |options| options.inline_threshold > 0,
+  },
+  pass!("drop-unused", drop_unused_functions),
+  pass!("propagate", propagate),
+  pass!("licm", licm),
+  pass!("fold", fold_constants),
+  pass!("fast-compare", fast_comparisons),
+  pass!("fuse-branches", fused_branches),
+  pass!("superinstructions", superinstructions),
+  pass!("intrinsics", intrinsics),
+  pass!("tail-calls", tail_calls),
+  pass!("cache-top", cache_top),
+  pass!("shared-calls", shared_calls),
+  pass!("shared-compare", shared_comparisons),
+  pass!("peephole", peephole),
+  pass!("outline", outline),
+  pass!("profile", profile_guided),
+];
+
+pub fn find(name: &str) -> Option<&'static Pass> {
+  PASSES.iter().find(|pass| pass.name == name)
+}
+
+impl Pass {
+  pub fn set(&self, options: &mut Options, on: bool) {
+    (self.enable)(options, on)
+  }
+
+  pub fn is_enabled(&self, options: &Options) -> bool {
+    (self.enabled)(options)
+  }
+
+  /// Switch the pass on or off at the user's request, so that it stays that
+  /// way.
+  pub fn pin(&self, options: &mut Options, on: bool) {
+    self.set(options, on);
+    options.pinned |= self.bit();
+  }
+
+  pub fn is_pinned(&self, options: &Options) -> bool {
+    options.pinned & self.bit() != 0
+  }
+
+  fn bit(&self) -> u32 {
+    1 << PASSES.iter().position(|pass| pass.name == self.name).unwrap()
+  }
+}
+
+/// The passes enabled by `-O0`, `-O1`, `-O2` or `-Os`. Only `-Os` prefers
+/// smaller code to faster.
+pub fn preset(level: &str) -> Option<Options> {
+  let names: &[&str] = match level {
+    "0" => &[],
+    "1" => &[
+      "drop-unused", "fold", "fast-compare", "fuse-branches", "superinstructions", "peephole",
+    ],
+    "2" => &[
+      "inline", "drop-unused", "propagate", "licm", "fold", "fast-compare", "fuse-branches",
+      "superinstructions", "tail-calls", "cache-top", "peephole",
+    ],
+    // shared comparison routines rarely pay for themselves once branches
+    // are fused
+    "s" => &[
+      "drop-unused", "propagate", "fold", "fast-compare", "fuse-branches", "superinstructions",
+      "tail-calls", "cache-top", "shared-calls", "peephole", "outline",
+    ],
+    _ => return None,
+  };
+  let mut options = Options::default();
+  for name in names {
+    find(name).unwrap().set(&mut options, true);
+  }
+  options.prefer_size = level == "s";
+  Some(options)
+}
+
+/// Apply a comma-separated list of `+name` and `-name` to `options`.
+pub fn toggle(options: &mut Options, list: &str) -> Result<(), String> {
+  for item in list.split(',') {
+    let (on, name) = match (item.strip_prefix('+'), item.strip_prefix('-')) {
+      (Some(name), _) => (true, name),
+      (_, Some(name)) => (false, name),
+      _ => return Err(format!("Expected +pass or -pass, found '{}'", item)),
+    };
+    match find(name) {
+      Some(pass) => pass.pin(options, on),
+      None => return Err(format!("Unknown pass '{}'", name)),
+    }
+  }
+  Ok(())
+}
+
+pub struct Compiled {
+  /// The modules after the VM-level passes.
+  pub modules: Vec<Module>,
+  pub translator: Translator,
+  /// The profile, if any, and what each VM-level pass, the
+  /// superinstructions, the intrinsics, the peephole optimiser and outlining
+  /// did.
+  pub reports: Vec<String>,
+  /// The profile the translation was tuned by, if any.
+  pub profile: Option<Profile>,
+}
+
+/// Run the VM-level passes over `modules`, translate them and optimise the
+/// result.
+pub fn compile(modules: &[Module], options: Options) -> Result<Compiled, String> {
+  let profile = record(modules, options)?;
+  let (modules, translator, reports) = compile_with(modules, options, profile.as_ref())?;
+  Ok(Compiled { modules, translator, reports, profile })
+}
+
+/// The profile `options` asks for, if any.
+fn record(modules: &[Module], options: Options) -> Result<Option<Profile>, String> {
+  match options.profile_guided {
+    true => profile::record(modules, profile::CYCLES).map(Some),
+    false => Ok(None),
+  }
+}
+
+fn compile_with(
+  modules: &[Module],
+  options: Options,
+  profile: Option<&Profile>,
+) -> Result<(Vec<Module>, Translator, Vec<String>), String> {
+  let mut modules = modules.to_vec();
+  let mut reports: Vec<String> = profile.iter().map(|profile| profile.to_string()).collect();
+  reports.extend(passes::optimise(&mut modules, options, profile)?);
+  let (mut translator, mut translation_reports) =
+    translate(&modules, options, profile.map(|profile| (profile, false)))?;
+  // shared call routines only make cold code smaller if there's enough of it
+  if let Some(profile) = profile {
+    let shared = translate(&modules, options, Some((profile, true)))?;
+    if size(&shared.0) < size(&translator) {
+      translation_reports = shared.1;
+      translation_reports.push(String::from("profile: cold code uses shared call routines"));
+      translator = shared.0;
+    }
+  }
+  reports.extend(translation_reports);
+  Ok((modules, translator, reports))
+}
+
+/// Translate `modules`, tuned by the profile if there is one, sharing call
+/// routines in cold code if asked to.
+fn translate(
+  modules: &[Module],
+  options: Options,
+  profile: Option<(&Profile, bool)>,
+) -> Result<(Translator, Vec<String>), String> {
+  let mut translator = Translator::new(options);
+  if let Some((profile, shared_calls)) = profile {
+    translator.use_profile(profile, shared_calls);
+  }
+  for module in modules {
+    translator.translate_module(module)?;
+  }
+  let mut reports: Vec<String> = translator.finish().into_iter().collect();
+  if options.propagate {
+    reports.push(translator.statement_report());
+  }
+  if options.superinstructions {
+    reports.push(translator.superinstruction_report());
+  }
+  if options.intrinsics {
+    reports.push(translator.intrinsic_report());
+  }
+  if options.peephole {
+    reports.push(translator.peephole().to_string());
+  }
+  if options.outline {
+    reports.push(translator.outline().to_string());
+  }
+  Ok((translator, reports))
+}
+
+/// How many words of ROM the translation takes.
+pub fn size(translator: &Translator) -> usize {
+  translator.instructions().iter().filter(|instruction| instruction.is_code()).count()
+}
+
+/// How many words of ROM `modules` take translated as they are with
+/// `options`, without a profile.
+pub fn rom_size(modules: &[Module], options: Options) -> Result<usize, String> {
+  Ok(size(&translate(modules, options, None)?.0))
+}
+
+/// For every enabled pass, how many instructions `compiled`, from `modules`
+/// with `options`, has without it and with it. Translating once per pass
+/// takes a while, so this is only worked out on request.
+pub fn effects(
+  modules: &[Module],
+  options: Options,
+  compiled: &Compiled,
+) -> Result<Vec<String>, String> {
+  let size_with = size(&compiled.translator);
+  let mut effects = Vec::new();
+  for pass in PASSES.iter().filter(|pass| pass.is_enabled(&options)) {
+    let mut without = options;
+    pass.pin(&mut without, false);
+    // running the program again for every pass would be slow
+    let profile = compiled.profile.as_ref().filter(|_| without.profile_guided);
+    let size_without = size(&compile_with(modules, without, profile)?.1);
+    effects.push(format!(
+      "{}: {} -> {} instructions", pass.name, size_without, size_with,
+    ));
+  }
+  Ok(effects)
+}
@@ -0,0 +1,188 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+
+use super::hack::asm::{Comp, Dest, Jump};
+use super::hack::assembler::{self, encode_c_instruction};
+use super::hack::emulator::Emulator;
+use super::instruction::{Instruction, Module};
+use super::pipeline;
+use super::translator::{Options, Translator};
+
+/// How long a program may run while being profiled. Programs that never
+/// halt, such as games waiting for input, are profiled over this many cycles.
+pub const CYCLES: u64 = 10_000_000;
+
+/// Functions with at least this share of all the counts, in percent, are hot.
+const HOT_PERCENT: u64 = 1;
+
+/// How many times each function was entered and each label reached while
+/// running a program's plain translation in the Hack emulator.
+#[derive(Debug, Default)]
+pub struct Profile {
+  /// Every function, including those that never ran.
+  pub functions: BTreeMap<String, u64>,
+  /// Keyed by `Function$LABEL`, as in the assembly.
+  pub labels: BTreeMap<String, u64>,
+  pub cycles: u64,
+  /// Whether the program finished within the cycle limit.
+  pub halted: bool,
+  /// The sum of all the counts.
+  pub total: u64,
+}
+
+/// Run `modules` for at most `cycles` and count how often execution reaches
+/// each function and label.
+pub fn record(modules: &[Module], cycles: u64) -> Result<Profile, String> {
+  let mut translator = Translator::new(Options::default());
+  for module in modules {
+    translator.translate_module(module)?;
+  }
+  let hack = assembler::assemble(translator.instructions())?;
+
+  let functions: HashSet<&str> = modules.iter()
+    .flat_map(|module| &module.instructions)
+    .filter_map(|located| match &located.instruction {
+      Instruction::Function(name, _) => Some(name.as_str()),
+      _ => None,
+    })
+    .collect();
+  let mut labels_at: HashMap<u16, Vec<&str>> = HashMap::new();
+  for (label, address) in &hack.labels {
+    if functions.contains(label.as_str()) || label.contains('$') {
+      labels_at.entry(*address).or_default().push(label);
+    }
+  }
+
+  let mut profile = Profile {
+    functions: functions.iter().map(|name| (name.to_string(), 0)).collect(),
+    ..Profile::default()
+  };
+  let jump = encode_c_instruction(Dest::NONE, Comp::Zero, Jump::Always);
+  let mut cpu = Emulator::new(hack.rom.clone());
+  while cpu.cycles < cycles {
+    let pc = cpu.pc;
+    for label in labels_at.get(&pc).into_iter().flatten() {
+      let counts = match functions.contains(label) {
+        true => &mut profile.functions,
+        false => &mut profile.labels,
+      };
+      *counts.entry(label.to_string()).or_insert(0) += 1;
+    }
+    // a `goto` to the label right before it
+    let halted = hack.rom.get(pc as usize) == Some(&pc)
+      && hack.rom.get(pc as usize + 1) == Some(&jump);
+    if halted || !cpu.step() {
+      profile.halted = true;
+      break;
+    }
+  }
+  profile.cycles = cpu.cycles;
+  profile.total = profile.functions.values().chain(profile.labels.values()).sum();
+  Ok(profile)
+}
+
+impl Profile {
+  /// How many times the function was entered plus how many times its labels
+  /// were reached.
+  pub fn weight(&self, function: &str) -> u64 {
+    let prefix = format!("{}$", function);
+    let labels: u64 = self.labels.range(prefix.clone()..)
+      .take_while(|(label, _)| label.starts_with(&prefix))
+      .map(|(_, count)| count)
+      .sum();
+    self.functions.get(function).cloned().unwrap_or(0) + labels
+  }
+
+  pub fn is_hot(&self, function: &str) -> bool {
+    self.total > 0 && self.weight(function) * 100 >= self.total * HOT_PERCENT
+  }
+
+  /// `options` for translating `function`: hot functions are translated for
+  /// speed and the rest for size, with shared call routines if
+  /// `shared_calls`. Passes the user pinned stay as they are.
+  pub fn tune(&self, function: &str, options: Options, shared_calls: bool) -> Options {
+    let mut tuned = options;
+    let mut relax = |name: &str, on: bool| {
+      let pass = pipeline::find(name).unwrap();
+      if !pass.is_pinned(&options) {
+        pass.set(&mut tuned, on);
+      }
+    };
+    let hot = self.is_hot(function);
+    if hot {
+      relax("fuse-branches", true);
+      relax("shared-calls", false);
+    } else {
+      relax("shared-calls", shared_calls);
+    }
+    tuned.prefer_size = !hot;
+    tuned
+  }
+}
+
+impl fmt::Display for Profile {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let mut weights: Vec<(u64, &String)> = self.functions.keys()
+      .map(|name| (self.weight(name), name))
+      .collect();
+    weights.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(b.1)));
+    write!(
+      f,
+      "profile: {} cycles{}, {} of {} functions hot",
+      self.cycles,
+      if self.halted { "" } else { " (stopped at the limit)" },
+      weights.iter().filter(|(_, name)| self.is_hot(name)).count(),
+      weights.len(),
+    )?;
+    for (weight, name) in weights.iter().filter(|(weight, _)| *weight > 0) {
+      write!(f, "\n  {:>8}  {}{}", weight, name, if self.is_hot(name) { " (hot)" } else { "" })?;
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use super::super::instruction::decode;
+  use super::super::lexer::lex;
+  use super::super::parser::parse;
+
+  /// `Main.step` runs 100 times and `Main.once` once.
+  fn profile() -> Profile {
+    let source = [
+      "function Sys.init 0", "call Main.once 0", "pop temp 0",
+      "label LOOP", "push temp 1", "push constant 100", "eq", "if-goto END",
+      "call Main.step 0", "pop temp 0", "goto LOOP", "label END", "goto END",
+      "function Main.once 0", "push constant 0", "return",
+      "function Main.step 0", "push temp 1", "push constant 1", "add", "pop temp 1",
+      "push constant 0", "return",
+    ].join("\n");
+    let modules = vec![decode("Sys", &parse(&lex(&source))).unwrap()];
+    record(&modules, 100_000).unwrap()
+  }
+
+  #[test]
+  fn tunes_hot_functions_for_speed() {
+    let profile = profile();
+    assert_eq!(profile.functions["Main.step"], 100);
+    assert!(profile.is_hot("Main.step") && !profile.is_hot("Main.once"));
+
+    let hot = profile.tune("Main.step", Options { shared_calls: true, ..Options::default() }, true);
+    assert!(hot.fused_branches && !hot.shared_calls && !hot.prefer_size);
+    let cold = profile.tune("Main.once", Options::default(), true);
+    assert!(!cold.fused_branches && cold.shared_calls && cold.prefer_size);
+  }
+
+  #[test]
+  fn leaves_pinned_passes_alone() {
+    let profile = profile();
+    let mut options = Options::default();
+    pipeline::find("fuse-branches").unwrap().pin(&mut options, false);
+    pipeline::find("shared-calls").unwrap().pin(&mut options, true);
+    let hot = profile.tune("Main.step", options, false);
+    assert!(!hot.fused_branches && hot.shared_calls && !hot.prefer_size);
+    let cold = profile.tune("Main.once", options, false);
+    assert!(cold.shared_calls && cold.prefer_size);
+  }
+}
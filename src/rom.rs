@@ -0,0 +1,183 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+
+use super::hack::asm::AsmInstr;
+use super::instruction::{Instruction, Located, Module};
+use super::translator::CHECKPOINT_PREFIX;
+
+/// How many instructions the Hack ROM holds.
+pub const ROM_SIZE: usize = 32768;
+
+/// A VM command, along with the file and function it's in.
+pub struct Command<'m> {
+  pub module: &'m str,
+  /// None before the first `function` of the file.
+  pub function: Option<&'m str>,
+  pub located: &'m Located,
+}
+
+/// Every command of `modules`, numbered the way the translator numbers its
+/// checkpoints.
+pub fn commands(modules: &[Module]) -> Vec<Command<'_>> {
+  let mut commands = Vec::new();
+  for module in modules {
+    let mut function = None;
+    for located in &module.instructions {
+      if let Instruction::Function(name, _) = &located.instruction {
+        function = Some(name.as_str());
+      }
+      commands.push(Command { module: &module.name, function, located });
+    }
+  }
+  commands
+}
+
+/// For each of `instructions`, translated from `commands` with checkpoints,
+/// the number of the command it belongs to: that of the last checkpoint
+/// before it. Shared routines, outlined sequences and functions that lost
+/// their checkpoints to merging belong to none. Commands translated
+/// together, or while the top of the stack was cached, count as the first.
+pub fn attribute(instructions: &[AsmInstr], commands: &[Command]) -> Vec<Option<usize>> {
+  let functions: HashSet<&str> = commands.iter().filter_map(|command| command.function).collect();
+  // code starting at a label that isn't part of a command's translation
+  let is_shared = |label: &str| {
+    label.starts_with("__VM_") && !label.starts_with("__VM_GENERATED_")
+      || functions.contains(label)
+  };
+
+  let mut owners = vec![None; instructions.len()];
+  let mut owner = None;
+  let mut index = 0;
+  while index < instructions.len() {
+    // labels at the same address: a checkpoint among them wins
+    let end = instructions[index..].iter()
+      .position(AsmInstr::is_code)
+      .map_or(instructions.len(), |offset| index + offset);
+    let labels = instructions[index..end].iter().filter_map(|instruction| match instruction {
+      AsmInstr::Label(label) => Some(label.as_str()),
+      _ => None,
+    });
+    let mut checkpoint = None;
+    let mut shared = false;
+    for label in labels {
+      match label.strip_prefix(CHECKPOINT_PREFIX) {
+        Some(number) => checkpoint = number.parse().ok(),
+        None => shared |= is_shared(label),
+      }
+    }
+    if checkpoint.is_some() || shared {
+      owner = checkpoint;
+    }
+    for slot in &mut owners[index..(end + 1).min(instructions.len())] {
+      *slot = owner;
+    }
+    index = end + 1;
+  }
+  owners
+}
+
+/// The command kind `instruction` counts as in `Usage`.
+fn kind(instruction: &Instruction) -> String {
+  match instruction {
+    Instruction::Push(segment, _) => format!("push {}", segment.name()),
+    Instruction::Pop(segment, _) => format!("pop {}", segment.name()),
+    Instruction::Arithmetic(op) => String::from(op.name()),
+    Instruction::Label(_) => String::from("label"),
+    Instruction::Goto(_) => String::from("goto"),
+    Instruction::IfGoto(_) => String::from("if-goto"),
+    Instruction::Function(..) => String::from("function"),
+    Instruction::Call(..) => String::from("call"),
+    Instruction::Return => String::from("return"),
+  }
+}
+
+/// How many instructions of a translation each file, function and kind of
+/// command takes. Labels and comments don't count.
+#[derive(Debug, Default)]
+pub struct Usage {
+  pub total: usize,
+  pub files: BTreeMap<String, usize>,
+  pub functions: BTreeMap<String, usize>,
+  pub kinds: BTreeMap<String, usize>,
+  /// The bootstrap code, shared routines and outlined sequences.
+  pub shared: usize,
+}
+
+impl Usage {
+  /// Measure `instructions`, translated from `modules` with checkpoints.
+  pub fn measure(instructions: &[AsmInstr], modules: &[Module]) -> Usage {
+    let commands = commands(modules);
+    let mut usage = Usage::default();
+    for (instruction, owner) in instructions.iter().zip(attribute(instructions, &commands)) {
+      if !instruction.is_code() {
+        continue;
+      }
+      usage.total += 1;
+      let command = match owner {
+        Some(number) => &commands[number],
+        None => {
+          usage.shared += 1;
+          continue;
+        },
+      };
+      *usage.files.entry(format!("{}.vm", command.module)).or_insert(0) += 1;
+      let function = command.function.unwrap_or("(outside functions)");
+      *usage.functions.entry(String::from(function)).or_insert(0) += 1;
+      *usage.kinds.entry(kind(&command.located.instruction)).or_insert(0) += 1;
+    }
+    usage
+  }
+}
+
+/// The largest first, with `shared` last.
+fn write_counts(
+  f: &mut fmt::Formatter,
+  title: &str,
+  counts: &BTreeMap<String, usize>,
+  shared: usize,
+  bars: bool,
+) -> fmt::Result {
+  let mut sorted: Vec<(&String, &usize)> = counts.iter().collect();
+  sorted.sort_by_key(|(_, count)| std::cmp::Reverse(**count));
+  let width = sorted.iter().map(|(name, _)| name.len()).max().unwrap_or(0);
+  let most = sorted.first().map_or(0, |(_, count)| **count);
+  write!(f, "\n  {}:", title)?;
+  for (name, count) in sorted {
+    write!(f, "\n    {:>6}  {}", count, name)?;
+    if bars {
+      // at most 40 wide, but at least 1 for anything there is
+      let length = (count * 40).div_ceil(most);
+      write!(f, "{:width$}  {}", "", "#".repeat(length), width = width - name.len())?;
+    }
+  }
+  if shared > 0 {
+    write!(f, "\n    {:>6}  (bootstrap and shared code)", shared)?;
+  }
+  Ok(())
+}
+
+impl fmt::Display for Usage {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(
+      f,
+      "rom: {} of {} instructions ({:.1}%)",
+      self.total, ROM_SIZE, self.total as f64 * 100.0 / ROM_SIZE as f64,
+    )?;
+    write_counts(f, "by file", &self.files, self.shared, false)?;
+    write_counts(f, "by function", &self.functions, self.shared, false)?;
+    write_counts(f, "by command", &self.kinds, 0, true)
+  }
+}
+
+/// An error if a program of `size` instructions doesn't fit in the ROM, or
+/// a warning if it takes more than `warn_above` instructions.
+pub fn check(size: usize, warn_above: Option<usize>) -> Result<Option<String>, String> {
+  if size > ROM_SIZE {
+    return Err(format!(
+      "The program takes {} instructions, but the ROM only holds {}", size, ROM_SIZE,
+    ));
+  }
+  Ok(warn_above.filter(|limit| size > *limit).map(|limit| {
+    format!("warning: the program takes {} instructions, more than the {} allowed", size, limit)
+  }))
+}
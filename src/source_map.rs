@@ -0,0 +1,91 @@
+use std::io::{Result, Write};
+
+use super::hack::asm::AsmInstr;
+use super::instruction::Module;
+use super::rom::{attribute, commands};
+
+/// Consecutive ROM addresses translated from the same command.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Range {
+  pub start: usize,
+  /// The last address, inclusive.
+  pub end: usize,
+  /// The number of the command, as in `rom::commands`, or None for shared
+  /// code.
+  pub command: Option<usize>,
+}
+
+/// Split the code of `instructions`, translated with checkpoints, into runs
+/// that belong to the same command, in address order.
+pub fn ranges(instructions: &[AsmInstr], modules: &[Module]) -> Vec<Range> {
+  let owners = attribute(instructions, &commands(modules));
+  let mut ranges: Vec<Range> = Vec::new();
+  let code = instructions.iter().zip(owners).filter(|(instruction, _)| instruction.is_code());
+  for (address, (_, command)) in code.enumerate() {
+    match ranges.last_mut() {
+      Some(range) if range.command == command => range.end = address,
+      _ => ranges.push(Range { start: address, end: address, command }),
+    }
+  }
+  ranges
+}
+
+/// Write a line for every range of `instructions`, translated from
+/// `modules` with checkpoints: `start-end File.vm:line:column function`, or
+/// `start-end -` for shared code. Commands made up by a pass have line 0.
+pub fn write(instructions: &[AsmInstr], modules: &[Module], stream: &mut dyn Write) -> Result<()> {
+  let commands = commands(modules);
+  for range in ranges(instructions, modules) {
+    write!(stream, "{}-{}", range.start, range.end)?;
+    match range.command.map(|number| &commands[number]) {
+      Some(command) => writeln!(
+        stream,
+        " {}.vm:{}:{} {}",
+        command.module,
+        command.located.line,
+        command.located.column,
+        command.function.unwrap_or("-"),
+      )?,
+      None => writeln!(stream, " -")?,
+    }
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use super::super::instruction::decode;
+  use super::super::lexer::lex;
+  use super::super::parser::parse;
+  use super::super::pipeline;
+  use super::super::translator::Options;
+
+  #[test]
+  fn maps_every_address_to_its_command() {
+    let source = "function Sys.init 0\npush constant 1\npop static 0\nlabel END\ngoto END";
+    let modules = vec![decode("Sys", &parse(&lex(source))).unwrap()];
+    let options = Options { checkpoints: true, ..Options::default() };
+    let compiled = pipeline::compile(&modules, options).unwrap();
+    let instructions = compiled.translator.instructions();
+
+    let ranges = ranges(instructions, &modules);
+    assert_eq!(ranges[0].start, 0);
+    assert_eq!(ranges[0].command, None);
+    for pair in ranges.windows(2) {
+      assert_eq!(pair[1].start, pair[0].end + 1);
+    }
+    assert_eq!(ranges.last().unwrap().end + 1, pipeline::size(&compiled.translator));
+    let numbered: Vec<usize> = ranges.iter().filter_map(|range| range.command).collect();
+    // neither the function with no locals nor the label takes any code
+    assert_eq!(numbered, [1, 2, 4]);
+
+    let mut output = Vec::new();
+    write(instructions, &modules, &mut output).unwrap();
+    let output = String::from_utf8(output).unwrap();
+    let lines: Vec<&str> = output.lines().collect();
+    assert_eq!(lines.len(), ranges.len());
+    let push = &ranges[1];
+    assert_eq!(lines[1], format!("{}-{} Sys.vm:2:1 Sys.init", push.start, push.end));
+  }
+}
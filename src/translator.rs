@@ -1,169 +1,810 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::io::{Result, Write};
 
-use super::assembly_builder::AssemblyBuilder;
-use super::command::Command;
-use super::token::{TokenType, Value};
+use super::analysis::range;
+use super::assembly_builder::{
+  divide_by_size, divide_steps, multiply_by_size, signed_digits, AssemblyBuilder, CLEAR_LOOP_SIZE,
+  CONSTANT_ROUTINE_CALL_SIZE, FRAME, RET, SHARED_CALL, SHARED_DIVIDE, SHARED_EQ, SHARED_GT,
+  SHARED_LT, SHARED_MULTIPLY, SHARED_RETURN,
+};
+use super::hack::asm::{AsmInstr, Comp, Jump};
+use super::hack::{outline, peephole};
+use super::instruction::{self, Instruction, Located, Module, Operation};
+use super::ir::codegen;
+use super::profile::Profile;
 use super::code_gen::segment::Segment;
 
+pub const CHECKPOINT_PREFIX: &str = "__VM_CHECKPOINT_";
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Options {
+  /// Emit a `(__VM_CHECKPOINT_n)` label before the code of the n-th VM
+  /// command translated, counting across all modules. Labels take no ROM
+  /// space, so this doesn't change the program's behaviour.
+  pub checkpoints: bool,
+  /// Use the shorter `gt`/`lt` sequences that subtract the operands directly
+  /// wherever range analysis shows that the subtraction can't overflow.
+  pub fast_comparisons: bool,
+  /// Run the peephole optimiser over the generated assembly.
+  pub peephole: bool,
+  /// Merge identical functions and move repeated instruction sequences into
+  /// subroutines after everything else. See `outline::outline`.
+  pub outline: bool,
+  /// Emit one shared routine each for `call` and `return` and jump to them,
+  /// rather than expanding them at every call site.
+  pub shared_calls: bool,
+  /// Emit one shared routine each for `eq`, `gt` and `lt` and jump to them.
+  /// Takes precedence over `fast_comparisons`.
+  pub shared_comparisons: bool,
+  /// Translate a comparison, optionally followed by `not`, followed by an
+  /// `if-goto` into a single conditional jump on the operands' difference.
+  pub fused_branches: bool,
+  /// Fold arithmetic on constants and remove identity operations before
+  /// translating. See `passes::fold`.
+  pub fold_constants: bool,
+  /// Leave out functions that can't be reached from `Sys.init`. See
+  /// `passes::dead_functions`.
+  pub drop_unused_functions: bool,
+  /// Propagate constants and copies and remove dead stores in blocks of
+  /// expression trees lifted from each function, and translate the trees
+  /// that don't call anything as a whole. See `ir`.
+  pub propagate: bool,
+  /// Hoist expressions that are the same on every iteration of a loop out
+  /// of it. See `ir::licm`.
+  pub licm: bool,
+  /// Inline calls to leaf functions of at most this many commands, or none
+  /// if 0. See `passes::inline`.
+  pub inline_threshold: usize,
+  /// Translate `call` immediately followed by `return` into a jump that
+  /// reuses the current frame.
+  pub tail_calls: bool,
+  /// Keep the top of the stack in D across pushes, pops, arithmetic and
+  /// `if-goto`, storing it to memory only before anything else.
+  pub cache_top: bool,
+  /// Where there's a choice, use the shorter translation of a command rather
+  /// than the faster one.
+  pub prefer_size: bool,
+  /// Translate common Jack idioms spanning several commands as a whole. See
+  /// `Superinstruction`.
+  pub superinstructions: bool,
+  /// Translate calls to well-known OS functions without a call frame,
+  /// assuming they do what the Jack OS's do. See `Intrinsic`.
+  pub intrinsics: bool,
+  /// Run the program once to find its hot functions, then translate those
+  /// for speed and everything else for size. See `profile::Profile::tune`.
+  pub profile_guided: bool,
+  /// The passes switched on or off by name, which tuning leaves alone, as
+  /// bits indexed like `pipeline::PASSES`.
+  pub pinned: u32,
+}
+
 #[derive(Debug)]
 pub struct Translator {
   assembly: AssemblyBuilder,
   current_function_name: Option<String>,
+  /// The options for the function being translated.
+  options: Options,
+  defaults: Options,
+  /// Options for particular functions, which `defaults` apply to otherwise.
+  function_options: HashMap<String, Options>,
+  command_count: usize,
+  /// How many times each superinstruction was used.
+  superinstructions: BTreeMap<&'static str, usize>,
+  /// How many calls to each intrinsic were translated.
+  intrinsics: BTreeMap<&'static str, usize>,
+  /// How many statements were translated from their expression trees.
+  statements: usize,
+  /// The shared routines intrinsics have called so far.
+  routines: BTreeSet<&'static str>,
+  /// The functions translated so far.
+  functions: HashSet<String>,
 }
 
 impl Translator {
-  pub fn new() -> Translator {
+  pub fn new(options: Options) -> Translator {
+    let mut assembly = AssemblyBuilder::new();
+    // with a profile, only if some function uses them
+    if options.shared_calls && !options.profile_guided {
+      shared_call_routines!(assembly);
+    }
+    if options.shared_comparisons {
+      shared_comparison_routines!(assembly);
+    }
     Translator {
-      assembly: AssemblyBuilder::new(),
+      assembly,
       current_function_name: None,
+      options,
+      defaults: options,
+      function_options: HashMap::new(),
+      command_count: 0,
+      superinstructions: BTreeMap::new(),
+      intrinsics: BTreeMap::new(),
+      statements: 0,
+      routines: BTreeSet::new(),
+      functions: HashSet::new(),
     }
   }
 
-  pub fn translate_file(&mut self, filename: &str, commands: Vec<Command>) -> std::result::Result<(), String> {
-    for command in commands {
+  pub fn translate_module(&mut self, module: &Module) -> std::result::Result<(), String> {
+    let ranges = if self.options.fast_comparisons {
+      range::operand_ranges(&module.instructions)
+    } else {
+      vec![None; module.instructions.len()]
+    };
 
-      macro_rules! err {
-        ( $message:expr ) => {
-          Err(new_error(String::from($message), filename, &command))
-        };
-        ( $fmtstr:expr, $($fmtargs:expr),* ) => {
-          Err(new_error(
-            format!($fmtstr, $($fmtargs),*),
-            filename,
-            &command,
-          ))
-        };
+    let statements = match self.options.propagate {
+      true => codegen::statements(module),
+      false => HashMap::new(),
+    };
+
+    let instructions = &module.instructions;
+    let mut index = 0;
+    // whether the top of the stack is in D rather than memory
+    let mut cached = false;
+    while index < instructions.len() {
+      let located = &instructions[index];
+      if let Some(statement) = statements.get(&index) {
+        if cached {
+          push_D!(self.assembly);
+          cached = false;
+        }
+        if self.options.checkpoints {
+          vm_label!(self.assembly, format!("{}{}", CHECKPOINT_PREFIX, self.command_count + index));
+        }
+        let fn_name = self.function_name(module, located)?;
+        statement.translate(&mut self.assembly, &fn_name, &self.options);
+        self.statements += 1;
+        index = statement.end + 1;
+        continue;
+      }
+      let operands = ranges[index];
+      // y-x is what the fast sequences compute
+      let fast_comparison = operands.is_some_and(|(x, y)| y.can_subtract(x));
+
+      let intrinsic = match self.options.intrinsics {
+        true => Intrinsic::find(&instructions[index..]),
+        false => None,
+      };
+      let superinstruction = match self.options.superinstructions && intrinsic.is_none() {
+        true => Superinstruction::find(&instructions[index..]),
+        false => None,
+      };
+      let uses_cache = self.options.cache_top
+        && intrinsic.is_none()
+        && superinstruction.is_none()
+        && uses_cache(&located.instruction);
+      if cached && !uses_cache {
+        push_D!(self.assembly);
+        cached = false;
+      }
+      // memory only matches the VM's state when nothing is cached, and a
+      // label before no code would share its address with the next one
+      let emits_code = !intrinsic.as_ref().is_some_and(Intrinsic::emits_no_code);
+      if self.options.checkpoints && !cached && emits_code {
+        vm_label!(self.assembly, format!("{}{}", CHECKPOINT_PREFIX, self.command_count + index));
       }
 
-      match command.name.lexeme { // FIXME: should have CommandType enum and match on that (or have command be enum)
-        "push" | "pop" => {
-          if command.num_args() != 2 {
-            return err!("Expected 2 arguments for {}", command.name.lexeme);
+      if uses_cache {
+        cached = self.translate_cached(module, located, cached)?;
+        index += 1;
+        continue;
+      }
+
+      if let Some(intrinsic) = intrinsic {
+        *self.intrinsics.entry(intrinsic.name()).or_insert(0) += 1;
+        index += intrinsic.len();
+        self.translate_intrinsic(intrinsic);
+        continue;
+      }
+
+      if let Some(superinstruction) = superinstruction {
+        *self.superinstructions.entry(superinstruction.name()).or_insert(0) += 1;
+        index += superinstruction.len();
+        if self.translate_superinstruction(superinstruction) {
+          if self.options.cache_top {
+            cached = true;
+          } else {
+            push_D!(self.assembly);
           }
-          let first_arg = command.arg(0);
-          let second_arg = command.arg(1);
-          if let TokenType::Identifier = first_arg.type_ {
-            if !Segment::is_valid_name(first_arg.lexeme) {
-              return err!("Unknown segment '{}'", first_arg.lexeme);
-            }
-            if let Value::Integer(index) = second_arg.value {
-              let segment = Segment::from_name(first_arg.lexeme, filename)?;
-
-              if command.name.lexeme == "pop" {
-                if !segment.is_writable() {
-                  return err!("Can't pop into read-only segment {}", segment);
-                }
-                pop!(self.assembly, segment, index);
-              }
-              else {
-                if let Segment::Constant = segment {
-                  push_constant!(self.assembly, index);
-                } else {
-                  push!(self.assembly, segment, index);
-                }
-              }
-            } else {
-              return err!("Expected second argument to be integer");
-            }
+        }
+        continue;
+      }
+
+      if self.options.tail_calls {
+        if let (Instruction::Call(function_name, num_args), Some(Instruction::Return)) = (
+          &located.instruction,
+          instructions.get(index + 1).map(|next| &next.instruction),
+        ) {
+          tail_call!(self.assembly, function_name, *num_args);
+          index += 2;
+          continue;
+        }
+      }
+
+      if self.options.fused_branches {
+        if let Some((jump, target, length)) = fused_branch(&instructions[index..]) {
+          let fn_name = self.function_name(module, &instructions[index + length - 1])?;
+          // x-y is what the fused sequences compute
+          if jump == Jump::Eq || jump == Jump::Ne || operands.is_some_and(|(x, y)| {
+            self.options.fast_comparisons && x.can_subtract(y)
+          }) {
+            compare_branch_fast!(self.assembly, jump, fn_name, target);
           } else {
-            return err!("Expected first argument to be identifier");
+            compare_branch!(self.assembly, jump, fn_name, target);
           }
+          index += length;
+          continue;
         }
+      }
+      index += 1;
 
-        "add" | "sub" | "neg" | "eq" | "gt" | "lt" | "and" | "or" | "not" => {
-          if command.num_args() > 0 {
-            return err!("Expected no arguments for command {}", command.name.lexeme);
+      match &located.instruction {
+        Instruction::Push(segment, index) => {
+          if let Segment::Constant = segment {
+            push_constant!(self.assembly, *index);
+          } else {
+            push!(self.assembly, segment, *index);
           }
+        },
 
-          match command.name.lexeme {
-            "add" => add!(self.assembly),
-            "sub" => sub!(self.assembly),
-            "neg" => neg!(self.assembly),
-            "eq" => eq!(self.assembly),
-            "gt" => gt!(self.assembly),
-            "lt" => lt!(self.assembly),
-            "and" => and!(self.assembly),
-            "or" => or!(self.assembly),
-            "not" => not!(self.assembly),
+        Instruction::Pop(segment, index) => {
+          pop!(self.assembly, segment, *index);
+        },
+
+        Instruction::Arithmetic(op) => {
+          match op {
+            Operation::Add => add!(self.assembly),
+            Operation::Sub => sub!(self.assembly),
+            Operation::Neg => neg!(self.assembly),
+            Operation::Eq if self.options.shared_comparisons => call_routine!(self.assembly, SHARED_EQ),
+            Operation::Gt if self.options.shared_comparisons => call_routine!(self.assembly, SHARED_GT),
+            Operation::Lt if self.options.shared_comparisons => call_routine!(self.assembly, SHARED_LT),
+            Operation::Eq => eq!(self.assembly),
+            Operation::Gt if fast_comparison => gt_fast!(self.assembly),
+            Operation::Gt => gt!(self.assembly),
+            Operation::Lt if fast_comparison => lt_fast!(self.assembly),
+            Operation::Lt => lt!(self.assembly),
+            Operation::And => and!(self.assembly),
+            Operation::Or => or!(self.assembly),
+            Operation::Not => not!(self.assembly),
+          };
+        },
+
+        Instruction::Label(label)
+        | Instruction::Goto(label)
+        | Instruction::IfGoto(label) => {
+          let fn_name = self.function_name(module, located)?;
+          match located.instruction {
+            Instruction::Label(_) => label!(self.assembly, fn_name, label),
+            Instruction::Goto(_) => goto!(self.assembly, fn_name, label),
+            Instruction::IfGoto(_) => if_goto!(self.assembly, fn_name, label),
             _ => unreachable!(),
           };
         },
 
-        "label" | "goto" | "if-goto" => {
-          if command.num_args() != 1 {
-            return err!("Expected 1 argument for {}",
-              command.name.lexeme);
-          }
-          let first_arg = command.arg(0);
-
-          if let TokenType::Identifier = first_arg.type_ {
-            let fn_name = match &self.current_function_name {
-              None => return err!("Cannot use {} in non-function context", command.name.lexeme),
-              Some(name) => name,
-            };
-            match command.name.lexeme {
-              "label" => label!(self.assembly, fn_name, first_arg.lexeme),
-              "goto" => goto!(self.assembly, fn_name, first_arg.lexeme),
-              "if-goto" => if_goto!(self.assembly, fn_name, first_arg.lexeme),
-              _ => unreachable!(),
-            };
-          } else {
-            return err!("Expected argument to {} to be identifier", command.name.lexeme);
-          }
+        Instruction::Function(function_name, num_vars) => {
+          self.current_function_name = Some(function_name.clone());
+          self.functions.insert(function_name.clone());
+          self.options = self.function_options.get(function_name).cloned().unwrap_or(self.defaults);
+          function!(self.assembly, function_name, *num_vars, self.options.prefer_size);
         },
 
-        "function" | "call" => {
-          if command.num_args() != 2 {
-            return err!("Expected 2 arguments for {}", command.name.lexeme);
+        Instruction::Call(function_name, num_args) => {
+          if self.options.shared_calls {
+            call_shared!(self.assembly, function_name, *num_args);
+          } else {
+            call!(self.assembly, function_name, *num_args);
           }
-          let first_arg = command.arg(0);
-          let second_arg = command.arg(1);
+        },
 
-          let function_name: &str;
-          if let TokenType::Identifier = first_arg.type_ {
-            function_name = first_arg.lexeme;
+        Instruction::Return => {
+          if self.options.shared_calls {
+            return_shared!(self.assembly);
           } else {
-            return err!("Expected first argument of {} to be identifier", command.name.lexeme);
+            return_!(self.assembly);
           }
+        },
+      }
+    };
+    if cached {
+      push_D!(self.assembly);
+    }
+    self.command_count += instructions.len();
+
+    Ok(())
+  }
+
+  /// Translate an instruction for which `uses_cache` is true, given whether
+  /// the top of the stack is in D. Returns whether it is afterwards.
+  fn translate_cached(
+    &mut self,
+    module: &Module,
+    located: &Located,
+    cached: bool,
+  ) -> std::result::Result<bool, String> {
+    match &located.instruction {
+      Instruction::Push(segment, index) => {
+        if cached {
+          push_D!(self.assembly);
+        }
+        if let Segment::Constant = segment {
+          load_constant!(self.assembly, *index);
+        } else {
+          load!(self.assembly, segment, *index);
+        }
+        Ok(true)
+      },
+
+      Instruction::Pop(segment, index) => {
+        if !cached {
+          pop_D!(self.assembly);
+        }
+        store_D!(self.assembly, segment, *index);
+        Ok(false)
+      },
+
+      Instruction::Arithmetic(op) => {
+        if !cached {
+          pop_D!(self.assembly);
+        }
+        match op {
+          Operation::Add => binary_D!(self.assembly, "ADD", Comp::DPlusM),
+          Operation::Sub => binary_D!(self.assembly, "SUB", Comp::MMinusD),
+          Operation::And => binary_D!(self.assembly, "AND", Comp::DAndM),
+          Operation::Or => binary_D!(self.assembly, "OR", Comp::DOrM),
+          Operation::Neg => unary_D!(self.assembly, "NEG", Comp::NegD),
+          Operation::Not => unary_D!(self.assembly, "NOT", Comp::NotD),
+          _ => unreachable!(),
+        };
+        Ok(true)
+      },
+
+      Instruction::IfGoto(label) => {
+        let fn_name = self.function_name(module, located)?;
+        if !cached {
+          pop_D!(self.assembly);
+        }
+        if_goto_D!(self.assembly, fn_name, label);
+        Ok(false)
+      },
+
+      _ => unreachable!(),
+    }
+  }
+
+  /// Translate each function the profile knows with the options it tunes
+  /// for that function, sharing call routines in cold ones if
+  /// `shared_calls`. Must come before translating anything.
+  pub fn use_profile(&mut self, profile: &Profile, shared_calls: bool) {
+    for function in profile.functions.keys() {
+      let options = profile.tune(function, self.defaults, shared_calls);
+      self.function_options.insert(function.clone(), options);
+    }
+    if self.function_options.values().any(|options| options.shared_calls) {
+      shared_call_routines!(self.assembly);
+    } else {
+      // nothing else may use the routines if they're not there
+      self.defaults.shared_calls = false;
+      self.options = self.defaults;
+    }
+  }
 
-          let num_vars: i16;
-          if let Value::Integer(index) = second_arg.value {
-            num_vars = index;
+  /// Returns whether the superinstruction left a value in D to be pushed.
+  fn translate_superinstruction(&mut self, superinstruction: Superinstruction) -> bool {
+    if let Superinstruction::Increment { address, delta } = superinstruction {
+      increment!(self.assembly, address, delta);
+      return false;
+    }
+    let (segment, index) = match &superinstruction {
+      Superinstruction::ArrayRead { base, .. } => *base,
+      Superinstruction::Move { from, .. } => *from,
+      Superinstruction::Increment { .. } => unreachable!(),
+    };
+    if let Segment::Constant = segment {
+      load_constant!(self.assembly, index);
+    } else {
+      load!(self.assembly, segment, index);
+    }
+    match superinstruction {
+      Superinstruction::ArrayRead { add, .. } => {
+        array_read_D!(self.assembly, add);
+        true
+      },
+      Superinstruction::Move { to: (segment, index), .. } => {
+        store_D!(self.assembly, segment, index);
+        false
+      },
+      Superinstruction::Increment { .. } => unreachable!(),
+    }
+  }
+
+  fn translate_intrinsic(&mut self, intrinsic: Intrinsic) {
+    match intrinsic {
+      Intrinsic::Abs => abs!(self.assembly),
+      Intrinsic::Peek => peek!(self.assembly),
+      Intrinsic::Poke => poke!(self.assembly),
+      Intrinsic::Multiply => self.call_routine(SHARED_MULTIPLY),
+      Intrinsic::Divide => self.call_routine(SHARED_DIVIDE),
+      Intrinsic::MultiplyBy { constant, first } => {
+        if let Some((segment, index)) = first {
+          if let Segment::Constant = segment {
+            push_constant!(self.assembly, index);
           } else {
-            return err!("Expected second argument of {} to be integer", command.name.lexeme);
+            push!(self.assembly, segment, index);
           }
+        }
+        if self.options.prefer_size && multiply_by_size(constant) > CONSTANT_ROUTINE_CALL_SIZE {
+          push_constant!(self.assembly, constant);
+          self.call_routine(SHARED_MULTIPLY);
+        } else {
+          multiply_by!(self.assembly, constant);
+        }
+      },
+      Intrinsic::DivideBy(constant) => {
+        if self.options.prefer_size && divide_by_size(constant) > CONSTANT_ROUTINE_CALL_SIZE {
+          push_constant!(self.assembly, constant);
+          self.call_routine(SHARED_DIVIDE);
+        } else {
+          divide_by!(self.assembly, constant);
+        }
+      },
+    }
+  }
 
-          match command.name.lexeme {
-            "function" => {
-              self.current_function_name = Some(String::from(function_name));
-              function!(self.assembly, function_name, num_vars);
-            },
-            "call" => call!(self.assembly, function_name, num_vars),
-            _ => unreachable!(),
-          };
-        },
+  fn call_routine(&mut self, routine: &'static str) {
+    self.routines.insert(routine);
+    call_routine!(self.assembly, routine);
+  }
 
-        "return" => {
-          return_!(self.assembly);
-        },
+  /// Emit the shared routines that intrinsics called. Must come after
+  /// translating everything. Dividing by 0 jumps to `Math.divide`; if that
+  /// isn't part of the translation, as when the OS is translated on its own,
+  /// the jump is left to it like a call, and this returns a warning.
+  pub fn finish(&mut self) -> Option<String> {
+    if self.routines.contains(SHARED_MULTIPLY) {
+      multiply_routine!(self.assembly);
+    }
+    if self.routines.contains(SHARED_DIVIDE) {
+      divide_routine!(self.assembly, "Math.divide");
+      if !self.functions.contains("Math.divide") {
+        return Some(String::from(
+          "warning: Math.divide isn't defined here, but dividing by 0 jumps to it",
+        ));
+      }
+    }
+    None
+  }
+
+  /// How many calls to each intrinsic were translated.
+  pub fn intrinsic_report(&self) -> String {
+    let total: usize = self.intrinsics.values().sum();
+    let mut report = format!("intrinsics: {} calls replaced", total);
+    for (name, count) in &self.intrinsics {
+      report += &format!("\n  {:>6}  {}", count, name);
+    }
+    report
+  }
+
+  /// How many statements were translated from their expression trees.
+  pub fn statement_report(&self) -> String {
+    format!("expression trees: translated {} statements", self.statements)
+  }
+
+  /// How many times each superinstruction was used.
+  pub fn superinstruction_report(&self) -> String {
+    let total: usize = self.superinstructions.values().sum();
+    let mut report = format!("superinstructions: {} used", total);
+    for (name, count) in &self.superinstructions {
+      report += &format!("\n  {:>6}  {}", count, name);
+    }
+    report
+  }
+
+  /// The function a label, goto or if-goto is scoped to.
+  fn function_name(&self, module: &Module, located: &Located) -> std::result::Result<String, String> {
+    match &self.current_function_name {
+      None => Err(instruction::error_at(
+        format!("Cannot use {} in non-function context", command_name(&located.instruction)),
+        &module.name,
+        located.line,
+        located.column,
+      )),
+      Some(name) => Ok(name.clone()),
+    }
+  }
+
+  /// Optimise everything translated so far. Checkpoint labels may be
+  /// dropped where code around them gets merged.
+  pub fn peephole(&mut self) -> peephole::Stats {
+    peephole::optimise(&mut self.assembly.instructions, &|label| {
+      label.starts_with(CHECKPOINT_PREFIX)
+    })
+  }
 
-        _ => {
-          return err!("Unknown command '{}'", command.name.lexeme);
+  /// Shrink everything translated so far, which nothing may be added to
+  /// afterwards. Checkpoint labels are dropped from shared code.
+  pub fn outline(&mut self) -> outline::Stats {
+    outline::outline(&mut self.assembly.instructions, &self.functions, &|label| {
+      label.starts_with(CHECKPOINT_PREFIX)
+    })
+  }
+
+  /// Remove the checkpoint labels from everything translated so far.
+  pub fn drop_checkpoints(&mut self) {
+    self.assembly.instructions.retain(|instruction| {
+      !matches!(instruction, AsmInstr::Label(label) if label.starts_with(CHECKPOINT_PREFIX))
+    });
+  }
+
+  pub fn instructions(&self) -> &[AsmInstr] {
+    &self.assembly.instructions
+  }
+
+  pub fn write(&self, stream: &mut dyn Write) -> Result<()> {
+    self.assembly.write(stream)
+  }
+}
+
+/// Superinstructions only address operands that take at most this many
+/// instructions without D.
+const SUPERINSTRUCTION_BUDGET: usize = 8;
+
+/// A sequence of commands common in Jack compiler output, translated as a
+/// whole.
+enum Superinstruction<'i> {
+  /// `push x, push y, add, pop pointer 1, push that 0`, reading `x[y]`.
+  /// `add` holds the instructions that add the operand other than `base`
+  /// to D.
+  ArrayRead { base: (&'i Segment, i16), add: Vec<AsmInstr> },
+  /// `push s i, push constant c, add, pop s i`, or `sub`.
+  Increment { address: Vec<AsmInstr>, delta: i16 },
+  /// `push x, pop y`, such as `push argument 0, pop pointer 0` at the start
+  /// of a method.
+  Move { from: (&'i Segment, i16), to: (&'i Segment, i16) },
+}
+
+impl<'i> Superinstruction<'i> {
+  /// Recognises the longest superinstruction at the start of `instructions`.
+  fn find(instructions: &'i [Located]) -> Option<Superinstruction<'i>> {
+    let commands: Vec<&Instruction> =
+      instructions.iter().take(5).map(|located| &located.instruction).collect();
+    if let [
+      Instruction::Push(x, i),
+      Instruction::Push(y, j),
+      Instruction::Arithmetic(Operation::Add),
+      Instruction::Pop(Segment::Pointer, 1),
+      Instruction::Push(Segment::That, 0),
+    ] = commands[..] {
+      let add = |segment: &Segment, index: i16| match segment {
+        Segment::Constant if index >= 0 => {
+          let mut add = Vec::new();
+          hack!(add, @(index), D=D+A);
+          Some(add)
         },
+        Segment::Constant => None,
+        _ => segment.address_without_d(index, SUPERINSTRUCTION_BUDGET).map(|mut add| {
+          hack!(add, D=D+M);
+          add
+        }),
+      };
+      // either operand can be the one added to D
+      if let Some(add) = add(y, *j) {
+        return Some(Superinstruction::ArrayRead { base: (x, *i), add });
       }
+      if let Some(add) = add(x, *i) {
+        return Some(Superinstruction::ArrayRead { base: (y, *j), add });
+      }
+    }
+    if let [
+      Instruction::Push(s, i),
+      Instruction::Push(Segment::Constant, c),
+      Instruction::Arithmetic(op @ (Operation::Add | Operation::Sub)),
+      Instruction::Pop(t, k),
+      ..
+    ] = commands[..] {
+      let delta = if *op == Operation::Add { *c } else { c.wrapping_neg() };
+      let address = match delta {
+        _ if s != t || i != k || !s.is_writable() => None,
+        1 | -1 => Some(s.resolve_address(*i)),
+        _ => s.address_without_d(*i, SUPERINSTRUCTION_BUDGET),
+      };
+      if let Some(address) = address {
+        return Some(Superinstruction::Increment { address, delta });
+      }
+    }
+    if let [Instruction::Push(x, i), Instruction::Pop(y, j), ..] = commands[..] {
+      return Some(Superinstruction::Move { from: (x, *i), to: (y, *j) });
+    }
+    None
+  }
+
+  fn name(&self) -> &'static str {
+    match self {
+      Superinstruction::ArrayRead { .. } => "array read",
+      Superinstruction::Increment { .. } => "increment",
+      Superinstruction::Move { .. } => "push then pop",
+    }
+  }
+
+  /// How many commands it stands for.
+  fn len(&self) -> usize {
+    match self {
+      Superinstruction::ArrayRead { .. } => 5,
+      Superinstruction::Increment { .. } => 4,
+      Superinstruction::Move { .. } => 2,
+    }
+  }
+}
+
+/// A call to an OS function translated without a call frame. `Math.abs`,
+/// `Memory.peek` and `Memory.poke` are translated inline; `Math.multiply`
+/// and `Math.divide` call shared routines, unless one operand is a constant.
+enum Intrinsic<'i> {
+  Abs,
+  Peek,
+  Poke,
+  Multiply,
+  Divide,
+  /// `push constant c, call Math.multiply 2`, or `push constant c, push x,
+  /// call Math.multiply 2` with the push of x in `first`.
+  MultiplyBy { constant: i16, first: Option<(&'i Segment, i16)> },
+  /// `push constant c, call Math.divide 2` for c other than 0.
+  DivideBy(i16),
+}
+
+impl<'i> Intrinsic<'i> {
+  /// Recognises the longest intrinsic at the start of `instructions`.
+  fn find(instructions: &'i [Located]) -> Option<Intrinsic<'i>> {
+    let commands: Vec<&Instruction> =
+      instructions.iter().take(3).map(|located| &located.instruction).collect();
+    let call = |command: &'i Instruction| match command {
+      Instruction::Call(name, num_args) => Some((name.as_str(), *num_args)),
+      _ => None,
     };
+    if let [
+      Instruction::Push(Segment::Constant, c),
+      Instruction::Push(x, i),
+      third,
+    ] = commands[..] {
+      if call(third) == Some(("Math.multiply", 2)) {
+        return Some(Intrinsic::MultiplyBy { constant: *c, first: Some((x, *i)) });
+      }
+    }
+    if let [Instruction::Push(Segment::Constant, c), second, ..] = commands[..] {
+      match call(second) {
+        Some(("Math.multiply", 2)) => {
+          return Some(Intrinsic::MultiplyBy { constant: *c, first: None });
+        },
+        Some(("Math.divide", 2)) if *c != 0 => return Some(Intrinsic::DivideBy(*c)),
+        _ => (),
+      }
+    }
+    match call(commands.first()?)? {
+      ("Math.abs", 1) => Some(Intrinsic::Abs),
+      ("Memory.peek", 1) => Some(Intrinsic::Peek),
+      ("Memory.poke", 2) => Some(Intrinsic::Poke),
+      ("Math.multiply", 2) => Some(Intrinsic::Multiply),
+      ("Math.divide", 2) => Some(Intrinsic::Divide),
+      _ => None,
+    }
+  }
 
-    Ok(())
+  fn name(&self) -> &'static str {
+    match self {
+      Intrinsic::Abs => "Math.abs",
+      Intrinsic::Peek => "Memory.peek",
+      Intrinsic::Poke => "Memory.poke",
+      Intrinsic::Multiply => "Math.multiply",
+      Intrinsic::Divide => "Math.divide",
+      Intrinsic::MultiplyBy { .. } => "Math.multiply by a constant",
+      Intrinsic::DivideBy(_) => "Math.divide by a constant",
+    }
   }
 
-  pub fn write(&self, stream: &mut Write) -> Result<()> {
-    self.assembly.write(stream)
+  /// Whether it's multiplying or dividing by 1, which takes no code.
+  fn emits_no_code(&self) -> bool {
+    matches!(self, Intrinsic::MultiplyBy { constant: 1, first: None } | Intrinsic::DivideBy(1))
+  }
+
+  /// How many commands it stands for.
+  fn len(&self) -> usize {
+    match self {
+      Intrinsic::MultiplyBy { first: Some(_), .. } => 3,
+      Intrinsic::MultiplyBy { first: None, .. } | Intrinsic::DivideBy(_) => 2,
+      _ => 1,
+    }
+  }
+}
+
+/// Recognises a comparison, optionally followed by `not`, followed by an
+/// `if-goto`. Returns the jump that branches on x-y, the target label and
+/// how many instructions were matched.
+fn fused_branch(instructions: &[Located]) -> Option<(Jump, &str, usize)> {
+  let op = match instructions.first()?.instruction {
+    Instruction::Arithmetic(op @ (Operation::Eq | Operation::Gt | Operation::Lt)) => op,
+    _ => return None,
+  };
+  let negated = matches!(instructions.get(1)?.instruction, Instruction::Arithmetic(Operation::Not));
+  let length = if negated { 3 } else { 2 };
+  let target = match &instructions.get(length - 1)?.instruction {
+    Instruction::IfGoto(label) => label,
+    _ => return None,
+  };
+  let jump = match (op, negated) {
+    (Operation::Eq, false) => Jump::Eq,
+    (Operation::Eq, true) => Jump::Ne,
+    (Operation::Gt, false) => Jump::Gt,
+    (Operation::Gt, true) => Jump::Le,
+    (Operation::Lt, false) => Jump::Lt,
+    (Operation::Lt, true) => Jump::Ge,
+    _ => unreachable!(),
+  };
+  Some((jump, target, length))
+}
+
+/// Whether the instruction can be translated with the top of the stack
+/// cached in D. Everything else needs it in memory.
+fn uses_cache(instruction: &Instruction) -> bool {
+  match instruction {
+    Instruction::Push(_, _) | Instruction::Pop(_, _) | Instruction::IfGoto(_) => true,
+    Instruction::Arithmetic(op) => !matches!(op, Operation::Eq | Operation::Gt | Operation::Lt),
+    _ => false,
   }
 }
 
-fn new_error(message: String, filename: &str, command: &Command) -> String {
-  format!(
-    "{} at {}.vm line {}, column {}",
-    message, filename, command.name.line, command.name.column,
-  )
+fn command_name(instruction: &Instruction) -> &'static str {
+  match instruction {
+    Instruction::Label(_) => "label",
+    Instruction::Goto(_) => "goto",
+    Instruction::IfGoto(_) => "if-goto",
+    _ => unreachable!(),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use super::super::instruction::decode;
+  use super::super::lexer::lex;
+  use super::super::parser::parse;
+
+  const SOURCE: [&str; 12] = [
+    "function Main.f 1",
+    "push argument 0", "push local 0", "add", "pop pointer 1", "push that 0",
+    "push local 0", "push constant 1", "add", "pop local 0",
+    "pop static 0",
+    "return",
+  ];
+
+  fn code_size(options: Options) -> (usize, String) {
+    let module = decode("Main", &parse(&lex(&SOURCE.join("\n")))).unwrap();
+    let mut translator = Translator::new(options);
+    let start = translator.instructions().len();
+    translator.translate_module(&module).unwrap();
+    let size = translator.instructions()[start..].iter()
+      .filter(|instruction| instruction.is_code())
+      .count();
+    (size, translator.superinstruction_report())
+  }
+
+  #[test]
+  fn recognises_superinstructions() {
+    let module = decode("Main", &parse(&lex(&SOURCE.join("\n")))).unwrap();
+    let found = |index| Superinstruction::find(&module.instructions[index..]);
+    assert!(matches!(
+      found(1),
+      Some(Superinstruction::ArrayRead { base: (Segment::Argument, 0), .. }),
+    ));
+    assert!(matches!(found(6), Some(Superinstruction::Increment { delta: 1, .. })));
+    assert!(found(2).is_none());
+    assert!(found(10).is_none());
+  }
+
+  #[test]
+  fn superinstructions_shorten_the_translation() {
+    let (plain, _) = code_size(Options::default());
+    let (fused, report) = code_size(Options { superinstructions: true, ..Options::default() });
+    assert!(fused < plain, "{} >= {}", fused, plain);
+    assert_eq!(report, "superinstructions: 2 used\n       1  array read\n       1  increment");
+  }
 }